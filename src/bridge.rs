@@ -17,6 +17,9 @@ pub enum BridgeEvent {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GameStarted {
     pub players: Vec<UserId>,
+    /// Map chosen by the players, if the lobby has a map pool.
+    #[serde(default)]
+    pub map: Option<String>,
 }
 
 impl From<GameStarted> for BridgeEvent {
@@ -1,7 +1,21 @@
+mod audit;
+mod constraint;
+mod draft;
+mod gate;
 mod info;
 mod lobby;
 mod misc;
+mod party;
+mod ready;
+mod vote;
 
+pub use audit::*;
+pub use constraint::*;
+pub use draft::*;
+pub use gate::*;
 pub use info::*;
 pub use lobby::*;
 pub use misc::*;
+pub use party::*;
+pub use ready::*;
+pub use vote::*;
@@ -0,0 +1,141 @@
+use harmony::client::Context;
+use harmony::model::id::{ChannelId, UserId};
+use harmony::model::{Member, Message};
+
+use crate::checks;
+use crate::config::Roles;
+use crate::model::{AuditEntry, AuditFilter, Database, Lobbies};
+use crate::{Error, Result};
+
+const PAGE_LEN: usize = 10;
+
+/// Records an audit entry and mirrors it to the log channel, if any.
+pub fn record(
+    ctx: &Context,
+    database: &Database,
+    log: Option<ChannelId>,
+    mut entry: AuditEntry,
+) -> Result {
+    database.insert_audit_entry(&mut entry)?;
+    if let Some(log) = log {
+        if let Err(err) = ctx.create_message(log, |m| {
+            m.embed(|e| {
+                e.title(format!("Audit {}", entry.id))
+                    .description(describe(&entry))
+                    .timestamp(entry.datetime)
+            })
+        }) {
+            eprintln!("Err: {:?}", err);
+        }
+    }
+    Ok(())
+}
+
+fn describe(entry: &AuditEntry) -> String {
+    let mut lines = vec![format!(
+        "{} used `{} {}` in {}",
+        entry.actor.mention(),
+        entry.command,
+        entry.arguments,
+        entry.channel.mention()
+    )];
+    if let Some(game_id) = entry.game {
+        lines.push(format!("Game: {}", game_id));
+    }
+    if let Some(user_id) = entry.player {
+        lines.push(format!("Player: {}", user_id.mention()));
+    }
+    if !entry.before.is_empty() || !entry.after.is_empty() {
+        lines.push(format!("{} → {}", entry.before, entry.after));
+    }
+    lines.join("\n")
+}
+
+/// Formats the teams of a game for the audit log.
+pub fn teams_summary(teams: &[Vec<UserId>]) -> String {
+    teams
+        .iter()
+        .enumerate()
+        .map(|(i, team)| {
+            format!(
+                "Team {}: {}",
+                i + 1,
+                team.iter()
+                    .map(|x| x.mention())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" / ")
+}
+
+pub fn audit(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &Lobbies,
+    database: &Database,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    if args.len() < 2 {
+        return Err(Error::NotEnoughArguments);
+    }
+    let kind = args[0].to_lowercase();
+    let filter = match kind.as_str() {
+        "game" | "g" => {
+            if lobbies.get(&msg.channel_id).is_none() {
+                return Err(Error::NotALobby(msg.channel_id));
+            }
+            AuditFilter::Game(msg.channel_id, args[1].parse()?)
+        }
+        "player" | "p" | "admin" | "a" => {
+            let member = if let Some(member) = Member::parse(ctx, guild_id, &args[1])? {
+                member
+            } else {
+                return Err(Error::MemberNotFound(args[1].to_owned()));
+            };
+            if kind.starts_with('p') {
+                AuditFilter::Player(member.user.id)
+            } else {
+                AuditFilter::Actor(member.user.id)
+            }
+        }
+        _ => return Err(Error::BadArgument),
+    };
+    let page = if let Some(page) = args.get(2) {
+        page.parse::<usize>()?.max(1)
+    } else {
+        1
+    };
+    let entries = database.get_audit_entries(filter, PAGE_LEN, (page - 1) * PAGE_LEN)?;
+    if entries.is_empty() {
+        ctx.create_message(msg.channel_id, |m| {
+            m.embed(|e| e.description("No audit entries."))
+        })?;
+        return Ok(());
+    }
+    let description = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "**#{}** <t:{}:f>\n{}",
+                entry.id,
+                entry.datetime.timestamp(),
+                describe(entry)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| {
+            e.title(format!("Audit log (page {})", page))
+                .description(description)
+        })
+    })?;
+    Ok(())
+}
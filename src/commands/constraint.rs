@@ -0,0 +1,147 @@
+use harmony::client::Context;
+use harmony::model::id::{ChannelId, GuildId, UserId};
+use harmony::model::{Member, Message};
+
+use crate::checks;
+use crate::config::Roles;
+use crate::model::{AuditEntry, Database, PairConstraint};
+use crate::{Error, Result};
+
+use super::audit;
+
+pub fn avoid(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    set_constraint(ctx, msg, roles, database, log, args, PairConstraint::Apart)
+}
+
+pub fn together(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    set_constraint(
+        ctx,
+        msg,
+        roles,
+        database,
+        log,
+        args,
+        PairConstraint::Together,
+    )
+}
+
+pub fn unpair(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    let (user1, user2) = parse_pair(ctx, guild_id, args)?;
+    let description = if database.remove_pair_constraint(user1, user2)? {
+        audit::record(
+            ctx,
+            database,
+            log,
+            AuditEntry::new(msg.author.id, msg.channel_id, "unpair", args)
+                .change("constraint", "none"),
+        )?;
+        format!(
+            "Constraint between {} and {} removed.",
+            user1.mention(),
+            user2.mention()
+        )
+    } else {
+        format!(
+            "No constraint between {} and {}.",
+            user1.mention(),
+            user2.mention()
+        )
+    };
+    ctx.create_message(msg.channel_id, |m| m.embed(|e| e.description(description)))?;
+    Ok(())
+}
+
+pub fn constraints(ctx: &Context, msg: &Message, roles: &Roles, database: &Database) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    let constraints = database.get_pair_constraints()?;
+    let description = if constraints.is_empty() {
+        "No constraints.".to_owned()
+    } else {
+        constraints
+            .into_iter()
+            .map(|(user1, user2, constraint)| constraint.describe(user1, user2))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| e.title("Constraints").description(description))
+    })?;
+    Ok(())
+}
+
+fn set_constraint(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+    constraint: PairConstraint,
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    let (user1, user2) = parse_pair(ctx, guild_id, args)?;
+    database.set_pair_constraint(user1, user2, constraint)?;
+    let command = match constraint {
+        PairConstraint::Apart => "avoid",
+        PairConstraint::Together => "together",
+    };
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, command, args).change("none", command),
+    )?;
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| e.description(constraint.describe(user1, user2)))
+    })?;
+    Ok(())
+}
+
+fn parse_pair(ctx: &Context, guild_id: GuildId, args: &[String]) -> Result<(UserId, UserId)> {
+    if args.len() < 2 {
+        return Err(Error::NotEnoughArguments);
+    }
+    let users = args[..2]
+        .iter()
+        .map(|arg| match Member::parse(ctx, guild_id, arg) {
+            Ok(Some(x)) => Ok(x.user.id),
+            Ok(None) => Err(Error::MemberNotFound(arg.clone())),
+            Err(err) => Err(err.into()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if users[0] == users[1] {
+        return Err(Error::BadArgument);
+    }
+    Ok((users[0], users[1]))
+}
@@ -0,0 +1,193 @@
+use chrono::{Duration, Utc};
+use harmony::client::Context;
+use harmony::model::id::{ChannelId, UserId};
+use harmony::model::{Member, Message};
+
+use crate::checks;
+use crate::config::Roles;
+use crate::model::{AuditEntry, Database, Draft, Lobbies, Rating};
+use crate::{Error, Result};
+
+use super::{audit, lobby};
+
+#[allow(clippy::too_many_arguments)]
+pub fn pick(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    bridge: ChannelId,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if args.is_empty() {
+        return Err(Error::NotEnoughArguments);
+    }
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let pick_time = match lobby.draft_options() {
+        Some(options) => options.pick_time,
+        None => return Err(Error::NoDraft),
+    };
+    let draft = lobby.draft_mut().ok_or(Error::NoDraft)?;
+    let member = match Member::parse(ctx, guild_id, &args[0])? {
+        Some(member) => member,
+        None => return Err(Error::MemberNotFound(args[0].clone())),
+    };
+    // Admins can pick on behalf of the captain
+    let captain = if msg.author.id != draft.captain()
+        && checks::has_role(ctx, guild_id, msg.author.id, roles.admin)?
+    {
+        draft.captain()
+    } else {
+        msg.author.id
+    };
+    let deadline = Utc::now() + Duration::seconds(pick_time as i64);
+    draft.pick(captain, member.user.id, deadline)?;
+    advance(ctx, msg.channel_id, bridge, lobbies, database)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn captains(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    if args.len() < 2 {
+        return Err(Error::NotEnoughArguments);
+    }
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let draft = lobby.draft_mut().ok_or(Error::NoDraft)?;
+    let captains = args[..2]
+        .iter()
+        .map(|arg| match Member::parse(ctx, guild_id, arg) {
+            Ok(Some(x)) => Ok(x.user.id),
+            Ok(None) => Err(Error::MemberNotFound(arg.clone())),
+            Err(err) => Err(err.into()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let previous = captains_summary(draft);
+    draft.set_captains([captains[0], captains[1]])?;
+    let summary = captains_summary(draft);
+    database.save_lobby(msg.channel_id, lobby)?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "captains", args).change(previous, summary),
+    )?;
+    announce(ctx, msg.channel_id, lobby.draft().unwrap())
+}
+
+/// Picks the best-rated player left for every captain who ran out of time.
+pub fn draft_timeout(ctx: &Context, lobbies: &mut Lobbies, database: &Database, bridge: ChannelId) {
+    let now = Utc::now();
+    let expired = lobbies
+        .iter_mut()
+        .filter_map(|(&channel_id, lobby)| {
+            let pick_time = lobby.draft_options()?.pick_time;
+            let draft = lobby.draft_mut()?;
+            if now < draft.deadline() {
+                return None;
+            }
+            let captain = draft.captain();
+            let user_id = draft.pick_best(now + Duration::seconds(pick_time as i64));
+            Some((channel_id, captain, user_id))
+        })
+        .collect::<Vec<_>>();
+    for (channel_id, captain, user_id) in expired {
+        if let Err(err) = ctx.create_message(channel_id, |m| {
+            m.embed(|e| {
+                e.description(format!(
+                    "{} picked {} (Timeout).",
+                    captain.mention(),
+                    user_id.mention()
+                ))
+            })
+        }) {
+            eprintln!("Err: {:?}", err);
+        }
+        if let Err(err) = advance(ctx, channel_id, bridge, lobbies, database) {
+            eprintln!("Err: {:?}", err);
+        }
+    }
+}
+
+/// Starts the game once every player has been picked, otherwise shows whose
+/// turn it is.
+pub(super) fn advance(
+    ctx: &Context,
+    channel_id: ChannelId,
+    bridge: ChannelId,
+    lobbies: &mut Lobbies,
+    database: &Database,
+) -> Result {
+    let lobby = lobbies.get_mut(&channel_id).unwrap();
+    database.save_lobby(channel_id, lobby)?;
+    let draft = match lobby.draft() {
+        Some(draft) if draft.is_done() => lobby.take_draft().unwrap(),
+        Some(draft) => return announce(ctx, channel_id, draft),
+        None => return Ok(()),
+    };
+    let guild_id = draft.guild_id();
+    lobby::launch_game(
+        ctx,
+        guild_id,
+        channel_id,
+        bridge,
+        lobbies,
+        draft.into_teams().into(),
+        database,
+    )?;
+    // The queue may have filled up during the draft
+    if let Some(players) = lobby::next_players(ctx, channel_id, lobbies, database)? {
+        lobby::start_game(
+            ctx, guild_id, channel_id, bridge, lobbies, players, database,
+        )?;
+    }
+    Ok(())
+}
+
+/// Shows the teams picked so far and the players left.
+fn announce(ctx: &Context, channel_id: ChannelId, draft: &Draft) -> Result {
+    let f = |users: &[(UserId, Rating)]| {
+        users
+            .iter()
+            .map(|(user_id, rating)| format!("{} ({:.0})", user_id.mention(), rating.mean()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let description = format!(
+        "Team 1:\n{}\n\nTeam 2:\n{}\n\nPlayers left:\n{}\n\n{} to pick ({} seconds left).",
+        f(&draft.teams()[0]),
+        f(&draft.teams()[1]),
+        f(draft.pool()),
+        draft.captain().mention(),
+        (draft.deadline() - Utc::now()).num_seconds().max(0),
+    );
+    ctx.create_message(channel_id, |m| {
+        m.content(draft.captain().mention())
+            .embed(|e| e.title("Draft").description(description))
+    })?;
+    Ok(())
+}
+
+fn captains_summary(draft: &Draft) -> String {
+    format!(
+        "{} / {}",
+        draft.teams()[0][0].0.mention(),
+        draft.teams()[1][0].0.mention()
+    )
+}
@@ -0,0 +1,162 @@
+use std::mem;
+
+use harmony::client::Context;
+use harmony::model::id::{ChannelId, GuildId, UserId};
+use harmony::model::{Member, Message};
+
+use crate::checks;
+use crate::config::Roles;
+use crate::model::{AuditEntry, Database, GateError, GateRating, Lobbies, Lobby, RatingSystem};
+use crate::{Error, Result};
+
+use super::audit;
+
+pub fn exempt(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &Lobbies,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    set_exemption(ctx, msg, roles, lobbies, database, log, args, true)
+}
+
+pub fn unexempt(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &Lobbies,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    set_exemption(ctx, msg, roles, lobbies, database, log, args, false)
+}
+
+pub fn exemptions(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &Lobbies,
+    database: &Database,
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    if !lobbies.contains_key(&msg.channel_id) {
+        return Err(Error::NotALobby(msg.channel_id));
+    }
+    let exemptions = database.get_exemptions(msg.channel_id)?;
+    let description = if exemptions.is_empty() {
+        "No exemptions.".to_owned()
+    } else {
+        exemptions
+            .into_iter()
+            .map(|x| x.mention())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| e.title("Exemptions").description(description))
+    })?;
+    Ok(())
+}
+
+/// Checks that a player meets the requirements of a lobby, unless an admin
+/// exempted them. Unrated players have the initial rating of the lobby.
+pub(super) fn check(
+    ctx: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    lobbies: &Lobbies,
+    database: &Database,
+    user_id: UserId,
+) -> Result {
+    let lobby = lobbies
+        .get(&channel_id)
+        .ok_or(Error::NotALobby(channel_id))?;
+    let gate = lobby.gate();
+    if gate.is_open() || database.is_exempt(channel_id, user_id)? {
+        return Ok(());
+    }
+    if !gate.roles.is_empty() {
+        let member_roles = ctx
+            .get_guild_member(guild_id, user_id)?
+            .map(|x| x.roles)
+            .unwrap_or_default();
+        if let Some(&role_id) = gate.roles.iter().find(|x| !member_roles.contains(x)) {
+            return Err(GateError::MissingRole(user_id, role_id).into());
+        }
+    }
+    let rating = |lobby: &Lobby| lobby.ratings().get(&user_id).map(|x| x.rating.mean());
+    let default = lobby.ratings().system().create_rating().mean();
+    let rating = match gate.rating {
+        GateRating::Lobby => rating(lobby),
+        // Ratings of different systems are on different scales
+        GateRating::Best => lobbies
+            .values()
+            .filter(|x| {
+                mem::discriminant(&x.ratings().system())
+                    == mem::discriminant(&lobby.ratings().system())
+            })
+            .filter_map(rating)
+            .reduce(f64::max),
+    };
+    gate.check_rating(user_id, rating.unwrap_or(default))?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn set_exemption(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &Lobbies,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+    exempt: bool,
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    if !lobbies.contains_key(&msg.channel_id) {
+        return Err(Error::NotALobby(msg.channel_id));
+    }
+    let arg = args.first().ok_or(Error::NotEnoughArguments)?;
+    let user_id = match Member::parse(ctx, guild_id, arg)? {
+        Some(member) => member.user.id,
+        None => return Err(Error::MemberNotFound(arg.clone())),
+    };
+    let changed = if exempt {
+        database.add_exemption(msg.channel_id, user_id)?
+    } else {
+        database.remove_exemption(msg.channel_id, user_id)?
+    };
+    let (command, previous, new) = if exempt {
+        ("exempt", "not exempt", "exempt")
+    } else {
+        ("unexempt", "exempt", "not exempt")
+    };
+    if changed {
+        audit::record(
+            ctx,
+            database,
+            log,
+            AuditEntry::new(msg.author.id, msg.channel_id, command, args)
+                .player(user_id)
+                .change(previous, new),
+        )?;
+    }
+    let description = format!(
+        "{} is {} from the requirements of this lobby.",
+        user_id.mention(),
+        new
+    );
+    ctx.create_message(msg.channel_id, |m| m.embed(|e| e.description(description)))?;
+    Ok(())
+}
@@ -1,14 +1,14 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
 use harmony::client::Context;
 use harmony::model::id::{ChannelId, UserId};
 use harmony::model::{Channel, Member, Message, User};
 use inline_python::python;
-use trueskill::{Rating, SimpleTrueSkill as TrueSkill};
 
 use crate::checks;
 use crate::config::{Rank, Roles};
-use crate::model::{Database, Lobbies, PlayerInfo, Score};
+use crate::model::{Database, Lobbies, Lobby, PlayerInfo, RatingSystem, Ratings, Score, System};
 use crate::utils;
 use crate::{Error, Result};
 
@@ -16,12 +16,14 @@ pub fn info(
     ctx: &Context,
     msg: &Message,
     lobbies: &Lobbies,
+    database: &Database,
     infos: &[ChannelId],
     args: &[String],
 ) -> Result {
     if !infos.contains(&msg.channel_id) {
         return Ok(());
     }
+    let (args, season) = split_season(args);
     if args.is_empty() {
         return Err(Error::NotEnoughArguments);
     }
@@ -32,7 +34,15 @@ pub fn info(
     } else {
         return Err(Error::ChannelNotFound(args[0].to_string()));
     };
-    info_internal(ctx, msg, lobbies, msg.author.id, channel_id)?;
+    info_internal(
+        ctx,
+        msg,
+        lobbies,
+        database,
+        msg.author.id,
+        channel_id,
+        season,
+    )?;
     Ok(())
 }
 
@@ -41,12 +51,14 @@ pub fn forceinfo(
     msg: &Message,
     roles: &Roles,
     lobbies: &Lobbies,
+    database: &Database,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
     }
+    let (args, season) = split_season(args);
     if args.len() < 2 {
         return Err(Error::NotEnoughArguments);
     }
@@ -59,7 +71,15 @@ pub fn forceinfo(
     };
     for arg in args.iter().skip(1) {
         if let Some(member) = Member::parse(ctx, guild_id, arg)? {
-            info_internal(ctx, msg, lobbies, member.user.id, channel_id)?;
+            info_internal(
+                ctx,
+                msg,
+                lobbies,
+                database,
+                member.user.id,
+                channel_id,
+                season,
+            )?;
         }
     }
     Ok(())
@@ -69,11 +89,19 @@ fn info_internal(
     ctx: &Context,
     msg: &Message,
     lobbies: &Lobbies,
+    database: &Database,
     member_id: UserId,
     channel: ChannelId,
+    season: Option<usize>,
 ) -> Result {
     let lobby = lobbies.get(&channel).ok_or(Error::NotALobby(channel))?;
-    if let Some(player_info) = lobby.ratings().get(&member_id) {
+    let ratings = season_ratings(lobby, database, channel, season)?;
+    let title = if let Some(season) = season {
+        format!("Info - Season {}", season)
+    } else {
+        "Info".to_owned()
+    };
+    if let Some(player_info) = ratings.get(&member_id) {
         ctx.create_message(msg.channel_id, |m| {
             m.embed(|e| {
                 e.description(format!(
@@ -85,14 +113,14 @@ fn info_internal(
                     player_info.losses,
                     player_info.draws
                 ))
-                .title("Info")
+                .title(title)
             })
         })?;
     } else {
         ctx.create_message(msg.channel_id, |m| {
             m.embed(|e| {
                 e.description(format!("{}\nNo info yet, play more!", member_id.mention()))
-                    .title("Info")
+                    .title(title)
             })
         })?;
     }
@@ -106,13 +134,13 @@ pub fn history(
     ranks: &[Rank],
     lobbies: &Lobbies,
     database: &Database,
-    trueskill: TrueSkill,
     infos: &[ChannelId],
     args: &[String],
 ) -> Result {
     if !infos.contains(&msg.channel_id) {
         return Ok(());
     }
+    let (args, season) = split_season(args);
     if args.is_empty() {
         return Err(Error::NotEnoughArguments);
     }
@@ -134,10 +162,10 @@ pub fn history(
         ranks,
         lobbies,
         database,
-        trueskill,
         &msg.author,
         channel_id,
         limit,
+        season,
     )?;
     Ok(())
 }
@@ -150,13 +178,13 @@ pub fn forcehistory(
     ranks: &[Rank],
     lobbies: &Lobbies,
     database: &Database,
-    trueskill: TrueSkill,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
     }
+    let (args, season) = split_season(args);
     if args.len() < 2 {
         return Err(Error::NotEnoughArguments);
     }
@@ -183,10 +211,10 @@ pub fn forcehistory(
         ranks,
         lobbies,
         database,
-        trueskill,
         &member.user,
         channel_id,
         limit,
+        season,
     )?;
     Ok(())
 }
@@ -198,94 +226,38 @@ fn history_internal(
     ranks: &[Rank],
     lobbies: &Lobbies,
     database: &Database,
-    trueskill: TrueSkill,
     user: &User,
     channel_id: ChannelId,
     limit: Option<usize>,
+    season: Option<usize>,
 ) -> Result {
-    if lobbies.get(&channel_id).is_none() {
-        return Err(Error::NotALobby(channel_id));
-    }
-    let games = database
-        .get_games()?
-        .remove(&channel_id)
-        .unwrap_or_default();
-    let mut info_history = Vec::new();
-    let initials = database.get_initial_ratings()?;
-    let mut ratings: HashMap<UserId, _> = HashMap::new();
-    let default_rating = trueskill.create_rating();
-    let default_info = PlayerInfo::new(default_rating);
-    info_history.push(if let Some(&rating) = initials.get(&user.id) {
-        PlayerInfo::new(Rating::new(rating, default_rating.variance()))
+    let lobby = lobbies
+        .get(&channel_id)
+        .ok_or(Error::NotALobby(channel_id))?;
+    let seasons = database.get_seasons(channel_id)?;
+    let season = season.unwrap_or(seasons.len());
+    let start = match season.checked_sub(1).and_then(|x| seasons.get(x)) {
+        Some(&start) => start,
+        None => return Err(Error::SeasonNotFound(season)),
+    };
+    let end = match seasons.get(season) {
+        Some(&end) => end,
+        None => database.last_game_id(channel_id)? + 1,
+    };
+    let games = database.get_player_game_ratings(channel_id, user.id, start, end)?;
+    let first = if let Some((before, _)) = games.first() {
+        *before
     } else {
-        default_info
-    });
-    for game in games.values() {
-        let score = match game.score() {
-            Score::Undecided | Score::Cancelled => continue,
-            Score::Team1 => trueskill::Score::Win,
-            Score::Team2 => trueskill::Score::Loss,
-            Score::Draw => trueskill::Score::Draw,
-        };
-        let teams = game.teams();
-        let mut team1_ratings = teams[0]
-            .iter()
-            .map(|x| {
-                ratings
-                    .get(x)
-                    .map(|y: &PlayerInfo| y.rating)
-                    .unwrap_or_else(|| {
-                        if let Some(&rating) = initials.get(x) {
-                            Rating::new(rating, default_rating.variance())
-                        } else {
-                            default_rating
-                        }
-                    })
-            })
-            .collect::<Vec<_>>();
-        let mut team2_ratings = teams[1]
-            .iter()
-            .map(|x| {
-                ratings.get(x).map(|y| y.rating).unwrap_or_else(|| {
-                    if let Some(&rating) = initials.get(x) {
-                        Rating::new(rating, default_rating.variance())
-                    } else {
-                        default_rating
-                    }
-                })
-            })
-            .collect::<Vec<_>>();
-        trueskill.update(&mut team1_ratings, &mut team2_ratings, score);
-        for (i, &user_id) in teams[0].iter().enumerate() {
-            let player_info = ratings.entry(user_id).or_insert(default_info);
-            match score {
-                trueskill::Score::Win => player_info.wins += 1,
-                trueskill::Score::Loss => player_info.losses += 1,
-                trueskill::Score::Draw => player_info.draws += 1,
-            };
-            player_info.rating = team1_ratings[i];
-            if user_id == user.id {
-                info_history.push(*player_info);
-            }
-        }
-        for (i, &user_id) in teams[1].iter().enumerate() {
-            let player_info = ratings.entry(user_id).or_insert(default_info);
-            match score {
-                trueskill::Score::Win => player_info.losses += 1,
-                trueskill::Score::Loss => player_info.wins += 1,
-                trueskill::Score::Draw => player_info.draws += 1,
-            };
-            player_info.rating = team2_ratings[i];
-            if user_id == user.id {
-                info_history.push(*player_info);
-            }
-        }
-    }
-    let xs = (0..info_history.len()).collect::<Vec<_>>();
-    let ys = info_history
-        .iter()
-        .map(|x| x.rating.mean())
+        season_ratings(lobby, database, channel_id, Some(season))?
+            .get(&user.id)
+            .map(|x| x.rating)
+            .unwrap_or_else(|| lobby.ratings().system().create_rating())
+    };
+    let info_history = std::iter::once(first)
+        .chain(games.into_iter().map(|(_, after)| after))
         .collect::<Vec<_>>();
+    let xs = (0..info_history.len()).collect::<Vec<_>>();
+    let ys = info_history.iter().map(|x| x.mean()).collect::<Vec<_>>();
     let (xs, ys) = if let Some(limit) = limit {
         (
             xs.into_iter()
@@ -340,6 +312,7 @@ pub fn leaderboard(
     msg: &Message,
     roles: &Roles,
     lobbies: &Lobbies,
+    database: &Database,
     ranks: &[Rank],
     args: &[String],
 ) -> Result {
@@ -347,6 +320,7 @@ pub fn leaderboard(
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
     }
+    let (args, season) = split_season(args);
     if args.is_empty() {
         return Err(Error::NotEnoughArguments);
     }
@@ -367,17 +341,24 @@ pub fn leaderboard(
     } else {
         return Err(Error::NotALobby(channel_id));
     };
+    let ratings = season_ratings(lobby, database, channel_id, season)?;
     let members_roles = ctx
         .list_guild_members(guild_id)?
         .into_iter()
         .map(|x| (x.user.id, x.roles))
         .collect::<HashMap<_, _>>();
-    let leaderboard = utils::leaderboard(lobby, 15, ranks, |user_id| {
-        Ok(members_roles
-            .get(&user_id)
-            .map(|x| x.contains(&roles.ranked))
-            .unwrap_or(false))
-    })?;
+    let leaderboard = utils::leaderboard(
+        &ratings,
+        lobby.leaderboard_options(),
+        15,
+        ranks,
+        |user_id| {
+            Ok(members_roles
+                .get(&user_id)
+                .map(|x| x.contains(&roles.ranked))
+                .unwrap_or(false))
+        },
+    )?;
     let pages = leaderboard.len();
     let page = page.max(1).min(pages);
     if page == 0 {
@@ -398,6 +379,7 @@ pub fn lball(
     msg: &Message,
     roles: &Roles,
     lobbies: &Lobbies,
+    database: &Database,
     ranks: &[Rank],
     args: &[String],
 ) -> Result {
@@ -405,6 +387,7 @@ pub fn lball(
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
     }
+    let (args, season) = split_season(args);
     if args.is_empty() {
         return Err(Error::NotEnoughArguments);
     }
@@ -425,8 +408,11 @@ pub fn lball(
     } else {
         return Err(Error::NotALobby(channel_id));
     };
+    let ratings = season_ratings(lobby, database, channel_id, season)?;
 
-    let leaderboard = utils::leaderboard(lobby, 15, ranks, |_| Ok(true))?;
+    let leaderboard = utils::leaderboard(&ratings, lobby.leaderboard_options(), 15, ranks, |_| {
+        Ok(true)
+    })?;
     let pages = leaderboard.len();
     let page = page.max(1).min(pages);
     if page == 0 {
@@ -441,3 +427,167 @@ pub fn lball(
     })?;
     Ok(())
 }
+
+/// Number of games loaded at once when replaying a whole lobby history.
+const REPLAY_BATCH: usize = 1000;
+
+/// Replays the whole history of a lobby with every rating system, ignoring
+/// seasons, and reports how well each one predicted the results, optionally
+/// only over the games of one player.
+pub fn compare(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &Lobbies,
+    database: &Database,
+    systems: &[System],
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    if args.is_empty() {
+        return Err(Error::NotEnoughArguments);
+    }
+    let channel_id = if let Some(channel) = Channel::parse(ctx, msg.guild_id, &args[0])? {
+        channel.id
+    } else if let Some(lobby) = lobbies.iter().find(|(_, x)| x.name() == args[0]) {
+        *lobby.0
+    } else {
+        return Err(Error::ChannelNotFound(args[0].to_string()));
+    };
+    if lobbies.get(&channel_id).is_none() {
+        return Err(Error::NotALobby(channel_id));
+    }
+    let member = if let Some(arg) = args.get(1) {
+        match Member::parse(ctx, guild_id, arg)? {
+            Some(member) => Some(member),
+            None => return Err(Error::MemberNotFound(arg.to_owned())),
+        }
+    } else {
+        None
+    };
+    let counted = match &member {
+        Some(member) => Some(
+            database
+                .get_player_games(channel_id, member.user.id)?
+                .into_keys()
+                .collect::<HashSet<_>>(),
+        ),
+        None => None,
+    };
+    let initials = database.get_initial_ratings()?;
+    let mut ratings = systems
+        .iter()
+        .map(|&system| Ratings::new(0, &initials, &HashMap::new(), system))
+        .collect::<Vec<_>>();
+    let mut predictions = vec![Vec::new(); systems.len()];
+    let last = database.last_game_id(channel_id)?;
+    let mut games = 0;
+    for from in (0..=last).step_by(REPLAY_BATCH) {
+        let batch = database.get_games_between(channel_id, from, from + REPLAY_BATCH)?;
+        games += batch.len();
+        for game in batch.values() {
+            for (ratings, predictions) in ratings.iter_mut().zip(predictions.iter_mut()) {
+                let game_ratings = match ratings.apply(game) {
+                    Some(game_ratings) => game_ratings,
+                    None => continue,
+                };
+                if counted.as_ref().is_some_and(|x| !x.contains(&game.id())) {
+                    continue;
+                }
+                let result = match game.score() {
+                    Score::Team1 => 1.0,
+                    Score::Team2 => 0.0,
+                    Score::Draw => 0.5,
+                    Score::Undecided | Score::Cancelled | Score::Ranked => continue,
+                };
+                if let Some(p) = game_ratings.win_probability {
+                    predictions.push((p.clamp(1e-9, 1.0 - 1e-9), result));
+                }
+            }
+        }
+    }
+    let mut description = String::new();
+    for (system, predictions) in systems.iter().zip(predictions) {
+        if predictions.is_empty() {
+            return Err(Error::GameNotFound(1));
+        }
+        let len = predictions.len() as f64;
+        let accuracy = predictions
+            .iter()
+            .map(|&(p, result)| {
+                if result == 0.5 || p == 0.5 {
+                    0.5
+                } else if (p > 0.5) == (result == 1.0) {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .sum::<f64>()
+            / len;
+        let brier = predictions
+            .iter()
+            .map(|&(p, result)| (p - result).powi(2))
+            .sum::<f64>()
+            / len;
+        let log_loss = predictions
+            .iter()
+            .map(|&(p, result)| -(result * p.ln() + (1.0 - result) * (1.0 - p).ln()))
+            .sum::<f64>()
+            / len;
+        description.push_str(&format!(
+            "**{}**\nAccuracy: {:.1}%\nBrier score: {:.4}\nLog loss: {:.4}\n\n",
+            system,
+            100.0 * accuracy,
+            brier,
+            log_loss
+        ));
+    }
+    let title = match (&member, &counted) {
+        (Some(member), Some(counted)) => format!(
+            "Rating systems over {}'s {} games",
+            member.user.username,
+            counted.len()
+        ),
+        _ => format!("Rating systems over {} games", games),
+    };
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| e.description(description).title(title))
+    })?;
+    Ok(())
+}
+
+/// Splits an optional trailing season argument, such as `s2`, from the
+/// arguments following the lobby.
+fn split_season(args: &[String]) -> (&[String], Option<usize>) {
+    if let Some((last, rest)) = args.split_last() {
+        if !rest.is_empty() {
+            if let Some(Ok(season)) = last.to_lowercase().strip_prefix('s').map(str::parse) {
+                return (rest, Some(season));
+            }
+        }
+    }
+    (args, None)
+}
+
+/// Returns the ratings of a lobby for the given season, the archived ones for
+/// a past season and the live ones for the current season.
+fn season_ratings<'a>(
+    lobby: &'a Lobby,
+    database: &Database,
+    channel_id: ChannelId,
+    season: Option<usize>,
+) -> Result<Cow<'a, HashMap<UserId, PlayerInfo>>> {
+    let current = database.get_seasons(channel_id)?.len();
+    match season {
+        None => Ok(Cow::Borrowed(lobby.ratings())),
+        Some(season) if season == current => Ok(Cow::Borrowed(lobby.ratings())),
+        Some(season) if season > 0 && season < current => {
+            Ok(Cow::Owned(database.get_season_ratings(channel_id, season)?))
+        }
+        Some(season) => Err(Error::SeasonNotFound(season)),
+    }
+}
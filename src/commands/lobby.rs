@@ -5,49 +5,122 @@ use harmony::client::Context;
 use harmony::model::id::{ChannelId, GuildId, UserId};
 use harmony::model::{Member, Message};
 use rayon::iter::{
-    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
-    IntoParallelRefMutIterator, ParallelIterator,
+    IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
 };
 use serde_json::json;
-use trueskill::SimpleTrueSkill as TrueSkill;
 
 use crate::bridge::{GameStarted, OpCode};
 use crate::checks;
 use crate::config::{Rank, Roles};
-use crate::model::{Database, Game, Lobbies, LobbyError, QueueUser, Ratings, Score};
+use crate::model::{
+    AuditEntry, Database, Draft, Game, GameRatings, Lobbies, Lobby, LobbyError, MapVote,
+    PairConstraint, Parties, PartyError, QueueUser, Rating, RatingSystem, Ratings, ReadyCheck,
+    Rebalance, Score, Substitution,
+};
 use crate::utils;
 use crate::{Error, Result};
 
+use super::{audit, draft, gate, ready, vote};
+
+/// Team splits listed by a rebalance unless asked otherwise.
+const DEFAULT_SPLITS: usize = 3;
+const MAX_SPLITS: usize = 5;
+
+/// Joins the queue of the lobby, or of the lobbies named in `args` from the
+/// channel of any lobby.
 #[allow(clippy::too_many_arguments)]
 pub fn join(
     ctx: &Context,
     msg: &Message,
     roles: &Roles,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
+    parties: &Parties,
     database: &Database,
     bridge: ChannelId,
     timeout: u64,
     warn: u64,
+    args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
-    if checks::has_role(ctx, guild_id, msg.author.id, roles.banned)? {
-        return Ok(());
+    // The leader of a party joins for the whole party
+    let users = match parties.get(msg.author.id) {
+        Some(party) if party.leader() != msg.author.id => {
+            return Err(PartyError::NotLeader(msg.author.id).into())
+        }
+        Some(party) => party.members().to_vec(),
+        None => vec![msg.author.id],
+    };
+    for &user_id in users.iter() {
+        if checks::has_role(ctx, guild_id, user_id, roles.banned)? {
+            return Ok(());
+        }
     }
     let timestamp = msg.timestamp + Duration::minutes(timeout as i64);
-    join_internal(
-        ctx,
-        guild_id,
-        msg.channel_id,
-        bridge,
-        msg.author.id,
-        timestamp,
-        Some(timestamp - Duration::minutes(warn as i64)),
-        false,
-        lobbies,
-        trueskill,
-        database,
-    )
+    let warn = Some(timestamp - Duration::minutes(warn as i64));
+    if args.is_empty() {
+        for &user_id in users.iter() {
+            gate::check(ctx, guild_id, msg.channel_id, lobbies, database, user_id)?;
+        }
+        return join_internal(
+            ctx,
+            guild_id,
+            msg.channel_id,
+            bridge,
+            &users,
+            timestamp,
+            warn,
+            false,
+            lobbies,
+            database,
+        );
+    }
+    let channels = find_lobbies(lobbies, msg.channel_id, args)?;
+    let mentions = users
+        .iter()
+        .map(|x| x.mention())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut lines = Vec::new();
+    for &channel_id in channels.iter() {
+        let result = users
+            .iter()
+            .try_for_each(|&user_id| {
+                gate::check(ctx, guild_id, channel_id, lobbies, database, user_id)
+            })
+            .and_then(|_| check_pending(lobbies, &users))
+            .and_then(|_| {
+                let lobby = lobbies.get_mut(&channel_id).unwrap();
+                match users.as_slice() {
+                    [user_id] => lobby.join(*user_id, timestamp, warn, false),
+                    _ => lobby.join_party(&users, timestamp, warn, false),
+                }
+                .map_err(Error::from)
+            });
+        let lobby = &lobbies[&channel_id];
+        lines.push(match result {
+            Ok(()) => {
+                database.save_lobby(channel_id, lobby)?;
+                if channel_id != msg.channel_id {
+                    notify(
+                        ctx,
+                        channel_id,
+                        lobby,
+                        &format!("{} joined the queue.", mentions),
+                    );
+                }
+                format!(
+                    "Joined {} [{}/{}].",
+                    lobby.name(),
+                    lobby.len(),
+                    lobby.queue_capacity()
+                )
+            }
+            Err(err) => format!("Could not join {}: {}", lobby.name(), err),
+        });
+    }
+    let description = format!("{}\n{}", mentions, lines.join("\n"));
+    ctx.create_message(msg.channel_id, |m| m.embed(|e| e.description(description)))?;
+    start_games(ctx, guild_id, bridge, lobbies, &channels, database)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -56,9 +129,9 @@ pub fn forcejoin(
     msg: &Message,
     roles: &Roles,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
     bridge: ChannelId,
+    log: Option<ChannelId>,
     timeout: u64,
     warn: u64,
     args: &[String],
@@ -82,14 +155,21 @@ pub fn forcejoin(
             guild_id,
             msg.channel_id,
             bridge,
-            member.user.id,
+            &[member.user.id],
             timestamp,
             Some(timestamp - Duration::minutes(warn as i64)),
             true,
             lobbies,
-            trueskill,
             database,
         )?;
+        audit::record(
+            ctx,
+            database,
+            log,
+            AuditEntry::new(msg.author.id, msg.channel_id, "forcejoin", args)
+                .player(member.user.id)
+                .change("not in queue", "in queue"),
+        )?;
     }
     Ok(())
 }
@@ -100,57 +180,168 @@ fn join_internal(
     guild_id: GuildId,
     channel_id: ChannelId,
     bridge: ChannelId,
-    user_id: UserId,
+    users: &[UserId],
     timestamp: DateTime<Utc>,
     warn: Option<DateTime<Utc>>,
     force: bool,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
 ) -> Result {
+    check_pending(lobbies, users)?;
     let lobby = lobbies
         .get_mut(&channel_id)
         .ok_or(Error::NotALobby(channel_id))?;
-    lobby.join(user_id, timestamp, warn, force)?;
+    match users {
+        [user_id] => lobby.join(*user_id, timestamp, warn, force)?,
+        _ => lobby.join_party(users, timestamp, warn, force)?,
+    }
+    database.save_lobby(channel_id, lobby)?;
     ctx.create_message(channel_id, |m| {
         m.embed(|e| {
             e.description(format!(
                 "[{}/{}] {} joined the queue.",
                 lobby.len(),
-                lobby.capacity(),
-                user_id.mention()
+                lobby.queue_capacity(),
+                users
+                    .iter()
+                    .map(|x| x.mention())
+                    .collect::<Vec<_>>()
+                    .join(", ")
             ))
         })
     })?;
-    if lobby.len() == lobby.capacity() {
-        let players = lobby.clear().into_keys().collect();
+    if let Some(players) = next_players(ctx, channel_id, lobbies, database)? {
         start_game(
-            ctx, guild_id, channel_id, bridge, lobbies, players, trueskill, database,
+            ctx, guild_id, channel_id, bridge, lobbies, players, database,
         )?;
     }
     Ok(())
 }
 
+/// Leaves the queue of the lobby, or of the lobbies named in `args` from the
+/// channel of any lobby.
 pub fn leave(
     ctx: &Context,
     msg: &Message,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
     bridge: ChannelId,
+    args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
-    leave_internal(
-        ctx,
-        guild_id,
-        msg.channel_id,
-        bridge,
-        msg.author.id,
-        false,
-        lobbies,
-        trueskill,
-        database,
-    )
+    if args.is_empty() {
+        return leave_internal(
+            ctx,
+            guild_id,
+            msg.channel_id,
+            bridge,
+            msg.author.id,
+            false,
+            lobbies,
+            database,
+        );
+    }
+    let channels = find_lobbies(lobbies, msg.channel_id, args)?;
+    let mention = msg.author.id.mention();
+    let mut lines = Vec::new();
+    for &channel_id in channels.iter() {
+        let lobby = lobbies.get_mut(&channel_id).unwrap();
+        lines.push(match lobby.leave(msg.author.id, false) {
+            Ok(()) => {
+                database.save_lobby(channel_id, lobby)?;
+                if channel_id != msg.channel_id {
+                    notify(
+                        ctx,
+                        channel_id,
+                        lobby,
+                        &format!("{} left the queue.", mention),
+                    );
+                }
+                format!(
+                    "Left {} [{}/{}].",
+                    lobby.name(),
+                    lobby.len(),
+                    lobby.queue_capacity()
+                )
+            }
+            Err(err) => format!("Could not leave {}: {}", lobby.name(), err),
+        });
+    }
+    let description = format!("{}\n{}", mention, lines.join("\n"));
+    ctx.create_message(msg.channel_id, |m| m.embed(|e| e.description(description)))?;
+    start_games(ctx, guild_id, bridge, lobbies, &channels, database)
+}
+
+/// Finds the lobbies named by `args`, every lobby for `all`, as long as the
+/// command comes from the channel of a lobby.
+fn find_lobbies(
+    lobbies: &Lobbies,
+    channel_id: ChannelId,
+    args: &[String],
+) -> Result<Vec<ChannelId>> {
+    if !lobbies.contains_key(&channel_id) {
+        return Err(Error::NotALobby(channel_id));
+    }
+    if args.iter().any(|x| x.eq_ignore_ascii_case("all")) {
+        let mut channels = lobbies.keys().copied().collect::<Vec<_>>();
+        channels.sort_by(|a, b| lobbies[a].name().cmp(lobbies[b].name()));
+        return Ok(channels);
+    }
+    let mut channels = Vec::new();
+    for arg in args {
+        let channel_id = lobbies
+            .find(arg)
+            .ok_or_else(|| Error::UnknownLobby(arg.clone()))?;
+        if !channels.contains(&channel_id) {
+            channels.push(channel_id);
+        }
+    }
+    Ok(channels)
+}
+
+/// Tells the channel of a lobby about a change made from another channel.
+fn notify(ctx: &Context, channel_id: ChannelId, lobby: &Lobby, text: &str) {
+    if let Err(err) = ctx.create_message(channel_id, |m| {
+        m.embed(|e| {
+            e.description(format!(
+                "[{}/{}] {}",
+                lobby.len(),
+                lobby.queue_capacity(),
+                text
+            ))
+        })
+    }) {
+        eprintln!("Err: {:?}", err);
+    }
+}
+
+/// Refuses players who are already picked for a game being set up.
+fn check_pending(lobbies: &Lobbies, users: &[UserId]) -> Result {
+    match users.iter().find(|&&x| lobbies.is_pending(x)) {
+        Some(&user_id) => Err(LobbyError::Pending(user_id).into()),
+        None => Ok(()),
+    }
+}
+
+/// Starts the games of the lobbies whose queue is full, one after the other.
+/// The players picked for a game leave the other queues as they are picked,
+/// so a later lobby only starts if its queue is still full.
+fn start_games(
+    ctx: &Context,
+    guild_id: GuildId,
+    bridge: ChannelId,
+    lobbies: &mut Lobbies,
+    channels: &[ChannelId],
+    database: &Database,
+) -> Result {
+    for &channel_id in channels {
+        if let Some(players) = next_players(ctx, channel_id, lobbies, database)? {
+            start_game(
+                ctx, guild_id, channel_id, bridge, lobbies, players, database,
+            )?;
+        }
+    }
+    Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -159,9 +350,9 @@ pub fn forceleave(
     msg: &Message,
     roles: &Roles,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
     bridge: ChannelId,
+    log: Option<ChannelId>,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
@@ -185,9 +376,16 @@ pub fn forceleave(
             member.user.id,
             true,
             lobbies,
-            trueskill,
             database,
         )?;
+        audit::record(
+            ctx,
+            database,
+            log,
+            AuditEntry::new(msg.author.id, msg.channel_id, "forceleave", args)
+                .player(member.user.id)
+                .change("in queue", "not in queue"),
+        )?;
     }
     Ok(())
 }
@@ -201,47 +399,42 @@ fn leave_internal(
     user_id: UserId,
     force: bool,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
 ) -> Result {
-    let players = {
-        let lobby = lobbies
-            .get_mut(&channel_id)
-            .ok_or(Error::NotALobby(channel_id))?;
-        lobby.leave(user_id, force)?;
-        ctx.create_message(channel_id, |m| {
-            m.embed(|e| {
-                e.description(format!(
-                    "[{}/{}] {} left the queue.",
-                    lobby.len(),
-                    lobby.capacity(),
-                    user_id.mention()
-                ))
-            })
-        })?;
-        if lobby.len() == lobby.capacity() {
-            Some(lobby.clear().into_keys().collect())
-        } else {
-            None
-        }
-    };
-    if let Some(players) = players {
+    let lobby = lobbies
+        .get_mut(&channel_id)
+        .ok_or(Error::NotALobby(channel_id))?;
+    lobby.leave(user_id, force)?;
+    database.save_lobby(channel_id, lobby)?;
+    ctx.create_message(channel_id, |m| {
+        m.embed(|e| {
+            e.description(format!(
+                "[{}/{}] {} left the queue.",
+                lobby.len(),
+                lobby.queue_capacity(),
+                user_id.mention()
+            ))
+        })
+    })?;
+    if let Some(players) = next_players(ctx, channel_id, lobbies, database)? {
         start_game(
-            ctx, guild_id, channel_id, bridge, lobbies, players, trueskill, database,
+            ctx, guild_id, channel_id, bridge, lobbies, players, database,
         )?;
     }
     Ok(())
 }
 
+/// Sets the number of players per team, or the number of players of a
+/// free-for-all lobby.
 #[allow(clippy::too_many_arguments)]
 pub fn players(
     ctx: &Context,
     msg: &Message,
     roles: &Roles,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
     bridge: ChannelId,
+    log: Option<ChannelId>,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
@@ -251,22 +444,38 @@ pub fn players(
     if args.is_empty() {
         return Err(Error::NotEnoughArguments);
     }
-    let players = {
-        let lobby = lobbies
-            .get_mut(&msg.channel_id)
-            .ok_or(Error::NotALobby(msg.channel_id))?;
-        let x = args[0].parse::<usize>()?;
-        lobby.set_capacity(2 * x);
-        ctx.create_message(msg.channel_id, |m| {
-            m.embed(|e| e.description(format!("Players per team set to {}.", x)))
-        })?;
-        if lobby.len() == lobby.capacity() {
-            Some(lobby.clear().into_keys().collect())
-        } else {
-            None
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let x = args[0].parse::<usize>()?;
+    // Free-for-all lobbies keep one team per player, and more than two players
+    // to stay apart from one versus one lobbies
+    let free_for_all = lobby.teams() > 2 && lobby.teams() == lobby.capacity();
+    let (previous, description) = if free_for_all {
+        if x < 3 {
+            return Err(Error::BadArgument);
+        }
+        let previous = lobby.capacity();
+        lobby.set_teams(x);
+        lobby.set_capacity(x);
+        (previous, format!("Players set to {}.", x))
+    } else {
+        if x == 0 {
+            return Err(Error::BadArgument);
         }
+        let previous = lobby.capacity() / lobby.teams();
+        lobby.set_capacity(lobby.teams() * x);
+        (previous, format!("Players per team set to {}.", x))
     };
-    if let Some(players) = players {
+    database.save_lobby(msg.channel_id, lobby)?;
+    ctx.create_message(msg.channel_id, |m| m.embed(|e| e.description(description)))?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "players", args).change(previous, x),
+    )?;
+    if let Some(players) = next_players(ctx, msg.channel_id, lobbies, database)? {
         start_game(
             ctx,
             guild_id,
@@ -274,66 +483,440 @@ pub fn players(
             bridge,
             lobbies,
             players,
-            trueskill,
             database,
         )?;
     }
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-fn start_game(
+/// Takes the players of the next game out of the queue of a lobby if it is
+/// full, and out of the queues of the other lobbies so that they cannot be
+/// picked for two games at once.
+pub(super) fn next_players(
+    ctx: &Context,
+    channel_id: ChannelId,
+    lobbies: &mut Lobbies,
+    database: &Database,
+) -> Result<Option<HashMap<UserId, QueueUser>>> {
+    let lobby = lobbies.get_mut(&channel_id).unwrap();
+    let players = match pick_next_players(lobby) {
+        Some(players) => players,
+        None => return Ok(None),
+    };
+    database.save_lobby(channel_id, lobby)?;
+    let name = lobby.name().to_owned();
+    for (&other_id, lobby) in lobbies.iter_mut() {
+        if other_id == channel_id {
+            continue;
+        }
+        let left = players
+            .keys()
+            .copied()
+            .filter(|&x| lobby.leave(x, true).is_ok())
+            .collect::<Vec<_>>();
+        if left.is_empty() {
+            continue;
+        }
+        notify(
+            ctx,
+            other_id,
+            lobby,
+            &format!(
+                "{} left the queue (Picked for a game in {}).",
+                left.iter()
+                    .map(|x| x.mention())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                name
+            ),
+        );
+        database.save_lobby(other_id, lobby)?;
+    }
+    Ok(Some(players))
+}
+
+/// Takes the players of the next game out of the queue if it is full: the
+/// whole queue, or the best game in it if the lobby pools its players.
+fn pick_next_players(lobby: &mut Lobby) -> Option<HashMap<UserId, QueueUser>> {
+    if !lobby.can_start() {
+        return None;
+    }
+    let options = match lobby.pool_options() {
+        Some(options) if lobby.len() > lobby.capacity() => options,
+        _ => return Some(lobby.clear()),
+    };
+    let now = Utc::now();
+    let system = lobby.ratings().system();
+    let mut indices = HashMap::new();
+    let (players, (parties, waits)): (Vec<_>, (Vec<_>, Vec<_>)) = lobby
+        .queue()
+        .iter()
+        .map(|(&user_id, queue_user)| {
+            let rating = lobby
+                .ratings()
+                .get(&user_id)
+                .map(|x| x.rating)
+                .unwrap_or_else(|| system.create_rating());
+            let len = indices.len();
+            let party = *indices
+                .entry(queue_user.party().unwrap_or(user_id))
+                .or_insert(len);
+            // Players with priority count as having waited the longest
+            let wait = if queue_user.priority() {
+                None
+            } else {
+                Some((now - queue_user.joined()).num_seconds().max(0) as f64)
+            };
+            ((user_id, rating), (party, wait))
+        })
+        .unzip();
+    let longest = waits.iter().flatten().copied().fold(0.0, f64::max);
+    let waits = waits
+        .into_iter()
+        .map(|x| match x {
+            Some(x) if longest > 0.0 => x / longest,
+            Some(_) => 0.0,
+            None => 1.0,
+        })
+        .collect::<Vec<_>>();
+    let picked = utils::pick_players(
+        &players,
+        &parties,
+        &waits,
+        lobby.capacity(),
+        lobby.teams(),
+        options.wait,
+        system,
+    )?;
+    Some(
+        picked
+            .into_iter()
+            .filter_map(|i| {
+                let user_id = players[i].0;
+                Some((user_id, lobby.queue_mut().remove(&user_id)?))
+            })
+            .collect(),
+    )
+}
+
+/// Starts a ready check with the players of a full queue, or forms the teams
+/// right away if the lobby has no ready check.
+pub(super) fn start_game(
     ctx: &Context,
     guild_id: GuildId,
     channel_id: ChannelId,
     bridge: ChannelId,
     lobbies: &mut Lobbies,
-    players: Vec<UserId>,
-    trueskill: TrueSkill,
+    queue: HashMap<UserId, QueueUser>,
     database: &Database,
 ) -> Result {
-    let lobby_name = lobbies.get(&channel_id).unwrap().name().to_owned();
-    let players = players
-        .into_iter()
-        .map(|x| {
+    let lobby = lobbies.get_mut(&channel_id).unwrap();
+    if let Some(options) = lobby.ready_options() {
+        let deadline = Utc::now() + Duration::seconds(options.time as i64);
+        lobby.set_ready_check(Some(ReadyCheck::new(guild_id, queue, deadline)));
+        database.save_lobby(channel_id, lobby)?;
+        return ready::announce(ctx, channel_id, lobby.ready_check().unwrap());
+    }
+    form_teams(ctx, guild_id, channel_id, bridge, lobbies, queue, database)
+}
+
+/// Balances the players into teams and starts the game, or starts a draft if
+/// the lobby is in draft mode.
+pub(super) fn form_teams(
+    ctx: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    bridge: ChannelId,
+    lobbies: &mut Lobbies,
+    queue: HashMap<UserId, QueueUser>,
+    database: &Database,
+) -> Result {
+    let lobby = lobbies.get_mut(&channel_id).unwrap();
+    let system = lobby.ratings().system();
+    let (players, parties): (Vec<_>, Vec<_>) = queue
+        .iter()
+        .map(|(&x, queue_user)| {
             (
-                x,
-                lobbies[&channel_id]
-                    .ratings()
-                    .get(&x)
-                    .map(|x| x.rating)
-                    .unwrap_or_else(|| trueskill.create_rating()),
+                (
+                    x,
+                    lobby
+                        .ratings()
+                        .get(&x)
+                        .map(|x| x.rating)
+                        .unwrap_or_else(|| system.create_rating()),
+                ),
+                queue_user.party().unwrap_or(x),
             )
         })
+        .unzip();
+    if let Some(options) = lobby.draft_options() {
+        let deadline = Utc::now() + Duration::seconds(options.pick_time as i64);
+        let draft = match Draft::new(guild_id, players, deadline) {
+            Ok(draft) => draft,
+            Err(err) => {
+                lobby.requeue(queue);
+                database.save_lobby(channel_id, lobby)?;
+                return Err(err.into());
+            }
+        };
+        lobby.set_draft(Some(draft));
+        return draft::advance(ctx, channel_id, bridge, lobbies, database);
+    }
+    let constraints = database.get_pair_constraints()?;
+    let before = database.last_game_id(channel_id)? + 1;
+    let teammates = recent_teammates(lobby, database, channel_id, before)?;
+    let teams = balance(lobby, &players, &parties, &constraints, &teammates);
+    launch_game(ctx, guild_id, channel_id, bridge, lobbies, teams, database)
+}
+
+/// Balances the teams, keeping the players with the same party leader in
+/// `parties` together, honoring the constraints recorded by admins and
+/// applying the party handicap and the teammate variety of the lobby.
+fn balance(
+    lobby: &Lobby,
+    players: &[(UserId, Rating)],
+    parties: &[UserId],
+    constraints: &[(UserId, UserId, PairConstraint)],
+    teammates: &HashMap<(UserId, UserId), usize>,
+) -> Vec<Vec<(UserId, Rating)>> {
+    candidates(lobby, players, parties, constraints, teammates, 1).swap_remove(0)
+}
+
+/// Returns up to `n` distinct splits found by [`balance`], best first.
+fn candidates(
+    lobby: &Lobby,
+    players: &[(UserId, Rating)],
+    parties: &[UserId],
+    constraints: &[(UserId, UserId, PairConstraint)],
+    teammates: &HashMap<(UserId, UserId), usize>,
+    n: usize,
+) -> Vec<Vec<Vec<(UserId, Rating)>>> {
+    let index = |user_id| players.iter().position(|x| x.0 == user_id);
+    let constraints = constraints
+        .iter()
+        .filter_map(|&(a, b, constraint)| Some((index(a)?, index(b)?, constraint)))
+        .collect::<Vec<_>>();
+    let teammates = teammates
+        .iter()
+        .filter_map(|(&(a, b), &games)| Some((index(a)?, index(b)?, games)))
+        .collect::<Vec<_>>();
+    let mut indices = HashMap::new();
+    let parties = parties
+        .iter()
+        .map(|x| {
+            let len = indices.len();
+            *indices.entry(x).or_insert(len)
+        })
+        .collect::<Vec<_>>();
+    let handicap = lobby.party_handicap();
+    let players = players
+        .iter()
+        .zip(parties.iter())
+        .map(|(&(user_id, rating), party)| {
+            let adjusted = if parties.iter().filter(|&x| x == party).count() > 1 {
+                Rating::with_volatility(
+                    rating.mean() + handicap,
+                    rating.variance(),
+                    rating.volatility(),
+                )
+            } else {
+                rating
+            };
+            ((user_id, rating), adjusted)
+        })
         .collect::<Vec<_>>();
-    let teams = utils::balance(&players);
-    let quality = utils::quality(&teams, trueskill);
+    utils::candidates(
+        &players,
+        &parties,
+        &constraints,
+        &teammates,
+        lobby.variety().weight,
+        lobby.teams(),
+        lobby.balance(),
+        lobby.ratings().system(),
+        n,
+    )
+    .into_iter()
+    .map(|teams| {
+        teams
+            .into_iter()
+            .map(|team| team.into_iter().map(|(x, _)| x).collect())
+            .collect()
+    })
+    .collect()
+}
+
+/// Counts the games each pair of players played on the same team among the
+/// last games of the lobby before `before`, if the lobby favors variety.
+fn recent_teammates(
+    lobby: &Lobby,
+    database: &Database,
+    channel_id: ChannelId,
+    before: usize,
+) -> Result<HashMap<(UserId, UserId), usize>> {
+    let mut teammates = HashMap::new();
+    let games = lobby.variety().games;
+    if games == 0 {
+        return Ok(teammates);
+    }
+    for game in database
+        .get_previous_games(channel_id, before, games)?
+        .values()
+    {
+        for team in game.teams() {
+            for (i, &a) in team.iter().enumerate() {
+                for &b in team[i + 1..].iter() {
+                    *teammates.entry(teammate_pair(a, b)).or_default() += 1;
+                }
+            }
+        }
+    }
+    Ok(teammates)
+}
+
+fn teammate_pair(a: UserId, b: UserId) -> (UserId, UserId) {
+    if a.0 <= b.0 {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Number of times the players of every team already played together.
+fn repeated_teammates(
+    teams: &[Vec<UserId>],
+    teammates: &HashMap<(UserId, UserId), usize>,
+) -> usize {
+    teams
+        .iter()
+        .flat_map(|team| {
+            team.iter()
+                .enumerate()
+                .flat_map(move |(i, &a)| team[i + 1..].iter().map(move |&b| teammate_pair(a, b)))
+        })
+        .filter_map(|x| teammates.get(&x))
+        .sum()
+}
+
+/// Lists the constraints that the teams could not satisfy, if any.
+fn describe_broken_constraints(
+    teams: &[Vec<UserId>],
+    constraints: &[(UserId, UserId, PairConstraint)],
+) -> String {
+    let broken = utils::broken_constraints(teams, constraints);
+    if broken.is_empty() {
+        return String::new();
+    }
+    format!(
+        "\n\nCould not satisfy:\n{}",
+        broken
+            .into_iter()
+            .map(|(user1, user2, constraint)| constraint.describe(user1, user2))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// Lists the players of every team, formatted by `f`.
+fn describe_teams<T>(teams: &[T], f: impl Fn(&T) -> String) -> String {
+    teams
+        .iter()
+        .enumerate()
+        .map(|(i, team)| format!("Team {}:\n{}", i + 1, f(team)))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Lets the players of `teams` vote for the map if the lobby has a map pool,
+/// otherwise starts the game right away.
+pub(super) fn launch_game(
+    ctx: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    bridge: ChannelId,
+    lobbies: &mut Lobbies,
+    teams: Vec<Vec<(UserId, Rating)>>,
+    database: &Database,
+) -> Result {
+    let lobby = &lobbies[&channel_id];
+    let (mut choices, time) = match lobby.map_vote_options() {
+        Some(options) => (vote::choices(database, channel_id, options)?, options.time),
+        None => (Vec::new(), 0),
+    };
+    if choices.len() <= 1 {
+        let map = choices.pop();
+        play_game(
+            ctx,
+            guild_id,
+            channel_id,
+            Some(bridge),
+            lobbies,
+            teams,
+            map,
+            database,
+        )?;
+        return Ok(());
+    }
+    // The game starts right away, the bridge is told once its map is known
+    let players = teams.iter().flatten().map(|x| x.0).collect();
+    let game_id = play_game(
+        ctx, guild_id, channel_id, None, lobbies, teams, None, database,
+    )?;
+    let lobby = lobbies.get_mut(&channel_id).unwrap();
+    let deadline = Utc::now() + Duration::seconds(time as i64);
+    lobby
+        .map_votes_mut()
+        .push(MapVote::new(guild_id, game_id, players, choices, deadline));
+    database.save_lobby(channel_id, lobby)?;
+    vote::announce(ctx, channel_id, lobby.map_votes().last().unwrap())
+}
+
+/// Saves the game played by `teams` on `map` and notifies the players, and
+/// the bridge unless it is `None`. Returns the id of the game.
+#[allow(clippy::too_many_arguments)]
+fn play_game(
+    ctx: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    bridge: Option<ChannelId>,
+    lobbies: &mut Lobbies,
+    teams: Vec<Vec<(UserId, Rating)>>,
+    map: Option<String>,
+    database: &Database,
+) -> Result<usize> {
+    let lobby_name = lobbies[&channel_id].name().to_owned();
+    let system = lobbies[&channel_id].ratings().system();
+    let quality = utils::quality(&teams, system);
     let mut game = Game::create(
-        teams[0].iter().map(|x| x.0).collect(),
-        teams[1].iter().map(|x| x.0).collect(),
+        teams
+            .iter()
+            .map(|team| team.iter().map(|x| x.0).collect())
+            .collect(),
         Utc::now(),
     );
+    game.set_map(map);
     database.insert_game(&mut game, channel_id)?;
-    let f = |users: &[(UserId, _)]| {
+    let f = |users: &Vec<UserId>| {
         users
             .iter()
-            .map(|x| x.0.mention())
+            .map(|x| x.mention())
             .collect::<Vec<_>>()
             .join("\n")
     };
     let title = format!("Game {} started", game.id());
-    let description = format!(
-        "Quality: {:.0}\n\nTeam 1:\n{}\n\nTeam 2:\n{}",
+    let mut description = format!(
+        "Quality: {:.0}{}\n\n{}",
         100.0 * quality,
-        f(&teams[0]),
-        f(&teams[1])
+        describe_map(&game),
+        describe_teams(game.teams(), f)
     );
+    description += &describe_broken_constraints(game.teams(), &database.get_pair_constraints()?);
     rayon::scope(|s| {
         // Send game started message
         s.spawn(|_| {
-            let content = teams[0]
+            let content = teams
                 .iter()
-                .chain(teams[1].iter())
+                .flatten()
                 .map(|(x, _)| x.mention())
                 .collect::<Vec<_>>()
                 .join(" ");
@@ -371,30 +954,7 @@ fn start_game(
                             .hoist(true)
                     })?
                     .id;
-                teams[0]
-                    .par_iter()
-                    .chain(teams[1].par_iter())
-                    .for_each(|(user_id, _)| {
-                        if let Err(err) = ctx.add_guild_member_role(guild_id, *user_id, role_id) {
-                            eprintln!("Err: {:?}", err);
-                        }
-                    });
-                Result::Ok(())
-            })() {
-                eprintln!("Err: {:?}", err);
-            }
-        });
-        // Create team 1 role
-        s.spawn(|_| {
-            if let Err(err) = (|| {
-                let role_id = ctx
-                    .create_guild_role(guild_id, |r| {
-                        r.name(format!("{} Game {} Team 1", lobby_name, game.id()))
-                            .mentionable(true)
-                            .hoist(true)
-                    })?
-                    .id;
-                teams[0].par_iter().for_each(|(user_id, _)| {
+                teams.par_iter().flatten().for_each(|(user_id, _)| {
                     if let Err(err) = ctx.add_guild_member_role(guild_id, *user_id, role_id) {
                         eprintln!("Err: {:?}", err);
                     }
@@ -404,55 +964,48 @@ fn start_game(
                 eprintln!("Err: {:?}", err);
             }
         });
-        // Create team 2 role
+        // Create team roles, which are pointless in free-for-all games
         s.spawn(|_| {
-            if let Err(err) = (|| {
-                let role_id = ctx
-                    .create_guild_role(guild_id, |r| {
-                        r.name(format!("{} Game {} Team 2", lobby_name, game.id()))
-                            .mentionable(true)
-                            .hoist(true)
-                    })?
-                    .id;
-                teams[1].par_iter().for_each(|(user_id, _)| {
-                    if let Err(err) = ctx.add_guild_member_role(guild_id, *user_id, role_id) {
-                        eprintln!("Err: {:?}", err);
-                    }
-                });
-                Result::Ok(())
-            })() {
-                eprintln!("Err: {:?}", err);
+            if teams.iter().all(|team| team.len() == 1) {
+                return;
             }
+            teams.par_iter().enumerate().for_each(|(i, team)| {
+                if let Err(err) = (|| {
+                    let role_id = ctx
+                        .create_guild_role(guild_id, |r| {
+                            r.name(format!("{} Game {} Team {}", lobby_name, game.id(), i + 1))
+                                .mentionable(true)
+                                .hoist(true)
+                        })?
+                        .id;
+                    team.par_iter().for_each(|(user_id, _)| {
+                        if let Err(err) = ctx.add_guild_member_role(guild_id, *user_id, role_id) {
+                            eprintln!("Err: {:?}", err);
+                        }
+                    });
+                    Result::Ok(())
+                })() {
+                    eprintln!("Err: {:?}", err);
+                }
+            });
         });
         // Send "GAME_STARTED" message to bridge
         s.spawn(|_| {
-            let bridge_event = GameStarted {
-                players: game.teams()[0]
-                    .iter()
-                    .copied()
-                    .chain(game.teams()[1].iter().copied())
-                    .collect(),
-            };
-            if let Err(err) = ctx.create_message(bridge, |m| {
-                m.content(json!({
-                    "t": OpCode::GameStarted,
-                    "d": bridge_event,
-                }))
-            }) {
-                eprintln!("Err: {:?}", err);
+            if let Some(bridge) = bridge {
+                send_game_started(ctx, bridge, &game);
             }
         });
         // Remove players from other lobbies
         s.spawn(|_| {
             lobbies.par_iter_mut().for_each(|(channel_id, lobby)| {
-                for (user_id, _) in players.iter() {
+                for (user_id, _) in teams.iter().flatten() {
                     if lobby.leave(*user_id, true).is_ok() {
                         if let Err(err) = ctx.create_message(*channel_id, |m| {
                             m.embed(|e| {
                                 e.description(format!(
                                     "[{}/{}] {} left the queue (Game started).",
                                     lobby.len(),
-                                    lobby.capacity(),
+                                    lobby.queue_capacity(),
                                     user_id.mention(),
                                 ))
                             })
@@ -464,10 +1017,37 @@ fn start_game(
             });
         });
     });
-    Ok(())
+    for (channel_id, lobby) in lobbies.iter() {
+        database.save_lobby(*channel_id, lobby)?;
+    }
+    Ok(game.id())
 }
 
-pub fn freeze(ctx: &Context, msg: &Message, roles: &Roles, lobbies: &mut Lobbies) -> Result {
+/// Sends the "GAME_STARTED" message of a game to the bridge.
+pub(super) fn send_game_started(ctx: &Context, bridge: ChannelId, game: &Game) {
+    let bridge_event = GameStarted {
+        players: game.teams().iter().flatten().copied().collect(),
+        map: game.map().map(str::to_owned),
+    };
+    if let Err(err) = ctx.create_message(bridge, |m| {
+        m.content(json!({
+            "t": OpCode::GameStarted,
+            "d": bridge_event,
+        }))
+    }) {
+        eprintln!("Err: {:?}", err);
+    }
+}
+
+pub fn freeze(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
     let guild_id = checks::get_guild(msg)?;
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
@@ -475,14 +1055,34 @@ pub fn freeze(ctx: &Context, msg: &Message, roles: &Roles, lobbies: &mut Lobbies
     let lobby = lobbies
         .get_mut(&msg.channel_id)
         .ok_or(Error::NotALobby(msg.channel_id))?;
+    let previous = if lobby.is_frozen() {
+        "frozen"
+    } else {
+        "unfrozen"
+    };
     lobby.freeze();
+    database.save_lobby(msg.channel_id, lobby)?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "freeze", args).change(previous, "frozen"),
+    )?;
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| e.description("Queue frozen."))
     })?;
     Ok(())
 }
 
-pub fn unfreeze(ctx: &Context, msg: &Message, roles: &Roles, lobbies: &mut Lobbies) -> Result {
+pub fn unfreeze(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
     let guild_id = checks::get_guild(msg)?;
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
@@ -490,7 +1090,20 @@ pub fn unfreeze(ctx: &Context, msg: &Message, roles: &Roles, lobbies: &mut Lobbi
     let lobby = lobbies
         .get_mut(&msg.channel_id)
         .ok_or(Error::NotALobby(msg.channel_id))?;
+    let previous = if lobby.is_frozen() {
+        "frozen"
+    } else {
+        "unfrozen"
+    };
     lobby.unfreeze();
+    database.save_lobby(msg.channel_id, lobby)?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "unfreeze", args)
+            .change(previous, "unfrozen"),
+    )?;
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| e.description("Queue unfrozen."))
     })?;
@@ -502,15 +1115,27 @@ pub fn queue(ctx: &Context, msg: &Message, lobbies: &Lobbies) -> Result {
         .get(&msg.channel_id)
         .ok_or(Error::NotALobby(msg.channel_id))?;
     let description = lobby
-        .queue()
-        .keys()
-        .map(|x| x.mention())
+        .order()
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let priority = if lobby.queue()[&x].priority() {
+                " (Priority)"
+            } else {
+                ""
+            };
+            format!("{}. {}{}", i + 1, x.mention(), priority)
+        })
         .collect::<Vec<_>>()
         .join("\n");
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| {
-            e.title(format!("Queue [{}/{}]", lobby.len(), lobby.capacity()))
-                .description(description)
+            e.title(format!(
+                "Queue [{}/{}]",
+                lobby.len(),
+                lobby.queue_capacity()
+            ))
+            .description(description)
         })
     })?;
     Ok(())
@@ -522,16 +1147,16 @@ pub fn score(
     msg: &Message,
     roles: &Roles,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
     ranks: &[Rank],
+    log: Option<ChannelId>,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
     }
-    let (webhook, lobby_name, leaderboard, game, score, old_ratings, new_ratings, members_roles) = {
+    let (webhook, lobby_name, leaderboard, game, old_ratings, new_ratings, members_roles) = {
         let lobby = lobbies
             .get_mut(&msg.channel_id)
             .ok_or(Error::NotALobby(msg.channel_id))?;
@@ -540,13 +1165,7 @@ pub fn score(
             return Err(Error::NotEnoughArguments);
         }
         let game_id = args[0].parse()?;
-        let score = match args[1].to_lowercase().as_ref() {
-            "1" => Score::Team1,
-            "2" => Score::Team2,
-            "draw" | "d" => Score::Draw,
-            _ => return Err(Error::BadArgument),
-        };
-        let mut game = match database.get_game(msg.channel_id.0, game_id) {
+        let mut game = match database.get_game(msg.channel_id, game_id) {
             Ok(game) => game,
             Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error::GameNotFound(game_id)),
             Err(err) => return Err(err.into()),
@@ -554,76 +1173,65 @@ pub fn score(
         if game.score() != Score::Undecided {
             return Err(Error::GameAlreadySet);
         }
-        game.set_score(score);
+        game.set_places(parse_places(&args[1..], game.teams().len())?);
+        // A game scored again after an undo keeps its first end, so that the
+        // play shares of its substitutes do not change
+        if game.ended().is_none() {
+            game.set_ended(Some(Utc::now()));
+        }
         database.update_game(&game, msg.channel_id)?;
-        let teams = game.teams();
-        let default_rating = trueskill.create_rating();
-        let old_ratings: [Vec<f64>; 2] = [
-            teams[0]
-                .iter()
-                .map(|x| {
-                    lobby
-                        .ratings()
-                        .get(x)
-                        .map(|x| x.rating)
-                        .unwrap_or(default_rating)
-                        .mean()
-                })
-                .collect(),
-            teams[1]
-                .iter()
-                .map(|x| {
-                    lobby
-                        .ratings()
-                        .get(x)
-                        .map(|x| x.rating)
-                        .unwrap_or(default_rating)
-                        .mean()
-                })
-                .collect(),
-        ];
-        let initial_ratings = database.get_initial_ratings()?;
-        let games = database
-            .get_games()?
-            .remove(&msg.channel_id)
-            .unwrap_or_default();
-        let ratings = Ratings::from_games(&games, &initial_ratings, trueskill);
-        lobby.set_ratings(ratings);
-        let new_ratings: [Vec<f64>; 2] = [
-            teams[0]
-                .iter()
-                .map(|x| {
-                    lobby
-                        .ratings()
-                        .get(x)
-                        .map(|x| x.rating)
-                        .unwrap_or(default_rating)
-                        .mean()
-                })
-                .collect(),
-            teams[1]
+        audit::record(
+            ctx,
+            database,
+            log,
+            AuditEntry::new(msg.author.id, msg.channel_id, "score", args)
+                .game(game_id)
+                .change(Score::Undecided, game.result()),
+        )?;
+        let default_rating = lobby.ratings().system().create_rating();
+        let means = |lobby: &Lobby| {
+            game.teams()
                 .iter()
-                .map(|x| {
-                    lobby
-                        .ratings()
-                        .get(x)
-                        .map(|x| x.rating)
-                        .unwrap_or(default_rating)
-                        .mean()
+                .map(|team| {
+                    team.iter()
+                        .map(|x| {
+                            lobby
+                                .ratings()
+                                .get(x)
+                                .map(|x| x.rating)
+                                .unwrap_or(default_rating)
+                                .mean()
+                        })
+                        .collect::<Vec<_>>()
                 })
-                .collect(),
-        ];
+                .collect::<Vec<_>>()
+        };
+        let old_ratings = means(lobby);
+        if lobby.ratings().is_latest(game_id) {
+            if let Some(game_ratings) = lobby.ratings_mut().apply(&game) {
+                database.save_game_ratings(msg.channel_id, game_id, &[game_ratings])?;
+            }
+        } else {
+            replay_ratings(lobby, database, msg.channel_id, game_id)?;
+        }
+        let new_ratings = means(lobby);
         let members = ctx.list_guild_members(guild_id)?;
         let members_roles = members
             .into_iter()
             .map(|x| (x.user.id, x.roles))
             .collect::<HashMap<_, _>>();
-        let leaderboard = utils::leaderboard(lobby, 15, ranks, |user_id| {
-            Ok(members_roles
-                .get(&user_id)
-                .map(|x| x.contains(&roles.ranked))
-                .unwrap_or(false))
-        })?;
+        let leaderboard = utils::leaderboard(
+            lobby.ratings(),
+            lobby.leaderboard_options(),
+            15,
+            ranks,
+            |user_id| {
+                Ok(members_roles
+                    .get(&user_id)
+                    .map(|x| x.contains(&roles.ranked))
+                    .unwrap_or(false))
+            },
+        )?;
         let webhook = if let Some(webhook) = lobby.webhook_mut() {
             let messages = std::mem::take(&mut webhook.2);
             Some((webhook.0, webhook.1.clone(), messages))
@@ -635,39 +1243,24 @@ pub fn score(
             lobby_name,
             leaderboard,
             game,
-            score,
             old_ratings,
             new_ratings,
             members_roles,
         )
     };
     let mut new_messages = Vec::new();
+    let mut orphaned = (Vec::new(), None);
     rayon::scope(|s| {
         s.spawn(|_| {
             if let Some((webhook_id, webhook_token, messages)) = webhook {
-                messages.par_iter().for_each(|&message| {
-                    if let Err(err) =
-                        ctx.delete_webhook_message(webhook_id, &webhook_token, message)
-                    {
-                        eprintln!("Err: {:?}", err);
-                    }
-                });
-                // messages.clear();
-                for (title, description) in leaderboard.iter() {
-                    let message = ctx.execute_webhook(webhook_id, &webhook_token, true, |m| {
-                        m.embed(|e| e.description(description).title(title))
-                    });
-                    let message = match message {
-                        Ok(message) => message,
-                        Err(err) => {
-                            eprintln!("Err: {:?}", err);
-                            continue;
-                        }
-                    };
-                    if let Some(message) = message {
-                        new_messages.push(message.id);
-                    }
-                }
+                new_messages = messages;
+                orphaned = utils::post_leaderboard(
+                    ctx,
+                    webhook_id,
+                    &webhook_token,
+                    &mut new_messages,
+                    &leaderboard,
+                );
             }
         });
         s.spawn(|_| {
@@ -688,7 +1281,7 @@ pub fn score(
             }
         });
         s.spawn(|_| {
-            game.teams().into_par_iter().flatten().for_each(|&user_id| {
+            game.participants().par_iter().for_each(|&user_id| {
                 let user_roles = members_roles.get(&user_id).cloned().unwrap_or_default();
                 if !user_roles.contains(&roles.ranked) {
                     for rank in ranks {
@@ -776,11 +1369,15 @@ pub fn score(
                     .collect::<Vec<_>>()
                     .join("\n")
             };
+            let teams = game
+                .teams()
+                .iter()
+                .zip(old_ratings.iter().zip(new_ratings.iter()))
+                .collect::<Vec<_>>();
             let description = format!(
-                "**{}**\n\nTeam 1:\n{}\n\nTeam 2:\n{}",
-                score,
-                f(game.teams()[0], &old_ratings[0], &new_ratings[0]),
-                f(game.teams()[1], &old_ratings[1], &new_ratings[1])
+                "**{}**\n\n{}",
+                game.result(),
+                describe_teams(&teams, |(team, (old, new))| f(team, old, new))
             );
             if let Err(err) = ctx.create_message(msg.channel_id, |m| {
                 m.embed(|e| {
@@ -794,18 +1391,64 @@ pub fn score(
     });
     if let Some(lobby) = lobbies.get_mut(&msg.channel_id) {
         if let Some(webhook) = lobby.webhook_mut() {
+            database.save_webhook_messages(
+                msg.channel_id,
+                &new_messages,
+                &orphaned.0,
+                orphaned.1,
+            )?;
             webhook.2 = new_messages;
         }
     }
     Ok(())
 }
 
+/// Parses the result of a game between `teams` teams into the finishing
+/// place of every team. The result is either `draw`, the winning team, or
+/// the teams in finishing order with `=` between tied teams, e.g. `3 1=2`.
+fn parse_places(args: &[String], teams: usize) -> Result<Vec<usize>> {
+    match args {
+        [] => return Err(Error::NotEnoughArguments),
+        [arg] if matches!(arg.to_lowercase().as_ref(), "draw" | "d") => return Ok(vec![0; teams]),
+        [arg] => {
+            // The other teams tie for second place
+            let winner = arg.parse::<usize>()?;
+            if winner == 0 || winner > teams {
+                return Err(Error::BadArgument);
+            }
+            let mut places = vec![1; teams];
+            places[winner - 1] = 0;
+            return Ok(places);
+        }
+        _ => (),
+    }
+    let mut places = vec![None; teams];
+    for (place, arg) in args.iter().enumerate() {
+        for team in arg.split('=') {
+            let team = team.parse::<usize>()?;
+            match places.get_mut(team.wrapping_sub(1)) {
+                Some(x @ None) => *x = Some(place),
+                _ => return Err(Error::BadArgument),
+            }
+        }
+    }
+    places
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::BadArgument)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn cancel(
     ctx: &Context,
     msg: &Message,
     roles: &Roles,
-    lobbies: &Lobbies,
+    lobbies: &mut Lobbies,
     database: &Database,
+    bridge: ChannelId,
+    log: Option<ChannelId>,
+    timeout: u64,
+    warn: u64,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
@@ -821,12 +1464,20 @@ pub fn cancel(
         return Err(Error::NotEnoughArguments);
     }
     let game_id = args[0].parse()?;
-    let mut game = database.get_game(msg.channel_id.into(), game_id)?;
+    let mut game = database.get_game(msg.channel_id, game_id)?;
     if game.score() != Score::Undecided {
         return Err(Error::GameAlreadySet);
     }
     game.set_score(Score::Cancelled);
     database.update_game(&game, msg.channel_id)?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "cancel", args)
+            .game(game_id)
+            .change(Score::Undecided, Score::Cancelled),
+    )?;
     ctx.get_guild_roles(guild_id)?.par_iter().for_each(|role| {
         if role
             .name
@@ -840,6 +1491,112 @@ pub fn cancel(
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| e.description(format!("Game {} cancelled.", game_id)))
     })?;
+    if !lobby.requeue_cancelled() {
+        return Ok(());
+    }
+    let expire = msg.timestamp + Duration::minutes(timeout as i64);
+    requeue_cancelled(
+        ctx,
+        guild_id,
+        msg.channel_id,
+        bridge,
+        roles,
+        lobbies,
+        &game,
+        expire,
+        Some(expire - Duration::minutes(warn as i64)),
+        database,
+    )
+}
+
+/// Puts the players of a cancelled game back at the front of the queue, with
+/// priority over the others. The players who could not join it are left out.
+#[allow(clippy::too_many_arguments)]
+fn requeue_cancelled(
+    ctx: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    bridge: ChannelId,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    game: &Game,
+    expire: DateTime<Utc>,
+    warn: Option<DateTime<Utc>>,
+    database: &Database,
+) -> Result {
+    if lobbies[&channel_id].is_frozen() {
+        return Ok(());
+    }
+    let mut playing = HashSet::new();
+    for &lobby_id in lobbies.keys() {
+        for game in database.get_undecided_games(lobby_id)?.values() {
+            playing.extend(game.teams().iter().flatten().copied());
+        }
+    }
+    let now = Utc::now();
+    let mut players = HashMap::new();
+    let mut refused = Vec::new();
+    for &user_id in game.teams().iter().flatten() {
+        let lobby = &lobbies[&channel_id];
+        if let Some(queue_user) = lobby.queue().get(&user_id) {
+            players.insert(user_id, queue_user.clone().with_priority(true));
+            continue;
+        }
+        if lobby.is_queued(user_id) || checks::has_role(ctx, guild_id, user_id, roles.banned)? {
+            continue;
+        }
+        let result = check_pending(lobbies, &[user_id])
+            .and_then(|_| match lobby.penalties().get(&user_id) {
+                Some(&until) if until > now => Err(LobbyError::Penalized(user_id, until).into()),
+                _ if playing.contains(&user_id) => Err(LobbyError::Pending(user_id).into()),
+                _ => Ok(()),
+            })
+            .and_then(|_| gate::check(ctx, guild_id, channel_id, lobbies, database, user_id));
+        match result {
+            Ok(()) => {
+                let queue_user = QueueUser::new(now, expire, warn).with_priority(true);
+                players.insert(user_id, queue_user);
+            }
+            Err(err) => refused.push(err.to_string()),
+        }
+    }
+    if players.is_empty() {
+        if !refused.is_empty() {
+            let description = refused.join("\n");
+            ctx.create_message(channel_id, |m| m.embed(|e| e.description(description)))?;
+        }
+        return Ok(());
+    }
+    let lobby = lobbies.get_mut(&channel_id).unwrap();
+    let f = |users: &[UserId]| {
+        users
+            .iter()
+            .map(|x| x.mention())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let requeued = players.keys().copied().collect::<Vec<_>>();
+    let removed = lobby.requeue(players);
+    let mut description = format!(
+        "[{}/{}] {} joined the queue with priority (Game {} cancelled).",
+        lobby.len(),
+        lobby.queue_capacity(),
+        f(&requeued),
+        game.id()
+    );
+    if !removed.is_empty() {
+        description += &format!("\n{} left the queue (Queue full).", f(&removed));
+    }
+    for line in refused {
+        description += &format!("\n{}", line);
+    }
+    database.save_lobby(channel_id, lobby)?;
+    ctx.create_message(channel_id, |m| m.embed(|e| e.description(description)))?;
+    if let Some(players) = next_players(ctx, channel_id, lobbies, database)? {
+        start_game(
+            ctx, guild_id, channel_id, bridge, lobbies, players, database,
+        )?;
+    }
     Ok(())
 }
 
@@ -850,8 +1607,8 @@ pub fn undo(
     roles: &Roles,
     lobbies: &mut Lobbies,
     database: &Database,
-    trueskill: TrueSkill,
     ranks: &[Rank],
+    log: Option<ChannelId>,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
@@ -865,45 +1622,48 @@ pub fn undo(
         return Err(Error::NotEnoughArguments);
     }
     let game_id = args[0].parse()?;
-    let initial_ratings = database.get_initial_ratings()?;
-    let mut game = match database.get_game(msg.channel_id.0, game_id) {
+    let mut game = match database.get_game(msg.channel_id, game_id) {
         Ok(game) => game,
         Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error::GameNotFound(game_id)),
         Err(err) => return Err(err.into()),
     };
     let prev_score = game.score();
+    if prev_score == Score::Undecided {
+        return Err(Error::GameUndecided(game_id));
+    }
+    // The players of a cancelled game may have been picked for another one
+    if prev_score == Score::Cancelled && lobby.requeue_cancelled() {
+        return Err(Error::GameRequeued(game_id));
+    }
+    let previous = game.result();
     game.set_score(Score::Undecided);
     database.update_game(&game, msg.channel_id)?;
-    let games = database
-        .get_games()?
-        .remove(&msg.channel_id)
-        .unwrap_or_default();
-    let ratings = Ratings::from_games(&games, &initial_ratings, trueskill);
-    lobby.set_ratings(ratings);
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "undo", args)
+            .game(game_id)
+            .change(previous, Score::Undecided),
+    )?;
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| e.description(format!("Game {} undone.", game_id)))
     })?;
-    if prev_score == Score::Cancelled || prev_score == Score::Undecided {
+    if prev_score == Score::Cancelled {
         return Ok(());
     }
-    let leaderboard = utils::leaderboard(lobby, 15, ranks, |user_id| {
-        checks::has_role(ctx, guild_id, user_id, roles.ranked)
-    })?;
+    replay_ratings(lobby, database, msg.channel_id, game_id)?;
+    let leaderboard = utils::leaderboard(
+        lobby.ratings(),
+        lobby.leaderboard_options(),
+        15,
+        ranks,
+        |user_id| checks::has_role(ctx, guild_id, user_id, roles.ranked),
+    )?;
     if let Some((webhook_id, webhook_token, messages)) = lobby.webhook_mut() {
-        messages.par_iter().for_each(|&message| {
-            if let Err(err) = ctx.delete_webhook_message(*webhook_id, webhook_token, message) {
-                eprintln!("Err: {:?}", err);
-            }
-        });
-        messages.clear();
-        for (title, description) in leaderboard.iter() {
-            let message = ctx.execute_webhook(*webhook_id, webhook_token, true, |m| {
-                m.embed(|e| e.description(description).title(title))
-            })?;
-            if let Some(message) = message {
-                messages.push(message.id);
-            }
-        }
+        let (orphaned, webhook_channel) =
+            utils::post_leaderboard(ctx, *webhook_id, webhook_token, messages, &leaderboard);
+        database.save_webhook_messages(msg.channel_id, messages, &orphaned, webhook_channel)?;
     }
     Ok(())
 }
@@ -912,18 +1672,14 @@ pub fn gamelist(ctx: &Context, msg: &Message, lobbies: &Lobbies, database: &Data
     if lobbies.get(&msg.channel_id).is_none() {
         return Err(Error::NotALobby(msg.channel_id));
     }
-    let games = database
-        .get_games()?
-        .remove(&msg.channel_id)
-        .unwrap_or_default();
+    let games = database.get_last_games(msg.channel_id, 20)?;
     if games.is_empty() {
         return Err(Error::GameNotFound(1));
     }
     let description = games
         .into_iter()
         .rev()
-        .take(20)
-        .map(|(id, game)| format!("Game {}: {}", id, game.score()))
+        .map(|(id, game)| format!("Game {}: {}", id, game.result()))
         .collect::<Vec<_>>()
         .join("\n");
     ctx.create_message(msg.channel_id, |m| m.embed(|e| e.description(description)))?;
@@ -934,25 +1690,240 @@ pub fn lastgame(ctx: &Context, msg: &Message, lobbies: &Lobbies, database: &Data
     if lobbies.get(&msg.channel_id).is_none() {
         return Err(Error::NotALobby(msg.channel_id));
     }
-    let games = database.get_games()?;
-    let game = match games.get(&msg.channel_id).and_then(|x| x.values().last()) {
+    let game = match database.get_last_game(msg.channel_id)? {
         Some(game) => game,
         None => return Err(Error::GameNotFound(1)),
     };
+    let game_ratings = database.get_game_ratings(msg.channel_id, game.id())?;
     let title = format!("Game {}", game.id());
-    let f = |users: &[UserId]| {
+    let description = describe_game(&game, game_ratings.as_ref());
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| {
+            e.title(title)
+                .description(description)
+                .timestamp(game.datetime())
+        })
+    })?;
+    Ok(())
+}
+
+pub fn gameinfo(
+    ctx: &Context,
+    msg: &Message,
+    lobbies: &Lobbies,
+    database: &Database,
+    args: &[String],
+) -> Result {
+    if lobbies.get(&msg.channel_id).is_none() {
+        return Err(Error::NotALobby(msg.channel_id));
+    }
+    if args.is_empty() {
+        return Err(Error::NotEnoughArguments);
+    }
+    let game_id = args[0].parse()?;
+    let game = match database.get_game(msg.channel_id, game_id) {
+        Ok(game) => game,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error::GameNotFound(game_id)),
+        Err(err) => return Err(err.into()),
+    };
+    let game_ratings = database.get_game_ratings(msg.channel_id, game.id())?;
+    let title = format!("Game {}", game.id());
+    let description = describe_game(&game, game_ratings.as_ref());
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| {
+            e.title(title)
+                .description(description)
+                .timestamp(game.datetime())
+        })
+    })?;
+    Ok(())
+}
+
+fn describe_game(game: &Game, game_ratings: Option<&GameRatings>) -> String {
+    let game_ratings = if let Some(game_ratings) = game_ratings {
+        game_ratings
+    } else {
+        let f = |users: &Vec<UserId>| {
+            users
+                .iter()
+                .map(|x| x.mention())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        return format!(
+            "**{}**{}\n\n{}",
+            game.result(),
+            describe_map(game),
+            describe_teams(game.teams(), f)
+        );
+    };
+    let players = game_ratings
+        .players
+        .iter()
+        .map(|(user_id, before, after)| (user_id, (before, after)))
+        .collect::<HashMap<_, _>>();
+    let f = |users: &Vec<UserId>| {
+        users
+            .iter()
+            .map(|x| match players.get(x) {
+                Some((before, after)) => format!(
+                    "{} {:.0} ± {:.0} → {:.0} ± {:.0} ({:+.0})",
+                    x.mention(),
+                    before.mean(),
+                    before.variance().sqrt(),
+                    after.mean(),
+                    after.variance().sqrt(),
+                    after.mean() - before.mean()
+                ),
+                None => x.mention(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let win_probability = match game_ratings.win_probability {
+        Some(win_probability) => {
+            format!("\nTeam 1 win probability: {:.0}%", 100.0 * win_probability)
+        }
+        None => String::new(),
+    };
+    format!(
+        "**{}**{}\nQuality: {:.0}{}\n\n{}",
+        game.result(),
+        describe_map(game),
+        100.0 * game_ratings.quality,
+        win_probability,
+        describe_teams(game.teams(), f)
+    )
+}
+
+fn describe_map(game: &Game) -> String {
+    match game.map() {
+        Some(map) => format!("\nMap: {}", map),
+        None => String::new(),
+    }
+}
+
+pub fn clear(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let previous = lobby
+        .clear()
+        .into_keys()
+        .map(|x| x.mention())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let previous = if previous.is_empty() {
+        String::from("empty")
+    } else {
+        previous
+    };
+    database.save_lobby(msg.channel_id, lobby)?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "clear", args).change(previous, "empty"),
+    )?;
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| e.description("Queue cleared"))
+    })?;
+    Ok(())
+}
+
+pub fn rebalance(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    parties: &Parties,
+    database: &Database,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let n = match args.first() {
+        Some(arg) => arg.parse::<usize>()?.clamp(1, MAX_SPLITS),
+        None => DEFAULT_SPLITS,
+    };
+    let game = match database.get_last_game(msg.channel_id)? {
+        Some(game) => game,
+        None => return Ok(()),
+    };
+    if game.score() != Score::Undecided {
+        return Err(Error::GameAlreadySet);
+    }
+    let current = rated_teams(lobby, game.teams());
+    let players = current.iter().flatten().copied().collect::<Vec<_>>();
+    let leaders = players
+        .iter()
+        .map(|&(x, _)| parties.get(x).map(|party| party.leader()).unwrap_or(x))
+        .collect::<Vec<_>>();
+    let constraints = database.get_pair_constraints()?;
+    let teammates = recent_teammates(lobby, database, msg.channel_id, game.id())?;
+    let splits = candidates(lobby, &players, &leaders, &constraints, &teammates, n);
+    let system = lobby.ratings().system();
+    let repeated = |teams: &[Vec<UserId>]| repeated_teammates(teams, &teammates);
+    let variety = lobby.variety().games > 0;
+    let f = |users: &Vec<(UserId, Rating)>| {
         users
             .iter()
-            .map(|x| x.mention())
+            .map(|x| x.0.mention())
             .collect::<Vec<_>>()
-            .join("\n")
+            .join(", ")
     };
-    let description = format!(
-        "**{}**\n\nTeam 1:\n{}\n\nTeam 2:\n{}",
-        game.score(),
-        f(game.teams()[0]),
-        f(game.teams()[1])
+    let mut description = format!(
+        "Current teams: quality {:.0}, mean difference {:.1}",
+        100.0 * utils::quality(&current, system),
+        utils::mean_difference(&current)
     );
+    if variety {
+        description += &format!(", repeated teammates {}", repeated(game.teams()));
+    }
+    for (i, teams) in splits.iter().enumerate() {
+        description += &format!(
+            "\n\n**Split {}**\nQuality: {:.0}\nMean difference: {:.1}",
+            i + 1,
+            100.0 * utils::quality(teams, system),
+            utils::mean_difference(teams)
+        );
+        // Repeated teammates compared to the current teams, negative when
+        // the split gains variety
+        if variety {
+            let count = repeated(&user_teams(teams));
+            description += &format!(
+                "\nRepeated teammates: {} ({:+})",
+                count,
+                count as i64 - repeated(game.teams()) as i64
+            );
+        }
+        description += &format!("\n{}", describe_teams(teams, f));
+        description += &describe_broken_constraints(&user_teams(teams), &constraints);
+    }
+    description += "\n\nUse the split command with a number to apply a split.";
+    lobby.set_rebalance(Some(Rebalance {
+        game_id: game.id(),
+        teams: game.teams().to_vec(),
+        splits: splits.iter().map(|teams| user_teams(teams)).collect(),
+    }));
+    let title = format!("Game {}", game.id());
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| {
             e.title(title)
@@ -963,39 +1934,77 @@ pub fn lastgame(ctx: &Context, msg: &Message, lobbies: &Lobbies, database: &Data
     Ok(())
 }
 
-pub fn gameinfo(
+/// Applies one of the splits proposed by the last rebalance.
+#[allow(clippy::too_many_arguments)]
+pub fn split(
     ctx: &Context,
     msg: &Message,
-    lobbies: &Lobbies,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
     database: &Database,
+    log: Option<ChannelId>,
     args: &[String],
 ) -> Result {
-    if lobbies.get(&msg.channel_id).is_none() {
-        return Err(Error::NotALobby(msg.channel_id));
-    }
-    if args.is_empty() {
-        return Err(Error::NotEnoughArguments);
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
     }
-    let game_id = args[0].parse()?;
-    let game = match database.get_game(msg.channel_id.into(), game_id) {
-        Ok(game) => game,
-        Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error::GameNotFound(game_id)),
-        Err(err) => return Err(err.into()),
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let index = args
+        .first()
+        .ok_or(Error::NotEnoughArguments)?
+        .parse::<usize>()?;
+    let mut game = match database.get_last_game(msg.channel_id)? {
+        Some(game) => game,
+        None => return Ok(()),
     };
-    let title = format!("Game {}", game.id());
-    let f = |users: &[UserId]| {
+    if game.score() != Score::Undecided {
+        return Err(Error::GameAlreadySet);
+    }
+    // Splits proposed before the teams last changed are outdated
+    let rebalance = lobby
+        .rebalance()
+        .filter(|x| x.game_id == game.id() && x.teams == game.teams())
+        .ok_or(Error::NoRebalance)?;
+    let teams = index
+        .checked_sub(1)
+        .and_then(|i| rebalance.splits.get(i))
+        .ok_or(Error::BadArgument)?
+        .clone();
+    lobby.set_rebalance(None);
+    let previous = game.teams().to_vec();
+    update_team_roles(ctx, guild_id, lobby.name(), game.id(), &previous, &teams)?;
+    game.set_teams(teams);
+    database.update_game(&game, msg.channel_id)?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "split", args)
+            .game(game.id())
+            .change(
+                audit::teams_summary(&previous),
+                audit::teams_summary(game.teams()),
+            ),
+    )?;
+    let rated = rated_teams(lobby, game.teams());
+    let f = |users: &Vec<UserId>| {
         users
             .iter()
             .map(|x| x.mention())
             .collect::<Vec<_>>()
             .join("\n")
     };
-    let description = format!(
-        "**{}**\n\nTeam 1:\n{}\n\nTeam 2:\n{}",
-        game.score(),
-        f(game.teams()[0]),
-        f(game.teams()[1])
+    let title = format!("Game {}", game.id());
+    let mut description = format!(
+        "Quality: {:.0}\nMean difference: {:.1}\n\n{}",
+        100.0 * utils::quality(&rated, lobby.ratings().system()),
+        utils::mean_difference(&rated),
+        describe_teams(game.teams(), f)
     );
+    description += &describe_broken_constraints(game.teams(), &database.get_pair_constraints()?);
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| {
             e.title(title)
@@ -1006,101 +2015,233 @@ pub fn gameinfo(
     Ok(())
 }
 
-pub fn clear(ctx: &Context, msg: &Message, roles: &Roles, lobbies: &mut Lobbies) -> Result {
-    let guild_id = checks::get_guild(msg)?;
-    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
-        return Ok(());
-    }
-    let lobby = lobbies
-        .get_mut(&msg.channel_id)
-        .ok_or(Error::NotALobby(msg.channel_id))?;
-    lobby.clear();
-    ctx.create_message(msg.channel_id, |m| {
-        m.embed(|e| e.description("Queue cleared"))
-    })?;
-    Ok(())
-}
-
-pub fn rebalance(
+/// Replaces a player of an undecided game by a substitute, in the last game
+/// of the player unless a game is given. The substitute must be allowed to
+/// join the lobby and must not be playing or picked for another game.
+#[allow(clippy::too_many_arguments)]
+pub fn sub(
     ctx: &Context,
     msg: &Message,
     roles: &Roles,
     lobbies: &mut Lobbies,
     database: &Database,
-    trueskill: TrueSkill,
+    log: Option<ChannelId>,
+    args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
     if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
         return Ok(());
     }
-    let lobby = lobbies
-        .get_mut(&msg.channel_id)
-        .ok_or(Error::NotALobby(msg.channel_id))?;
-    let mut game = match database.get_games()?.remove(&msg.channel_id) {
-        Some(games) => {
-            if let Some(game) = games.into_iter().last() {
-                game.1
-            } else {
-                return Ok(());
+    let lobby_name = lobbies
+        .get(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?
+        .name()
+        .to_owned();
+    if args.len() < 2 {
+        return Err(Error::NotEnoughArguments);
+    }
+    let mut users = Vec::new();
+    for arg in args[..2].iter() {
+        match Member::parse(ctx, guild_id, arg)? {
+            Some(member) => users.push(member.user.id),
+            None => return Err(Error::MemberNotFound(arg.clone())),
+        }
+    }
+    let (player_out, player_in) = (users[0], users[1]);
+    let plays = |game: &Game, user_id| game.teams().iter().flatten().any(|&x| x == user_id);
+    let mut game = match args.get(2) {
+        Some(arg) => {
+            let game_id = arg.parse()?;
+            match database.get_game(msg.channel_id, game_id) {
+                Ok(game) => game,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    return Err(Error::GameNotFound(game_id))
+                }
+                Err(err) => return Err(err.into()),
             }
         }
-        None => return Ok(()),
+        None => database
+            .get_undecided_games(msg.channel_id)?
+            .into_values()
+            .rev()
+            .find(|game| plays(game, player_out))
+            .ok_or(Error::NotPlaying(player_out))?,
     };
     if game.score() != Score::Undecided {
         return Err(Error::GameAlreadySet);
     }
-    let players = game
-        .teams()
-        .into_iter()
-        .flatten()
-        .map(|x| {
-            (
-                x,
-                lobby
-                    .ratings()
-                    .get(x)
-                    .map(|x| x.rating)
-                    .unwrap_or_else(|| trueskill.create_rating()),
-            )
-        })
-        .collect::<Vec<_>>();
-    let teams = utils::balance(&players);
-    let quality = utils::quality(&teams, trueskill);
-    let team1 = teams[0].iter().map(|x| x.0).copied().collect::<Vec<_>>();
-    let team2 = teams[1].iter().map(|x| x.0).copied().collect::<Vec<_>>();
-    game.set_teams([team1, team2]);
+    if !plays(&game, player_out) {
+        return Err(Error::NotPlaying(player_out));
+    }
+    if checks::has_role(ctx, guild_id, player_in, roles.banned)? {
+        return Err(Error::Banned(player_in));
+    }
+    for &lobby_id in lobbies.keys() {
+        let games = database.get_undecided_games(lobby_id)?;
+        if games.values().any(|game| plays(game, player_in)) {
+            return Err(Error::AlreadyPlaying(player_in));
+        }
+    }
+    check_pending(lobbies, &[player_in])?;
+    match lobbies[&msg.channel_id].penalties().get(&player_in) {
+        Some(&until) if until > Utc::now() => {
+            return Err(LobbyError::Penalized(player_in, until).into())
+        }
+        _ => (),
+    }
+    gate::check(ctx, guild_id, msg.channel_id, lobbies, database, player_in)?;
+    let previous = game.teams().to_vec();
+    game.substitute(Substitution {
+        player_out,
+        player_in,
+        datetime: Utc::now(),
+    });
+    update_team_roles(
+        ctx,
+        guild_id,
+        &lobby_name,
+        game.id(),
+        &previous,
+        game.teams(),
+    )?;
     database.update_game(&game, msg.channel_id)?;
-    let title = format!("Game {}", game.id());
-    let f = |users: &[UserId]| {
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "sub", args)
+            .game(game.id())
+            .player(player_in)
+            .change(player_out.mention(), player_in.mention()),
+    )?;
+    for (&channel_id, lobby) in lobbies.iter_mut() {
+        if lobby.leave(player_in, true).is_err() {
+            continue;
+        }
+        database.save_lobby(channel_id, lobby)?;
+        if let Err(err) = ctx.create_message(channel_id, |m| {
+            m.embed(|e| {
+                e.description(format!(
+                    "[{}/{}] {} left the queue (Substitute).",
+                    lobby.len(),
+                    lobby.queue_capacity(),
+                    player_in.mention(),
+                ))
+            })
+        }) {
+            eprintln!("Err: {:?}", err);
+        }
+    }
+    let f = |users: &Vec<UserId>| {
         users
             .iter()
             .map(|x| x.mention())
             .collect::<Vec<_>>()
             .join("\n")
     };
+    let title = format!("Game {}", game.id());
     let description = format!(
-        "Quality: {:.0}\n\nTeam 1:\n{}\n\nTeam 2:\n{}",
-        100.0 * quality,
-        f(game.teams()[0]),
-        f(game.teams()[1])
+        "{} replaces {}.\n\n{}",
+        player_in.mention(),
+        player_out.mention(),
+        describe_teams(game.teams(), f)
     );
     ctx.create_message(msg.channel_id, |m| {
-        m.embed(|e| {
-            e.title(title)
-                .description(description)
-                .timestamp(game.datetime())
-        })
+        m.embed(|e| e.title(title).description(description))
     })?;
     Ok(())
 }
 
+/// Pairs the players of `teams` with their rating in the lobby.
+fn rated_teams(lobby: &Lobby, teams: &[Vec<UserId>]) -> Vec<Vec<(UserId, Rating)>> {
+    let system = lobby.ratings().system();
+    teams
+        .iter()
+        .map(|team| {
+            team.iter()
+                .map(|&x| {
+                    (
+                        x,
+                        lobby
+                            .ratings()
+                            .get(&x)
+                            .map(|x| x.rating)
+                            .unwrap_or_else(|| system.create_rating()),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn user_teams(teams: &[Vec<(UserId, Rating)>]) -> Vec<Vec<UserId>> {
+    teams
+        .iter()
+        .map(|team| team.iter().map(|x| x.0).collect())
+        .collect()
+}
+
+/// Moves the players whose team changed to the role of their new team, and
+/// the role of the game from the players who left it to those who joined it.
+fn update_team_roles(
+    ctx: &Context,
+    guild_id: GuildId,
+    lobby_name: &str,
+    game_id: usize,
+    previous: &[Vec<UserId>],
+    teams: &[Vec<UserId>],
+) -> Result {
+    let roles = ctx.get_guild_roles(guild_id)?;
+    let role = |team: usize| {
+        roles
+            .iter()
+            .find(|x| x.name == format!("{} Game {} Team {}", lobby_name, game_id, team + 1))
+            .map(|x| x.id)
+    };
+    let team_of = |teams: &[Vec<UserId>], user_id| teams.iter().position(|x| x.contains(&user_id));
+    let game_role = roles
+        .iter()
+        .find(|x| x.name == format!("{} Game {}", lobby_name, game_id))
+        .map(|x| x.id);
+    if let Some(role_id) = game_role {
+        for &user_id in previous.iter().flatten() {
+            if team_of(teams, user_id).is_none() {
+                ctx.remove_guild_member_role(guild_id, user_id, role_id)?;
+            }
+        }
+        for &user_id in teams.iter().flatten() {
+            if team_of(previous, user_id).is_none() {
+                ctx.add_guild_member_role(guild_id, user_id, role_id)?;
+            }
+        }
+    }
+    for (i, team) in previous.iter().enumerate() {
+        for &user_id in team.iter().filter(|&&x| team_of(teams, x) != Some(i)) {
+            if let Some(role_id) = role(i) {
+                ctx.remove_guild_member_role(guild_id, user_id, role_id)?;
+            }
+        }
+    }
+    for (i, team) in teams.iter().enumerate() {
+        for &user_id in team.iter().filter(|&&x| team_of(previous, x) != Some(i)) {
+            if let Some(role_id) = role(i) {
+                ctx.add_guild_member_role(guild_id, user_id, role_id)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Swaps two players of different teams in the last game. Players who are not
+/// playing are brought in with `sub` instead.
+#[allow(clippy::too_many_arguments)]
 pub fn swap(
     ctx: &Context,
     msg: &Message,
     roles: &Roles,
     lobbies: &Lobbies,
     database: &Database,
-    trueskill: TrueSkill,
+    log: Option<ChannelId>,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
@@ -1125,125 +2266,75 @@ pub fn swap(
     } else {
         return Err(Error::MemberNotFound(args[1].to_owned()));
     };
-    let mut game = match database.get_games()?.remove(&msg.channel_id) {
-        Some(games) => {
-            if let Some(game) = games.into_iter().last() {
-                game.1
-            } else {
-                return Ok(());
-            }
-        }
+    let mut game = match database.get_last_game(msg.channel_id)? {
+        Some(game) => game,
         None => return Ok(()),
     };
     if game.score() != Score::Undecided {
         return Err(Error::GameAlreadySet);
     }
     let teams = game.teams();
-    let mut team1 = teams[0].iter().copied().collect::<HashSet<_>>();
-    let mut team2 = teams[1].iter().copied().collect::<HashSet<_>>();
-    if team1.contains(&member1.user.id) {
-        if team1.contains(&member2.user.id) {
-            return Err(Error::SameTeam);
-        } else {
-            let roles = ctx.get_guild_roles(guild_id)?;
-            let role1 = roles
-                .iter()
-                .find(|x| x.name == format!("{} Game {} Team 1", lobby.name(), game.id()));
-            team1.remove(&member1.user.id);
-            team1.insert(member2.user.id);
-            if let Some(role1) = role1 {
-                ctx.remove_guild_member_role(guild_id, member1.user.id, role1.id)?;
-                ctx.add_guild_member_role(guild_id, member2.user.id, role1.id)?;
-            }
-            if team2.contains(&member2.user.id) {
-                let role2 = roles
-                    .iter()
-                    .find(|x| x.name == format!("{} Game {} Team 2", lobby.name(), game.id()));
-                team2.remove(&member2.user.id);
-                team2.insert(member1.user.id);
-                if let Some(role2) = role2 {
-                    ctx.remove_guild_member_role(guild_id, member2.user.id, role2.id)?;
-                    ctx.add_guild_member_role(guild_id, member1.user.id, role2.id)?;
-                }
-            }
-        }
-    } else if team2.contains(&member1.user.id) {
-        if team2.contains(&member2.user.id) {
-            return Err(Error::SameTeam);
-        } else {
-            let roles = ctx.get_guild_roles(guild_id)?;
-            let role2 = roles
-                .iter()
-                .find(|x| x.name == format!("{} Game {} Team 2", lobby.name(), game.id()));
-            team2.remove(&member1.user.id);
-            team2.insert(member2.user.id);
-            if let Some(role2) = role2 {
-                ctx.remove_guild_member_role(guild_id, member1.user.id, role2.id)?;
-                ctx.add_guild_member_role(guild_id, member2.user.id, role2.id)?;
-            }
-            if team1.contains(&member2.user.id) {
-                let role1 = roles
-                    .iter()
-                    .find(|x| x.name == format!("{} Game {} Team 1", lobby.name(), game.id()));
-                team1.remove(&member2.user.id);
-                team1.insert(member1.user.id);
-                if let Some(role1) = role1 {
-                    ctx.remove_guild_member_role(guild_id, member2.user.id, role1.id)?;
-                    ctx.add_guild_member_role(guild_id, member1.user.id, role1.id)?;
-                }
-            }
-        }
-    } else {
-        return Err(Error::NotPlaying(member1.user.id));
+    let previous = audit::teams_summary(teams);
+    let team1 = teams
+        .iter()
+        .position(|team| team.contains(&member1.user.id))
+        .ok_or(Error::NotPlaying(member1.user.id))?;
+    let team2 = teams
+        .iter()
+        .position(|team| team.contains(&member2.user.id))
+        .ok_or(Error::NotPlaying(member2.user.id))?;
+    if team2 == team1 {
+        return Err(Error::SameTeam);
+    }
+    let swapped = teams
+        .iter()
+        .map(|team| {
+            team.iter()
+                .map(|&x| {
+                    if x == member1.user.id {
+                        member2.user.id
+                    } else if x == member2.user.id {
+                        member1.user.id
+                    } else {
+                        x
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    // Refuse swaps breaking a constraint that the current teams satisfy
+    let constraints = database.get_pair_constraints()?;
+    let broken = utils::broken_constraints(teams, &constraints);
+    if let Some(&(user1, user2, constraint)) = utils::broken_constraints(&swapped, &constraints)
+        .iter()
+        .find(|x| !broken.contains(x))
+    {
+        return Err(Error::BrokenConstraint(user1, user2, constraint));
     }
-    game.set_teams([
-        team1.into_iter().collect::<Vec<_>>(),
-        team2.into_iter().collect::<Vec<_>>(),
-    ]);
+    update_team_roles(ctx, guild_id, lobby.name(), game.id(), teams, &swapped)?;
+    game.set_teams(swapped);
     database.update_game(&game, msg.channel_id)?;
-    let f = |users: &[UserId]| {
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "swap", args)
+            .game(game.id())
+            .change(previous, audit::teams_summary(game.teams())),
+    )?;
+    let f = |users: &Vec<UserId>| {
         users
             .iter()
             .map(|x| x.mention())
             .collect::<Vec<_>>()
             .join("\n")
     };
-    let ratings = lobby.ratings();
-    let quality = utils::quality(
-        &[
-            game.teams()[0]
-                .iter()
-                .map(|x| {
-                    (
-                        (),
-                        ratings
-                            .get(x)
-                            .map(|x| x.rating)
-                            .unwrap_or_else(|| trueskill.create_rating()),
-                    )
-                })
-                .collect(),
-            game.teams()[1]
-                .iter()
-                .map(|x| {
-                    (
-                        (),
-                        ratings
-                            .get(x)
-                            .map(|x| x.rating)
-                            .unwrap_or_else(|| trueskill.create_rating()),
-                    )
-                })
-                .collect(),
-        ],
-        trueskill,
-    );
+    let quality = utils::quality(&rated_teams(lobby, game.teams()), lobby.ratings().system());
     let title = format!("Game {}", game.id());
     let description = format!(
-        "Quality: {:.0}\n\nTeam 1:\n{}\n\nTeam 2:\n{}",
+        "Quality: {:.0}\n\n{}",
         100.0 * quality,
-        f(game.teams()[0]),
-        f(game.teams()[1])
+        describe_teams(game.teams(), f)
     );
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| e.description(description).title(title))
@@ -1257,9 +2348,9 @@ pub fn setrating(
     msg: &Message,
     roles: &Roles,
     lobbies: &mut Lobbies,
-    trueskill: TrueSkill,
     database: &Database,
     ranks: &[Rank],
+    log: Option<ChannelId>,
     args: &[String],
 ) -> Result {
     let guild_id = checks::get_guild(msg)?;
@@ -1275,57 +2366,68 @@ pub fn setrating(
         return Err(Error::MemberNotFound(args[0].to_owned()));
     };
     let rating = args[1].parse::<i64>()?;
+    let previous = database
+        .get_initial_ratings()?
+        .get(&member.user.id)
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "none".to_owned());
     database.insert_initial_rating(member.user.id, rating as f64)?;
-    let games = database.get_games()?;
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "setrating", args)
+            .player(member.user.id)
+            .change(previous, rating),
+    )?;
+    for (&channel_id, lobby) in lobbies.iter_mut() {
+        // The initial rating replaces the seed of the current season.
+        let season = database.get_seasons(channel_id)?.len();
+        database.remove_season_seed(channel_id, season, member.user.id)?;
+        let first_game =
+            database.get_first_game(channel_id, member.user.id, lobby.ratings().start())?;
+        lobby
+            .ratings_mut()
+            .set_initial(member.user.id, rating as f64, first_game);
+        if let Some(first_game) = first_game {
+            replay_ratings(lobby, database, channel_id, first_game)?;
+        }
+    }
     let member_roles = ctx
         .list_guild_members(guild_id)?
         .into_iter()
         .map(|x| (x.user.id, x.roles))
         .collect::<HashMap<_, _>>();
-    let initials = database.get_initial_ratings()?;
+    let mut webhooks = Vec::new();
     rayon::scope(|s| {
         s.spawn(|_| {
-            lobbies.par_iter_mut().for_each(|(channel_id, lobby)| {
-                let games = if let Some(games) = games.get(channel_id) {
-                    games
-                } else {
-                    return;
-                };
-                let ratings = Ratings::from_games(games, &initials, trueskill);
-                lobby.set_ratings(ratings);
-                let leaderboard = utils::leaderboard(lobby, 15, ranks, |user_id| {
-                    Ok(member_roles
-                        .get(&user_id)
-                        .map(|x| x.contains(&roles.ranked))
-                        .unwrap_or(false))
+            webhooks = lobbies
+                .par_iter_mut()
+                .filter_map(|(channel_id, lobby)| {
+                    let leaderboard = utils::leaderboard(
+                        lobby.ratings(),
+                        lobby.leaderboard_options(),
+                        15,
+                        ranks,
+                        |user_id| {
+                            Ok(member_roles
+                                .get(&user_id)
+                                .map(|x| x.contains(&roles.ranked))
+                                .unwrap_or(false))
+                        },
+                    )
+                    .unwrap();
+                    let (webhook_id, webhook_token, messages) = lobby.webhook_mut().as_mut()?;
+                    let (orphaned, webhook_channel) = utils::post_leaderboard(
+                        ctx,
+                        *webhook_id,
+                        webhook_token,
+                        messages,
+                        &leaderboard,
+                    );
+                    Some((*channel_id, messages.clone(), orphaned, webhook_channel))
                 })
-                .unwrap();
-                if let Some((webhook_id, webhook_token, messages)) = lobby.webhook_mut() {
-                    messages.par_iter().for_each(|&message| {
-                        if let Err(err) =
-                            ctx.delete_webhook_message(*webhook_id, webhook_token, message)
-                        {
-                            eprintln!("Err: {:?}", err);
-                        }
-                    });
-                    messages.clear();
-                    for (title, description) in leaderboard.iter() {
-                        let message =
-                            match ctx.execute_webhook(*webhook_id, webhook_token, true, |m| {
-                                m.embed(|e| e.description(description).title(title))
-                            }) {
-                                Ok(message) => message,
-                                Err(err) => {
-                                    eprintln!("Err: {:?}", err);
-                                    continue;
-                                }
-                            };
-                        if let Some(message) = message {
-                            messages.push(message.id);
-                        }
-                    }
-                }
-            });
+                .collect();
         });
         s.spawn(|_| {
             if let Err(err) = ctx.create_message(msg.channel_id, |m| {
@@ -1335,6 +2437,100 @@ pub fn setrating(
             }
         });
     });
+    for (channel_id, messages, orphaned, webhook_channel) in webhooks {
+        database.save_webhook_messages(channel_id, &messages, &orphaned, webhook_channel)?;
+    }
+    Ok(())
+}
+
+/// Closes the current season of the lobby and starts a new one. With a
+/// percentage argument, players start the new season with their ratings
+/// moved toward the default one, keeping that percentage of the difference.
+#[allow(clippy::too_many_arguments)]
+pub fn endseason(
+    ctx: &Context,
+    msg: &Message,
+    roles: &Roles,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    ranks: &[Rank],
+    log: Option<ChannelId>,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    if !checks::has_role(ctx, guild_id, msg.author.id, roles.admin)? {
+        return Ok(());
+    }
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let keep = if let Some(keep) = args.first() {
+        keep.trim_end_matches('%').parse::<u8>()?
+    } else {
+        0
+    };
+    if keep > 100 {
+        return Err(Error::BadArgument);
+    }
+    let keep = f64::from(keep) / 100.0;
+    // A game scored after the season ends would belong to neither season
+    let undecided = database.get_undecided_games(msg.channel_id)?;
+    if !undecided.is_empty() {
+        return Err(Error::GamesUndecided(undecided.into_keys().collect()));
+    }
+    let season = database.get_seasons(msg.channel_id)?.len();
+    let start = database.last_game_id(msg.channel_id)? + 1;
+    let system = lobby.ratings().system();
+    let default_rating = system.create_rating();
+    let default_sigma = default_rating.variance().sqrt();
+    let seeds = if keep > 0.0 {
+        lobby
+            .ratings()
+            .iter()
+            .map(|(&user_id, info)| {
+                let mean =
+                    default_rating.mean() + keep * (info.rating.mean() - default_rating.mean());
+                let sigma = default_sigma + keep * (info.rating.variance().sqrt() - default_sigma);
+                (user_id, system.rating(mean, sigma.powi(2)))
+            })
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    database.end_season(msg.channel_id, season, lobby.ratings(), start, &seeds)?;
+    let initials = database.get_initial_ratings()?;
+    *lobby.ratings_mut() = Ratings::new(start, &initials, &seeds, system);
+    audit::record(
+        ctx,
+        database,
+        log,
+        AuditEntry::new(msg.author.id, msg.channel_id, "endseason", args).change(
+            format!("season {}", season),
+            format!("season {}", season + 1),
+        ),
+    )?;
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| {
+            e.description(format!(
+                "Season {} ended, season {} starts with game {}.",
+                season,
+                season + 1,
+                start
+            ))
+        })
+    })?;
+    let leaderboard = utils::leaderboard(
+        lobby.ratings(),
+        lobby.leaderboard_options(),
+        15,
+        ranks,
+        |user_id| checks::has_role(ctx, guild_id, user_id, roles.ranked),
+    )?;
+    if let Some((webhook_id, webhook_token, messages)) = lobby.webhook_mut() {
+        let (orphaned, webhook_channel) =
+            utils::post_leaderboard(ctx, *webhook_id, webhook_token, messages, &leaderboard);
+        database.save_webhook_messages(msg.channel_id, messages, &orphaned, webhook_channel)?;
+    }
     Ok(())
 }
 
@@ -1342,6 +2538,7 @@ pub fn expire(
     ctx: &Context,
     msg: &Message,
     lobbies: &mut Lobbies,
+    database: &Database,
     timeout: u64,
     warn: u64,
     args: &[String],
@@ -1382,7 +2579,10 @@ pub fn expire(
     } else {
         None
     };
-    *queue_user = QueueUser::new(expire, warn);
+    *queue_user = QueueUser::new(queue_user.joined(), expire, warn)
+        .with_party(queue_user.party())
+        .with_priority(queue_user.priority());
+    database.save_lobby(msg.channel_id, lobby)?;
     ctx.create_message(msg.channel_id, |m| {
         m.embed(|e| {
             e.description(format!(
@@ -1394,3 +2594,53 @@ pub fn expire(
     })?;
     Ok(())
 }
+
+/// Recomputes the ratings of a lobby after the game `game_id` changed,
+/// replaying the games from the last snapshot taken before it.
+fn replay_ratings(
+    lobby: &mut Lobby,
+    database: &Database,
+    channel_id: ChannelId,
+    game_id: usize,
+) -> Result {
+    let from = lobby.ratings().replay_start(game_id);
+    let games = database.get_games_since(channel_id, from)?;
+    let game_ratings = lobby.ratings_mut().replay(from, &games);
+    database.save_game_ratings(channel_id, from, &game_ratings)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn places(args: &[&str], teams: usize) -> Result<Vec<usize>> {
+        let args = args.iter().map(|&x| x.to_owned()).collect::<Vec<_>>();
+        parse_places(&args, teams)
+    }
+
+    #[test]
+    fn parse_places_winner() {
+        assert_eq!(places(&["2"], 2).unwrap(), vec![1, 0]);
+        assert_eq!(places(&["1"], 3).unwrap(), vec![0, 1, 1]);
+        assert_eq!(places(&["draw"], 3).unwrap(), vec![0, 0, 0]);
+        assert_eq!(places(&["D"], 2).unwrap(), vec![0, 0]);
+    }
+
+    #[test]
+    fn parse_places_ranking() {
+        assert_eq!(places(&["3", "1", "2"], 3).unwrap(), vec![1, 2, 0]);
+        assert_eq!(places(&["2=3", "1"], 3).unwrap(), vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn parse_places_errors() {
+        assert!(matches!(places(&[], 2), Err(Error::NotEnoughArguments)));
+        assert!(matches!(places(&["3"], 2), Err(Error::BadArgument)));
+        assert!(matches!(places(&["0"], 2), Err(Error::BadArgument)));
+        // Teams missing or ranked twice
+        assert!(matches!(places(&["1", "2"], 3), Err(Error::BadArgument)));
+        assert!(matches!(places(&["1", "1"], 2), Err(Error::BadArgument)));
+        assert!(places(&["x", "1"], 2).is_err());
+    }
+}
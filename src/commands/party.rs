@@ -0,0 +1,119 @@
+use harmony::client::Context;
+use harmony::model::id::{GuildId, UserId};
+use harmony::model::{Member, Message};
+
+use crate::checks;
+use crate::model::{Database, Lobbies, Parties, PartyError};
+use crate::{Error, Result};
+
+pub fn party(
+    ctx: &Context,
+    msg: &Message,
+    lobbies: &mut Lobbies,
+    parties: &mut Parties,
+    database: &Database,
+    args: &[String],
+) -> Result {
+    let guild_id = checks::get_guild(msg)?;
+    let author = msg.author.id;
+    let description = match args.first().map(|x| x.to_lowercase()).as_deref() {
+        None => {
+            let party = parties.get(author).ok_or(PartyError::NotInParty(author))?;
+            let mut description = format!(
+                "Members:\n{}",
+                party
+                    .members()
+                    .iter()
+                    .map(|x| x.mention())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+            if !party.invites().is_empty() {
+                description += &format!(
+                    "\n\nInvited:\n{}",
+                    party
+                        .invites()
+                        .iter()
+                        .map(|x| x.mention())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+            }
+            description
+        }
+        Some("invite") => {
+            let user_id = parse_member(ctx, guild_id, args)?;
+            parties.invite(author, user_id)?;
+            database.save_parties(parties)?;
+            format!(
+                "{} invited {} to their party.",
+                author.mention(),
+                user_id.mention()
+            )
+        }
+        Some("accept") => {
+            let leader = parse_member(ctx, guild_id, args)?;
+            parties.accept(author, leader)?;
+            database.save_parties(parties)?;
+            format!(
+                "{} joined the party of {}.",
+                author.mention(),
+                leader.mention()
+            )
+        }
+        Some("leave") => {
+            let party = parties.get(author).ok_or(PartyError::NotInParty(author))?;
+            let (leader, members) = (party.leader(), party.members().to_vec());
+            parties.leave(author)?;
+            database.save_parties(parties)?;
+            update_queues(lobbies, parties, database, leader, &members)?;
+            format!("{} left their party.", author.mention())
+        }
+        Some("disband") => {
+            let members = parties.disband(author)?;
+            database.save_parties(parties)?;
+            update_queues(lobbies, parties, database, author, &members)?;
+            format!("The party of {} was disbanded.", author.mention())
+        }
+        Some(_) => return Err(Error::BadArgument),
+    };
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| e.title("Party").description(description))
+    })?;
+    Ok(())
+}
+
+/// Updates the queued players of a party led by `leader` after it changed,
+/// so that the players who left it are no longer kept together.
+fn update_queues(
+    lobbies: &mut Lobbies,
+    parties: &Parties,
+    database: &Database,
+    leader: UserId,
+    members: &[UserId],
+) -> Result {
+    for (&channel_id, lobby) in lobbies.iter_mut() {
+        let mut changed = false;
+        for &user_id in members {
+            if let Some(queue_user) = lobby.queue_mut().get_mut(&user_id) {
+                if queue_user.party() == Some(leader) {
+                    let party = parties.get(user_id).map(|x| x.leader());
+                    *queue_user = queue_user.clone().with_party(party);
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            database.save_lobby(channel_id, lobby)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_member(ctx: &Context, guild_id: GuildId, args: &[String]) -> Result<UserId> {
+    let arg = args.get(1).ok_or(Error::NotEnoughArguments)?;
+    match Member::parse(ctx, guild_id, arg)? {
+        Some(member) => Ok(member.user.id),
+        None => Err(Error::MemberNotFound(arg.clone())),
+    }
+}
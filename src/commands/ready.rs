@@ -0,0 +1,142 @@
+use chrono::{Duration, Utc};
+use harmony::client::Context;
+use harmony::model::id::{ChannelId, UserId};
+use harmony::model::Message;
+
+use crate::model::{Database, Lobbies, ReadyCheck};
+use crate::{Error, Result};
+
+use super::lobby;
+
+pub fn ready(
+    ctx: &Context,
+    msg: &Message,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    bridge: ChannelId,
+) -> Result {
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    let ready_check = lobby.ready_check_mut().ok_or(Error::NoReadyCheck)?;
+    if !ready_check.contains(msg.author.id) {
+        return Err(Error::NotPlaying(msg.author.id));
+    }
+    if !ready_check.confirm(msg.author.id) {
+        return Ok(());
+    }
+    let (confirmed, len) = (ready_check.confirmed(), ready_check.players().len());
+    ctx.create_message(msg.channel_id, |m| {
+        m.embed(|e| {
+            e.description(format!(
+                "[{}/{}] {} is ready.",
+                confirmed,
+                len,
+                msg.author.id.mention()
+            ))
+        })
+    })?;
+    if !ready_check.is_done() {
+        database.save_lobby(msg.channel_id, lobby)?;
+        return Ok(());
+    }
+    let ready_check = lobby.take_ready_check().unwrap();
+    database.save_lobby(msg.channel_id, lobby)?;
+    let guild_id = ready_check.guild_id();
+    let (players, _) = ready_check.into_players();
+    lobby::form_teams(
+        ctx,
+        guild_id,
+        msg.channel_id,
+        bridge,
+        lobbies,
+        players,
+        database,
+    )
+}
+
+/// Ends the ready checks which ran out of time. The players who did not
+/// confirm are removed and penalized, the others go back to the front of the
+/// queue.
+pub fn ready_timeout(ctx: &Context, lobbies: &mut Lobbies, database: &Database, bridge: ChannelId) {
+    let now = Utc::now();
+    let expired = lobbies
+        .iter()
+        .filter(|(_, lobby)| {
+            lobby
+                .ready_check()
+                .map(|x| now >= x.deadline())
+                .unwrap_or(false)
+        })
+        .map(|(&channel_id, _)| channel_id)
+        .collect::<Vec<_>>();
+    for channel_id in expired {
+        if let Err(err) = fail(ctx, channel_id, lobbies, database, bridge) {
+            eprintln!("Err: {:?}", err);
+        }
+    }
+}
+
+fn fail(
+    ctx: &Context,
+    channel_id: ChannelId,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    bridge: ChannelId,
+) -> Result {
+    let lobby = lobbies.get_mut(&channel_id).unwrap();
+    let penalty = lobby.ready_options().map(|x| x.penalty).unwrap_or_default();
+    let ready_check = lobby.take_ready_check().unwrap();
+    let guild_id = ready_check.guild_id();
+    let (players, pending) = ready_check.into_players();
+    if penalty > 0 {
+        let until = Utc::now() + Duration::minutes(penalty as i64);
+        for &user_id in pending.iter() {
+            lobby.penalize(user_id, until);
+        }
+    }
+    let removed = lobby.requeue(players);
+    let f = |users: &[UserId]| {
+        users
+            .iter()
+            .map(|x| x.mention())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut description = format!(
+        "[{}/{}] Ready check failed, {} did not confirm.",
+        lobby.len(),
+        lobby.queue_capacity(),
+        f(&pending)
+    );
+    if !removed.is_empty() {
+        description += &format!("\n{} left the queue (Queue full).", f(&removed));
+    }
+    database.save_lobby(channel_id, lobby)?;
+    ctx.create_message(channel_id, |m| m.embed(|e| e.description(description)))?;
+    if let Some(players) = lobby::next_players(ctx, channel_id, lobbies, database)? {
+        lobby::start_game(
+            ctx, guild_id, channel_id, bridge, lobbies, players, database,
+        )?;
+    }
+    Ok(())
+}
+
+/// Pings the players of a ready check.
+pub(super) fn announce(ctx: &Context, channel_id: ChannelId, ready_check: &ReadyCheck) -> Result {
+    let content = ready_check
+        .players()
+        .keys()
+        .map(|x| x.mention())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let description = format!(
+        "The queue is full, use the ready command within {} seconds to play.",
+        (ready_check.deadline() - Utc::now()).num_seconds().max(0)
+    );
+    ctx.create_message(channel_id, |m| {
+        m.content(content)
+            .embed(|e| e.title("Ready check").description(description))
+    })?;
+    Ok(())
+}
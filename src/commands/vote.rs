@@ -0,0 +1,158 @@
+use std::mem;
+
+use chrono::Utc;
+use harmony::client::Context;
+use harmony::model::id::ChannelId;
+use harmony::model::Message;
+use rand::seq::SliceRandom;
+
+use crate::model::{Database, Lobbies, MapVote, MapVoteOptions, Score};
+use crate::{Error, Result};
+
+use super::lobby;
+
+pub fn vote(
+    ctx: &Context,
+    msg: &Message,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    bridge: ChannelId,
+    args: &[String],
+) -> Result {
+    let lobby = lobbies
+        .get_mut(&msg.channel_id)
+        .ok_or(Error::NotALobby(msg.channel_id))?;
+    if lobby.map_votes().is_empty() {
+        return Err(Error::NoMapVote);
+    }
+    if args.is_empty() {
+        return Err(Error::NotEnoughArguments);
+    }
+    let index = lobby
+        .map_votes()
+        .iter()
+        .position(|x| x.contains(msg.author.id))
+        .ok_or(Error::NotPlaying(msg.author.id))?;
+    let map_vote = &mut lobby.map_votes_mut()[index];
+    let arg = args.join(" ");
+    let choice = map_vote.parse(&arg).ok_or(Error::UnknownMap(arg))?;
+    map_vote.vote(msg.author.id, choice);
+    let description = format!(
+        "[{}/{}] {} voted for {}.",
+        map_vote.votes(),
+        map_vote.players().count(),
+        msg.author.id.mention(),
+        map_vote.choices()[choice]
+    );
+    ctx.create_message(msg.channel_id, |m| m.embed(|e| e.description(description)))?;
+    if !map_vote.is_done() {
+        database.save_lobby(msg.channel_id, lobby)?;
+        return Ok(());
+    }
+    let map_vote = lobby.map_votes_mut().remove(index);
+    finish(ctx, msg.channel_id, bridge, lobbies, database, map_vote)
+}
+
+/// Ends the map votes which ran out of time, the players who did not vote
+/// being left out.
+pub fn vote_timeout(ctx: &Context, lobbies: &mut Lobbies, database: &Database, bridge: ChannelId) {
+    let now = Utc::now();
+    let expired = lobbies
+        .iter_mut()
+        .flat_map(|(&channel_id, lobby)| {
+            let (expired, pending): (Vec<_>, _) = mem::take(lobby.map_votes_mut())
+                .into_iter()
+                .partition(|x| now >= x.deadline());
+            *lobby.map_votes_mut() = pending;
+            expired.into_iter().map(move |x| (channel_id, x))
+        })
+        .collect::<Vec<_>>();
+    for (channel_id, map_vote) in expired {
+        if let Err(err) = finish(ctx, channel_id, bridge, lobbies, database, map_vote) {
+            eprintln!("Err: {:?}", err);
+        }
+    }
+}
+
+/// Draws the maps of a vote from the pool of a lobby, leaving out the maps of
+/// its last games unless that would leave none.
+pub(super) fn choices(
+    database: &Database,
+    channel_id: ChannelId,
+    options: &MapVoteOptions,
+) -> Result<Vec<String>> {
+    let recent = database.get_recent_maps(channel_id, options.recent)?;
+    let mut choices = options
+        .pool
+        .iter()
+        .filter(|x| !recent.contains(x))
+        .cloned()
+        .collect::<Vec<_>>();
+    if choices.is_empty() {
+        choices = options.pool.clone();
+    }
+    if options.choices > 0 && options.choices < choices.len() {
+        choices.shuffle(&mut rand::thread_rng());
+        choices.truncate(options.choices);
+    }
+    Ok(choices)
+}
+
+/// Pings the players of a map vote and lists the maps.
+pub(super) fn announce(ctx: &Context, channel_id: ChannelId, map_vote: &MapVote) -> Result {
+    let content = map_vote
+        .players()
+        .map(|x| x.mention())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let description = format!(
+        "{}\n\nUse the vote command with a number or a name within {} seconds.",
+        map_vote
+            .choices()
+            .iter()
+            .enumerate()
+            .map(|(i, x)| format!("{}. {}", i + 1, x))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        (map_vote.deadline() - Utc::now()).num_seconds().max(0)
+    );
+    ctx.create_message(channel_id, |m| {
+        m.content(content)
+            .embed(|e| e.title("Map vote").description(description))
+    })?;
+    Ok(())
+}
+
+/// Sets the map of the game to the one with the most votes, unless the game
+/// was decided in the meantime.
+fn finish(
+    ctx: &Context,
+    channel_id: ChannelId,
+    bridge: ChannelId,
+    lobbies: &mut Lobbies,
+    database: &Database,
+    map_vote: MapVote,
+) -> Result {
+    database.save_lobby(channel_id, &lobbies[&channel_id])?;
+    let mut game = database.get_game(channel_id, map_vote.game_id())?;
+    if !matches!(game.score(), Score::Undecided) {
+        return Ok(());
+    }
+    let tally = map_vote.tally();
+    let mut description = map_vote
+        .choices()
+        .iter()
+        .zip(tally)
+        .map(|(x, votes)| format!("{}: {}", x, votes))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let map = map_vote.into_result();
+    description += &format!("\n\nGame {} is played on {}.", game.id(), map);
+    game.set_map(Some(map));
+    database.update_game(&game, channel_id)?;
+    ctx.create_message(channel_id, |m| {
+        m.embed(|e| e.title("Map vote results").description(description))
+    })?;
+    lobby::send_game_started(ctx, bridge, &game);
+    Ok(())
+}
@@ -1,11 +1,22 @@
+use std::error::Error;
+use std::fmt;
+
 use harmony::model::id::{ChannelId, RoleId, WebhookId};
 use serde::Deserialize;
-use trueskill::SimpleTrueSkill as TrueSkill;
+
+use crate::model::{
+    Balance, DraftOptions, Elo, Gate, Glicko2, LeaderboardOptions, MapVoteOptions, PoolOptions,
+    ReadyOptions, TrueSkill, Variety,
+};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub prefix: String,
     pub trueskill: TrueSkill,
+    #[serde(default)]
+    pub glicko2: Glicko2,
+    #[serde(default)]
+    pub elo: Elo,
     pub lobbies: Vec<Lobby>,
     pub infos: Vec<ChannelId>,
     pub roles: Roles,
@@ -14,6 +25,58 @@ pub struct Config {
     pub timeout: Timeout,
     pub bridge: ChannelId,
     pub game: Option<String>,
+    pub audit: Option<ChannelId>,
+}
+
+impl Config {
+    /// Checks the values that cannot be expressed by the types.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for lobby in self.lobbies.iter() {
+            if lobby.capacity < 2 {
+                return Err(ConfigError::Capacity(lobby.name.clone()));
+            }
+            if let Some(teams) = lobby.teams {
+                if teams < 2 || teams > lobby.capacity {
+                    return Err(ConfigError::Teams(lobby.name.clone()));
+                }
+            }
+            // Drafts are between two captains
+            if lobby.draft.is_some() && lobby.teams.unwrap_or(2) != 2 {
+                return Err(ConfigError::Draft(lobby.name.clone()));
+            }
+            if let Some(map_vote) = &lobby.map_vote {
+                if map_vote.pool.is_empty() {
+                    return Err(ConfigError::MapPool(lobby.name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Capacity(String),
+    Teams(String),
+    Draft(String),
+    MapPool(String),
+}
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Capacity(name) => write!(f, "The capacity of {} must be at least 2", name),
+            Self::Teams(name) => write!(
+                f,
+                "The teams of {} must be between 2 and the capacity",
+                name
+            ),
+            Self::Draft(name) => write!(f, "The drafts of {} need exactly 2 teams", name),
+            Self::MapPool(name) => write!(f, "The map vote of {} needs a map pool", name),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -24,6 +87,34 @@ pub struct Lobby {
     pub aliases: Vec<String>,
     pub webhook: Option<Webhook>,
     pub capacity: usize,
+    pub teams: Option<usize>,
+    #[serde(default)]
+    pub rating: RatingKind,
+    #[serde(default)]
+    pub leaderboard: LeaderboardOptions,
+    #[serde(default)]
+    pub balance: Balance,
+    #[serde(default)]
+    pub variety: Variety,
+    #[serde(default)]
+    pub party_handicap: f64,
+    pub draft: Option<DraftOptions>,
+    pub ready_check: Option<ReadyOptions>,
+    pub map_vote: Option<MapVoteOptions>,
+    pub pool: Option<PoolOptions>,
+    #[serde(default)]
+    pub requeue_cancelled: bool,
+    #[serde(default)]
+    pub gate: Gate,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RatingKind {
+    #[default]
+    TrueSkill,
+    Glicko2,
+    Elo,
 }
 
 #[derive(Deserialize)]
@@ -3,7 +3,7 @@ use std::num::ParseIntError;
 
 use harmony::model::id::{ChannelId, UserId};
 
-use crate::model::LobbyError;
+use crate::model::{DraftError, GateError, LobbyError, PairConstraint, PartyError};
 
 #[derive(Debug)]
 pub enum Error {
@@ -11,6 +11,9 @@ pub enum Error {
     Rusqlite(rusqlite::Error),
     ParseInt(ParseIntError),
     Lobby(LobbyError),
+    Draft(DraftError),
+    Party(PartyError),
+    Gate(GateError),
     NotALobby(ChannelId),
     NotAGuild,
     NotEnoughArguments,
@@ -19,8 +22,21 @@ pub enum Error {
     MemberNotFound(String),
     ChannelNotFound(String),
     GameNotFound(usize),
+    GameUndecided(usize),
+    GameRequeued(usize),
+    SeasonNotFound(usize),
+    GamesUndecided(Vec<usize>),
     NotPlaying(UserId),
+    AlreadyPlaying(UserId),
+    Banned(UserId),
     SameTeam,
+    NoDraft,
+    NoReadyCheck,
+    NoMapVote,
+    UnknownMap(String),
+    UnknownLobby(String),
+    NoRebalance,
+    BrokenConstraint(UserId, UserId, PairConstraint),
 }
 
 impl fmt::Display for Error {
@@ -30,6 +46,9 @@ impl fmt::Display for Error {
             Self::Rusqlite(err) => err.fmt(f),
             Self::ParseInt(err) => err.fmt(f),
             Self::Lobby(err) => err.fmt(f),
+            Self::Draft(err) => err.fmt(f),
+            Self::Party(err) => err.fmt(f),
+            Self::Gate(err) => err.fmt(f),
             Self::NotALobby(channel_id) => write!(f, "{} is not a lobby.", channel_id.mention()),
             Self::NotAGuild => "Not a guild".fmt(f),
             Self::NotEnoughArguments => "Not enough arguments.".fmt(f),
@@ -38,8 +57,35 @@ impl fmt::Display for Error {
             Self::MemberNotFound(member) => write!(f, "Member {} not found.", member),
             Self::ChannelNotFound(channel) => write!(f, "Channel {} not found.", channel),
             Self::GameNotFound(game) => write!(f, "Game {} not found.", game),
+            Self::GameUndecided(game) => write!(f, "Game {} is not decided.", game),
+            Self::GameRequeued(game) => write!(
+                f,
+                "The players of game {} were put back in the queue.",
+                game
+            ),
+            Self::SeasonNotFound(season) => write!(f, "Season {} not found.", season),
+            Self::GamesUndecided(games) => write!(
+                f,
+                "Score or cancel the undecided games first: {}.",
+                games
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
             Self::NotPlaying(user) => write!(f, "{} is not playing.", user.mention()),
+            Self::AlreadyPlaying(user) => write!(f, "{} is already playing.", user.mention()),
+            Self::Banned(user) => write!(f, "{} is banned.", user.mention()),
             Self::SameTeam => "The players are in the same team.".fmt(f),
+            Self::NoDraft => "No draft in progress.".fmt(f),
+            Self::NoReadyCheck => "No ready check in progress.".fmt(f),
+            Self::NoMapVote => "No map vote in progress.".fmt(f),
+            Self::UnknownMap(map) => write!(f, "{} is not one of the maps.", map),
+            Self::UnknownLobby(name) => write!(f, "There is no lobby named {}.", name),
+            Self::NoRebalance => "No team splits to choose from, rebalance first.".fmt(f),
+            Self::BrokenConstraint(user1, user2, constraint) => {
+                constraint.describe(*user1, *user2).fmt(f)
+            }
         }
     }
 }
@@ -64,6 +110,24 @@ impl From<LobbyError> for Error {
     }
 }
 
+impl From<DraftError> for Error {
+    fn from(err: DraftError) -> Self {
+        Self::Draft(err)
+    }
+}
+
+impl From<PartyError> for Error {
+    fn from(err: PartyError) -> Self {
+        Self::Party(err)
+    }
+}
+
+impl From<GateError> for Error {
+    fn from(err: GateError) -> Self {
+        Self::Gate(err)
+    }
+}
+
 impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Self {
         Self::Rusqlite(err)
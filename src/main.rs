@@ -6,30 +6,34 @@ mod error;
 mod model;
 mod utils;
 
+use std::collections::HashSet;
 use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
 use harmony::client::{ClientBuilder, Context};
 use harmony::gateway::{Intents, Ready, Status};
-use harmony::model::id::{ChannelId, UserId};
+use harmony::model::id::{ChannelId, MessageId, UserId};
 use harmony::model::{Activity, Message};
 use parking_lot::Mutex;
-use trueskill::SimpleTrueSkill as TrueSkill;
 
 use bridge::BridgeEvent;
-use config::{Config, Rank, Roles, Timeout};
+use config::{Config, Rank, RatingKind, Roles, Timeout};
 pub use error::Error;
-use model::{Database, Lobbies, Lobby, QueueUser, Ratings};
+use model::{Database, Lobbies, Lobby, Parties, QueueUser, Ratings, System};
 
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
 const REFRESH_DELAY: Duration = Duration::from_secs(60);
+const TIMER_DELAY: Duration = Duration::from_secs(5);
+/// Number of recent messages of a webhook channel searched for untracked
+/// leaderboard messages at startup.
+const ORPHAN_SCAN_LIMIT: usize = 100;
 
 fn parse_command(msg: &str) -> Option<(String, Vec<String>)> {
     let mut it = msg.split_whitespace().map(|x| x.to_owned());
@@ -37,11 +41,49 @@ fn parse_command(msg: &str) -> Option<(String, Vec<String>)> {
     Some((command, it.collect()))
 }
 
+/// Deletes the leaderboard messages that a previous run failed to delete, and
+/// the recent messages of the webhook that no lobby keeps track of, such as
+/// the ones posted right before a crash.
+fn remove_orphaned_messages(
+    ctx: &Context,
+    database: &Database,
+    channel_id: ChannelId,
+    lobby: &Lobby,
+    tracked: &HashSet<MessageId>,
+) -> Result {
+    let (webhook_id, webhook_token) = match lobby.webhook() {
+        Some((webhook_id, webhook_token, _)) => (*webhook_id, webhook_token),
+        None => return Ok(()),
+    };
+    let (_, orphaned) = database.get_webhook_messages(channel_id)?;
+    for message in orphaned {
+        if let Err(err) = ctx.delete_webhook_message(webhook_id, webhook_token, message) {
+            eprintln!("Err: {:?}", err);
+        }
+    }
+    database.remove_orphaned_webhook_messages(channel_id)?;
+    if let Some(webhook_channel) = database.get_webhook_channel(channel_id)? {
+        for message in ctx.get_channel_messages(webhook_channel, ORPHAN_SCAN_LIMIT)? {
+            if message.webhook_id == Some(webhook_id) && !tracked.contains(&message.id) {
+                if let Err(err) = ctx.delete_webhook_message(webhook_id, webhook_token, message.id)
+                {
+                    eprintln!("Err: {:?}", err);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn ready<T: ToString>(
     ctx: Context,
     ready: Ready,
     prefix: String,
     lobbies: Arc<Mutex<Lobbies>>,
+    database: Arc<Database>,
+    bridge: ChannelId,
+    expired: Vec<(ChannelId, UserId)>,
     game: Option<T>,
 ) -> UserId {
     println!("Bot started");
@@ -50,10 +92,46 @@ fn ready<T: ToString>(
             eprintln!("Err: {:?}", err);
         }
     }
-    thread::spawn(move || loop {
-        thread::sleep(REFRESH_DELAY);
-        {
+    {
+        let lobbies = lobbies.lock();
+        let tracked = lobbies
+            .values()
+            .filter_map(|x| x.webhook())
+            .flat_map(|(_, _, messages)| messages.iter().copied())
+            .collect::<HashSet<_>>();
+        for (&channel_id, lobby) in lobbies.iter() {
+            if let Err(err) = remove_orphaned_messages(&ctx, &database, channel_id, lobby, &tracked)
+            {
+                eprintln!("Err: {:?}", err);
+            }
+        }
+        for (channel_id, user_id) in expired {
+            let lobby = &lobbies[&channel_id];
+            ctx.create_message(channel_id, |m| {
+                m.content(user_id.mention()).embed(|e| {
+                    e.description(format!(
+                        "[{}/{}] {} left the queue (Timeout while the bot was offline).",
+                        lobby.len(),
+                        lobby.queue_capacity(),
+                        user_id.mention()
+                    ))
+                })
+            })
+            .ok();
+        }
+    }
+    thread::spawn(move || {
+        let mut refreshed = Instant::now();
+        loop {
+            thread::sleep(TIMER_DELAY);
             let mut lobbies = lobbies.lock();
+            commands::draft_timeout(&ctx, &mut lobbies, &database, bridge);
+            commands::ready_timeout(&ctx, &mut lobbies, &database, bridge);
+            commands::vote_timeout(&ctx, &mut lobbies, &database, bridge);
+            if refreshed.elapsed() < REFRESH_DELAY {
+                continue;
+            }
+            refreshed = Instant::now();
             let now = Utc::now();
             for (&channel_id, lobby) in lobbies.iter_mut() {
                 let users = lobby
@@ -73,12 +151,16 @@ fn ready<T: ToString>(
                         }
                     })
                     .collect::<Vec<_>>();
+                if users.is_empty() {
+                    continue;
+                }
                 for (user_id, expire) in users {
                     if let Some(expire) = expire {
-                        lobby
-                            .queue_mut()
-                            .entry(user_id)
-                            .and_modify(|e| *e = QueueUser::new(e.expire(), None));
+                        lobby.queue_mut().entry(user_id).and_modify(|e| {
+                            *e = QueueUser::new(e.joined(), e.expire(), None)
+                                .with_party(e.party())
+                                .with_priority(e.priority())
+                        });
                         ctx.create_message(channel_id, |m| {
                         m.content(user_id.mention()).embed(|e| {
                             e.description(format!(
@@ -95,7 +177,7 @@ fn ready<T: ToString>(
                                 e.description(format!(
                                     "[{}/{}] {} left the queue (Timeout).",
                                     lobby.len(),
-                                    lobby.capacity(),
+                                    lobby.queue_capacity(),
                                     user_id.mention()
                                 ))
                             })
@@ -103,6 +185,9 @@ fn ready<T: ToString>(
                         .ok();
                     }
                 }
+                if let Err(err) = database.save_lobby(channel_id, lobby) {
+                    eprintln!("Err: {:?}", err);
+                }
             }
         }
     });
@@ -119,8 +204,10 @@ fn message_create(
     ranks: &[Rank],
     infos: &[ChannelId],
     lobbies: Arc<Mutex<Lobbies>>,
+    parties: &Mutex<Parties>,
     bridge: ChannelId,
-    trueskill: &mut TrueSkill,
+    audit: Option<ChannelId>,
+    systems: &[System],
     database: &Database,
     timeout: Timeout,
 ) {
@@ -145,7 +232,7 @@ fn message_create(
                                     e.description(format!(
                                         "[{}/{}] {} left the queue (Game started).",
                                         lobby.len(),
-                                        lobby.capacity(),
+                                        lobby.queue_capacity(),
                                         user_id.mention(),
                                     ))
                                 })
@@ -154,6 +241,9 @@ fn message_create(
                             }
                         }
                     }
+                    if let Err(err) = database.save_lobby(*channel_id, lobby) {
+                        eprintln!("Err: {:?}", err);
+                    }
                 }
             }
         }
@@ -168,74 +258,97 @@ fn message_create(
                     &msg,
                     roles,
                     &mut lobbies.lock(),
-                    *trueskill,
+                    &parties.lock(),
                     database,
                     bridge,
                     timeout.default,
                     timeout.warn,
+                    &args,
                 ),
                 "forcejoin" | "forcej" | "forceadd" => commands::forcejoin(
                     &ctx,
                     &msg,
                     roles,
                     &mut lobbies.lock(),
-                    *trueskill,
                     database,
                     bridge,
+                    audit,
                     timeout.default,
                     timeout.warn,
                     &args,
                 ),
-                "leave" | "l" => commands::leave(
+                "leave" | "l" => {
+                    commands::leave(&ctx, &msg, &mut lobbies.lock(), database, bridge, &args)
+                }
+                "forceleave" | "forcel" | "forceremove" => commands::forceleave(
                     &ctx,
                     &msg,
+                    roles,
                     &mut lobbies.lock(),
-                    *trueskill,
                     database,
                     bridge,
+                    audit,
+                    &args,
                 ),
-                "forceleave" | "forcel" | "forceremove" => commands::forceleave(
+                "players" => commands::players(
                     &ctx,
                     &msg,
                     roles,
                     &mut lobbies.lock(),
-                    *trueskill,
                     database,
                     bridge,
+                    audit,
                     &args,
                 ),
-                "players" => commands::players(
+                "freeze" => commands::freeze(
                     &ctx,
                     &msg,
                     roles,
                     &mut lobbies.lock(),
-                    *trueskill,
                     database,
-                    bridge,
+                    audit,
+                    &args,
+                ),
+                "unfreeze" => commands::unfreeze(
+                    &ctx,
+                    &msg,
+                    roles,
+                    &mut lobbies.lock(),
+                    database,
+                    audit,
                     &args,
                 ),
-                "freeze" => commands::freeze(&ctx, &msg, roles, &mut lobbies.lock()),
-                "unfreeze" => commands::unfreeze(&ctx, &msg, roles, &mut lobbies.lock()),
                 "queue" | "q" => commands::queue(&ctx, &msg, &lobbies.lock()),
                 "score" | "g" => commands::score(
                     &ctx,
                     &msg,
                     roles,
                     &mut lobbies.lock(),
-                    *trueskill,
                     database,
                     ranks,
+                    audit,
+                    &args,
+                ),
+                "cancel" => commands::cancel(
+                    &ctx,
+                    &msg,
+                    roles,
+                    &mut lobbies.lock(),
+                    database,
+                    bridge,
+                    audit,
+                    timeout.default,
+                    timeout.warn,
                     &args,
                 ),
-                "cancel" => commands::cancel(&ctx, &msg, roles, &lobbies.lock(), database, &args),
                 "undo" | "unset" => commands::undo(
                     &ctx,
                     &msg,
                     roles,
                     &mut lobbies.lock(),
                     database,
-                    *trueskill,
                     ranks,
+                    audit,
                     &args,
                 ),
                 "gamelist" | "gl" => commands::gamelist(&ctx, &msg, &lobbies.lock(), database),
@@ -243,46 +356,111 @@ fn message_create(
                 "gameinfo" | "gi" => {
                     commands::gameinfo(&ctx, &msg, &lobbies.lock(), database, &args)
                 }
-                "clear" => commands::clear(&ctx, &msg, roles, &mut lobbies.lock()),
+                "clear" => commands::clear(
+                    &ctx,
+                    &msg,
+                    roles,
+                    &mut lobbies.lock(),
+                    database,
+                    audit,
+                    &args,
+                ),
+                "ready" | "r" => commands::ready(&ctx, &msg, &mut lobbies.lock(), database, bridge),
+                "vote" => commands::vote(&ctx, &msg, &mut lobbies.lock(), database, bridge, &args),
+                "pick" | "p" => commands::pick(
+                    &ctx,
+                    &msg,
+                    roles,
+                    &mut lobbies.lock(),
+                    database,
+                    bridge,
+                    &args,
+                ),
+                "captains" => commands::captains(
+                    &ctx,
+                    &msg,
+                    roles,
+                    &mut lobbies.lock(),
+                    database,
+                    audit,
+                    &args,
+                ),
                 "rebalance" | "rb" => commands::rebalance(
                     &ctx,
                     &msg,
                     roles,
                     &mut lobbies.lock(),
+                    &parties.lock(),
                     database,
-                    *trueskill,
+                    &args,
                 ),
-                "swap" => commands::swap(
+                "split" => commands::split(
                     &ctx,
                     &msg,
                     roles,
-                    &lobbies.lock(),
+                    &mut lobbies.lock(),
+                    database,
+                    audit,
+                    &args,
+                ),
+                "avoid" => commands::avoid(&ctx, &msg, roles, database, audit, &args),
+                "together" => commands::together(&ctx, &msg, roles, database, audit, &args),
+                "unpair" => commands::unpair(&ctx, &msg, roles, database, audit, &args),
+                "constraints" => commands::constraints(&ctx, &msg, roles, database),
+                "exempt" => {
+                    commands::exempt(&ctx, &msg, roles, &lobbies.lock(), database, audit, &args)
+                }
+                "unexempt" => {
+                    commands::unexempt(&ctx, &msg, roles, &lobbies.lock(), database, audit, &args)
+                }
+                "exemptions" => commands::exemptions(&ctx, &msg, roles, &lobbies.lock(), database),
+                "party" => commands::party(
+                    &ctx,
+                    &msg,
+                    &mut lobbies.lock(),
+                    &mut parties.lock(),
+                    database,
+                    &args,
+                ),
+                "sub" => commands::sub(
+                    &ctx,
+                    &msg,
+                    roles,
+                    &mut lobbies.lock(),
                     database,
-                    *trueskill,
+                    audit,
                     &args,
                 ),
+                "swap" => {
+                    commands::swap(&ctx, &msg, roles, &lobbies.lock(), database, audit, &args)
+                }
                 "rating" | "setrating" => commands::setrating(
                     &ctx,
                     &msg,
                     roles,
                     &mut lobbies.lock(),
-                    *trueskill,
                     database,
                     ranks,
+                    audit,
                     &args,
                 ),
-                "info" => commands::info(&ctx, &msg, &lobbies.lock(), infos, &args),
-                "forceinfo" => commands::forceinfo(&ctx, &msg, roles, &lobbies.lock(), &args),
-                "history" => commands::history(
+                "endseason" => commands::endseason(
                     &ctx,
                     &msg,
-                    ranks,
-                    &lobbies.lock(),
+                    roles,
+                    &mut lobbies.lock(),
                     database,
-                    *trueskill,
-                    infos,
+                    ranks,
+                    audit,
                     &args,
                 ),
+                "info" => commands::info(&ctx, &msg, &lobbies.lock(), database, infos, &args),
+                "forceinfo" => {
+                    commands::forceinfo(&ctx, &msg, roles, &lobbies.lock(), database, &args)
+                }
+                "history" => {
+                    commands::history(&ctx, &msg, ranks, &lobbies.lock(), database, infos, &args)
+                }
                 "forcehistory" => commands::forcehistory(
                     &ctx,
                     &msg,
@@ -290,17 +468,29 @@ fn message_create(
                     ranks,
                     &lobbies.lock(),
                     database,
-                    *trueskill,
                     &args,
                 ),
-                "leaderboard" | "lb" => {
-                    commands::leaderboard(&ctx, &msg, roles, &lobbies.lock(), ranks, &args)
+                "leaderboard" | "lb" => commands::leaderboard(
+                    &ctx,
+                    &msg,
+                    roles,
+                    &lobbies.lock(),
+                    database,
+                    ranks,
+                    &args,
+                ),
+                "audit" => commands::audit(&ctx, &msg, roles, &lobbies.lock(), database, &args),
+                "compare" => {
+                    commands::compare(&ctx, &msg, roles, &lobbies.lock(), database, systems, &args)
+                }
+                "lball" => {
+                    commands::lball(&ctx, &msg, roles, &lobbies.lock(), database, ranks, &args)
                 }
-                "lball" => commands::lball(&ctx, &msg, roles, &lobbies.lock(), ranks, &args),
                 "expire" => commands::expire(
                     &ctx,
                     &msg,
                     &mut lobbies.lock(),
+                    database,
                     timeout.maximum,
                     timeout.warn,
                     &args,
@@ -322,44 +512,111 @@ fn read_config<P: AsRef<Path>>(path: P) -> Config {
     let mut buf = String::new();
     file.read_to_string(&mut buf)
         .expect("Could not read config file");
-    serde_json::from_str(&buf).expect("Malformed config file")
+    let config: Config = serde_json::from_str(&buf).expect("Malformed config file");
+    config
+        .validate()
+        .unwrap_or_else(|err| panic!("Invalid config file: {}", err));
+    config
 }
 
 fn main() {
     let token = env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN");
     let config = read_config("config.json");
-    let database = Database::open(config.database).expect("Could not open database");
-    let mut games = database.get_games().unwrap();
+    let database = Database::open(config.database)
+        .unwrap_or_else(|err| panic!("Could not open database: {}", err));
+    let now = Utc::now();
+    let mut expired = Vec::new();
     let initials = database.get_initial_ratings().unwrap();
-    let mut trueskill = config.trueskill;
+    let systems = [
+        System::TrueSkill(config.trueskill),
+        System::Glicko2(config.glicko2),
+        System::Elo(config.elo),
+    ];
     let lobbies = {
         let mut lobbies = Lobbies::default();
         for conf_lobby in config.lobbies {
-            let ratings = Ratings::from_games(
-                &games.remove(&conf_lobby.channel).unwrap_or_default(),
-                &initials,
-                trueskill,
+            let seasons = database.get_seasons(conf_lobby.channel).unwrap();
+            let start = *seasons.last().unwrap();
+            let seeds = database
+                .get_season_seeds(conf_lobby.channel, seasons.len())
+                .unwrap();
+            let system = match conf_lobby.rating {
+                RatingKind::TrueSkill => System::TrueSkill(config.trueskill),
+                RatingKind::Glicko2 => System::Glicko2(config.glicko2),
+                RatingKind::Elo => System::Elo(config.elo),
+            };
+            let mut ratings = Ratings::new(start, &initials, &seeds, system);
+            let game_ratings = ratings.replay(
+                start,
+                &database.get_games_since(conf_lobby.channel, start).unwrap(),
             );
+            // The configuration may have changed since the last run.
+            database
+                .save_game_ratings(conf_lobby.channel, start, &game_ratings)
+                .expect("Could not save game ratings");
             let mut lobby = Lobby::new(conf_lobby.name, conf_lobby.capacity, ratings);
+            lobby.set_aliases(conf_lobby.aliases);
+            lobby.set_leaderboard_options(conf_lobby.leaderboard);
+            lobby.set_balance(conf_lobby.balance);
+            lobby.set_variety(conf_lobby.variety);
+            lobby.set_party_handicap(conf_lobby.party_handicap);
+            lobby.set_teams(conf_lobby.teams.unwrap_or(2));
+            lobby.set_draft_options(conf_lobby.draft);
+            lobby.set_ready_options(conf_lobby.ready_check);
+            lobby.set_map_vote_options(conf_lobby.map_vote);
+            lobby.set_pool_options(conf_lobby.pool);
+            lobby.set_requeue_cancelled(conf_lobby.requeue_cancelled);
+            lobby.set_gate(conf_lobby.gate);
             if let Some(webhook) = conf_lobby.webhook {
-                lobby.set_webhook(webhook.id, webhook.token);
+                let (messages, _) = database
+                    .get_webhook_messages(conf_lobby.channel)
+                    .expect("Could not restore leaderboard messages");
+                lobby.set_webhook(webhook.id, webhook.token, messages);
             }
+            database
+                .load_lobby(conf_lobby.channel, &mut lobby)
+                .expect("Could not restore lobby");
+            let users = lobby
+                .queue()
+                .iter()
+                .filter(|(_, queue_user)| now >= queue_user.expire())
+                .map(|(&user_id, _)| user_id)
+                .collect::<Vec<_>>();
+            for user_id in users {
+                lobby.leave(user_id, true).ok();
+                expired.push((conf_lobby.channel, user_id));
+            }
+            database
+                .save_lobby(conf_lobby.channel, &lobby)
+                .expect("Could not save lobby");
             lobbies.insert(conf_lobby.channel, lobby);
         }
         Arc::new(Mutex::new(lobbies))
     };
+    let database = Arc::new(database);
+    let parties = Mutex::new(database.load_parties().expect("Could not restore parties"));
     let prefix = config.prefix;
     let roles = config.roles;
     let ranks = Box::leak(config.ranks.into_boxed_slice());
     let infos = config.infos;
     let bridge = config.bridge;
+    let audit = config.audit;
     let game = config.game;
     let bot_user_id = Mutex::new(0.into());
     let client = ClientBuilder::new()
         .with_bot_token(&token)
         .intents(Intents::GUILD_MESSAGES | Intents::DIRECT_MESSAGES)
         .on_ready(|ctx, rdy| {
-            *bot_user_id.lock() = ready(ctx, rdy, prefix.clone(), lobbies.clone(), game.as_ref())
+            *bot_user_id.lock() = ready(
+                ctx,
+                rdy,
+                prefix.clone(),
+                lobbies.clone(),
+                database.clone(),
+                bridge,
+                std::mem::take(&mut expired),
+                game.as_ref(),
+            )
         })
         .on_message_create(|ctx, msg| {
             message_create(
@@ -371,8 +628,10 @@ fn main() {
                 ranks,
                 &infos,
                 lobbies.clone(),
+                &parties,
                 bridge,
-                &mut trueskill,
+                audit,
+                &systems,
                 &database,
                 config.timeout,
             )
@@ -1,9 +1,26 @@
+mod audit;
+mod constraint;
 mod database;
+mod draft;
 mod game;
+mod gate;
 mod lobby;
+mod party;
 mod rating;
+mod ready;
+mod vote;
 
+pub use audit::{AuditEntry, AuditFilter};
+pub use constraint::PairConstraint;
 pub use database::Database;
-pub use game::{Game, Score};
-pub use lobby::{Lobbies, Lobby, LobbyError, QueueUser};
-pub use rating::{PlayerInfo, Ratings};
+pub use draft::{Draft, DraftError, DraftOptions};
+pub use game::{Game, Score, Substitution};
+pub use gate::{Gate, GateError, GateRating};
+pub use lobby::{Lobbies, Lobby, LobbyError, PoolOptions, QueueUser, Rebalance};
+pub use party::{pack, Parties, PartyError};
+pub use rating::{
+    Balance, Elo, GameRatings, Glicko2, LeaderboardOptions, PlayerInfo, Rating, RatingSystem,
+    Ratings, System, TrueSkill, Variety,
+};
+pub use ready::{ReadyCheck, ReadyOptions};
+pub use vote::{MapVote, MapVoteOptions};
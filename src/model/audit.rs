@@ -0,0 +1,56 @@
+use chrono::{DateTime, Utc};
+use harmony::model::id::{ChannelId, UserId};
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub id: usize,
+    pub datetime: DateTime<Utc>,
+    pub actor: UserId,
+    pub channel: ChannelId,
+    pub command: String,
+    pub arguments: String,
+    pub game: Option<usize>,
+    pub player: Option<UserId>,
+    pub before: String,
+    pub after: String,
+}
+
+impl AuditEntry {
+    pub fn new(actor: UserId, channel: ChannelId, command: &str, arguments: &[String]) -> Self {
+        Self {
+            id: 0,
+            datetime: Utc::now(),
+            actor,
+            channel,
+            command: command.to_owned(),
+            arguments: arguments.join(" "),
+            game: None,
+            player: None,
+            before: String::new(),
+            after: String::new(),
+        }
+    }
+
+    pub fn game(mut self, game_id: usize) -> Self {
+        self.game = Some(game_id);
+        self
+    }
+
+    pub fn player(mut self, user_id: UserId) -> Self {
+        self.player = Some(user_id);
+        self
+    }
+
+    pub fn change<T: ToString, U: ToString>(mut self, before: T, after: U) -> Self {
+        self.before = before.to_string();
+        self.after = after.to_string();
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuditFilter {
+    Game(ChannelId, usize),
+    Player(UserId),
+    Actor(UserId),
+}
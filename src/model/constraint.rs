@@ -0,0 +1,32 @@
+use harmony::model::id::UserId;
+
+/// Requirement on the teams of two players, recorded by admins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairConstraint {
+    Apart,
+    Together,
+}
+
+impl PairConstraint {
+    pub fn is_satisfied(self, same_team: bool) -> bool {
+        match self {
+            Self::Apart => !same_team,
+            Self::Together => same_team,
+        }
+    }
+
+    pub fn describe(self, user1: UserId, user2: UserId) -> String {
+        match self {
+            Self::Apart => format!(
+                "{} and {} must not play on the same team.",
+                user1.mention(),
+                user2.mention()
+            ),
+            Self::Together => format!(
+                "{} and {} must play on the same team.",
+                user1.mention(),
+                user2.mention()
+            ),
+        }
+    }
+}
@@ -1,92 +1,1371 @@
-use std::collections::{BTreeMap, HashMap};
+mod migrations;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::path::Path;
 
-use harmony::model::id::{ChannelId, UserId};
-use rusqlite::{params, Connection};
+use chrono::{TimeZone, Utc};
+use harmony::model::id::{ChannelId, MessageId, UserId};
+use parking_lot::Mutex;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Type, ValueRef};
+use rusqlite::{params, params_from_iter, Connection, ToSql};
+
+use super::{
+    AuditEntry, AuditFilter, Draft, Game, GameRatings, Lobby, MapVote, PairConstraint, Parties,
+    PlayerInfo, QueueUser, Rating, RatingSystem, ReadyCheck, Score, Substitution,
+};
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Rusqlite(rusqlite::Error),
+    UnsupportedVersion(usize),
+}
+
+impl Error for DatabaseError {}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rusqlite(err) => err.fmt(f),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Database schema version {} is newer than the latest supported version {}, update the bot.",
+                version,
+                migrations::latest_version()
+            ),
+        }
+    }
+}
 
-use super::Game;
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Rusqlite(err)
+    }
+}
 
+/// Connection to the database, locked around each operation so callers never
+/// hold it while waiting on Discord.
 pub struct Database {
-    connection: Connection,
+    connection: Mutex<Connection>,
 }
 
 impl Database {
-    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+    /// Opens the database, creating the schema or migrating it to the latest
+    /// version if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DatabaseError> {
+        let mut connection = Connection::open(path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        let version = migrations::schema_version(&connection)?;
+        if version > migrations::latest_version() {
+            return Err(DatabaseError::UnsupportedVersion(version));
+        }
+        migrations::run(&mut connection, version)?;
         Ok(Self {
-            connection: Connection::open(path)?,
+            connection: Mutex::new(connection),
         })
     }
 
-    fn last_game_id(&self, channel: ChannelId) -> rusqlite::Result<usize> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT MAX(id) as id FROM games WHERE channel = ?1;")?;
+    pub fn last_game_id(&self, channel: ChannelId) -> rusqlite::Result<usize> {
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare("SELECT MAX(id) as id FROM games WHERE channel = ?1;")?;
         stmt.query_row(params![channel.0], |row| Ok(row.get(0).unwrap_or_default()))
     }
 
+    fn insert_game_players(
+        connection: &Connection,
+        game: &Game,
+        channel: ChannelId,
+    ) -> rusqlite::Result<()> {
+        let mut stmt = connection.prepare(
+            "INSERT INTO game_players (channel, game, team, position, player, place) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+        )?;
+        // Only ranked results need the places of the teams
+        let places = match game.score() {
+            Score::Ranked => game.places(),
+            _ => None,
+        };
+        for (team, players) in game.teams().iter().enumerate() {
+            let place = places.as_ref().map(|x| x[team]);
+            for (position, player) in players.iter().enumerate() {
+                stmt.execute(params![
+                    channel.0,
+                    game.id(),
+                    team,
+                    position,
+                    player.0,
+                    place
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_substitutions(
+        connection: &Connection,
+        game: &Game,
+        channel: ChannelId,
+    ) -> rusqlite::Result<()> {
+        let mut stmt = connection.prepare(
+            "INSERT INTO substitutions (channel, game, position, player_out, player_in, datetime) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+        )?;
+        for (position, substitution) in game.substitutions().iter().enumerate() {
+            stmt.execute(params![
+                channel.0,
+                game.id(),
+                position,
+                substitution.player_out.0,
+                substitution.player_in.0,
+                substitution.datetime.timestamp_millis()
+            ])?;
+        }
+        Ok(())
+    }
+
     pub fn insert_game(&self, game: &mut Game, channel: ChannelId) -> rusqlite::Result<()> {
-        let game_id = self.last_game_id(channel)? + 1;
+        let connection = self.connection.lock();
+        let tx = connection.unchecked_transaction()?;
+        let game_id = connection.query_row(
+            "SELECT MAX(id) as id FROM games WHERE channel = ?1;",
+            params![channel.0],
+            |row| Ok(row.get::<_, usize>(0).unwrap_or_default()),
+        )? + 1;
         game.set_id(game_id);
-        self.connection.execute(
-            "INSERT INTO games (id, channel, game) VALUES (?1, ?2, ?3);",
-            params![game_id, channel.0, serde_json::to_string(game).unwrap()],
+        connection.execute(
+            "INSERT INTO games (channel, id, score, datetime, map, ended) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+            params![
+                channel.0,
+                game_id,
+                game.score(),
+                game.datetime().timestamp_millis(),
+                game.map(),
+                game.ended().map(|x| x.timestamp_millis())
+            ],
         )?;
-        Ok(())
+        Self::insert_game_players(&connection, game, channel)?;
+        Self::insert_substitutions(&connection, game, channel)?;
+        tx.commit()
     }
 
     pub fn update_game(&self, game: &Game, channel: ChannelId) -> rusqlite::Result<()> {
-        self.connection.execute(
-            "UPDATE games SET game = ?3 WHERE channel = ?2 AND id = ?1;",
-            params![game.id(), channel.0, serde_json::to_string(game).unwrap()],
+        let connection = self.connection.lock();
+        let tx = connection.unchecked_transaction()?;
+        connection.execute(
+            "UPDATE games SET score = ?3, ended = ?4, map = ?5 WHERE channel = ?1 AND id = ?2;",
+            params![
+                channel.0,
+                game.id(),
+                game.score(),
+                game.ended().map(|x| x.timestamp_millis()),
+                game.map()
+            ],
+        )?;
+        connection.execute(
+            "DELETE FROM game_players WHERE channel = ?1 AND game = ?2;",
+            params![channel.0, game.id()],
+        )?;
+        connection.execute(
+            "DELETE FROM substitutions WHERE channel = ?1 AND game = ?2;",
+            params![channel.0, game.id()],
+        )?;
+        Self::insert_game_players(&connection, game, channel)?;
+        Self::insert_substitutions(&connection, game, channel)?;
+        tx.commit()
+    }
+
+    /// Returns the maps of the last `limit` games of a lobby played on a map,
+    /// cancelled games aside.
+    pub fn get_recent_maps(
+        &self,
+        channel: ChannelId,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare(
+            "SELECT map FROM games WHERE channel = ?1 AND map IS NOT NULL AND score != ?2 ORDER BY id DESC LIMIT ?3;",
+        )?;
+        let maps = stmt.query_map(params![channel.0, Score::Cancelled, limit], |row| {
+            row.get(0)
+        })?;
+        maps.collect()
+    }
+
+    /// Loads the games of a lobby matching `condition`, an SQL expression on
+    /// the `games` table where `?1` is the channel.
+    fn query_games(
+        &self,
+        condition: &str,
+        params: &[&dyn ToSql],
+    ) -> rusqlite::Result<BTreeMap<usize, Game>> {
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare(&format!(
+            "SELECT game, team, player, place FROM game_players WHERE channel = ?1 AND game IN (SELECT id FROM games WHERE channel = ?1 AND {}) ORDER BY game, team, position;",
+            condition
+        ))?;
+        let mut teams = HashMap::<usize, (Vec<Vec<UserId>>, Vec<usize>)>::new();
+        let players = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, usize>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, Option<usize>>(3)?,
+            ))
+        })?;
+        for player in players {
+            let (game_id, team, user_id, place) = player?;
+            let (teams, places) = teams.entry(game_id).or_default();
+            // Games always have two teams, even if one of them is empty
+            let len = (team + 1).max(2);
+            if teams.len() < len {
+                teams.resize(len, Vec::new());
+                places.resize(len, 0);
+            }
+            teams[team].push(user_id.into());
+            places[team] = place.unwrap_or_default();
+        }
+        let mut stmt = connection.prepare(&format!(
+            "SELECT game, player_out, player_in, datetime FROM substitutions WHERE channel = ?1 AND game IN (SELECT id FROM games WHERE channel = ?1 AND {}) ORDER BY game, position;",
+            condition
+        ))?;
+        let mut substitutions = HashMap::<usize, Vec<Substitution>>::new();
+        let rows = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                Substitution {
+                    player_out: row.get::<_, u64>(1)?.into(),
+                    player_in: row.get::<_, u64>(2)?.into(),
+                    datetime: Utc.timestamp_millis_opt(row.get(3)?).unwrap(),
+                },
+            ))
+        })?;
+        for row in rows {
+            let (game_id, substitution) = row?;
+            substitutions.entry(game_id).or_default().push(substitution);
+        }
+        let mut stmt = connection.prepare(&format!(
+            "SELECT id, score, datetime, map, ended FROM games WHERE channel = ?1 AND {};",
+            condition
+        ))?;
+        let games = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, Score>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        })?;
+        let mut result = BTreeMap::new();
+        for game in games {
+            let (game_id, score, datetime, map, ended) = game?;
+            let (teams, places) = teams
+                .remove(&game_id)
+                .unwrap_or_else(|| (vec![Vec::new(); 2], vec![0; 2]));
+            let mut game = Game::create(teams, Utc.timestamp_millis_opt(datetime).unwrap());
+            game.set_id(game_id);
+            game.set_map(map);
+            game.set_substitutions(substitutions.remove(&game_id).unwrap_or_default());
+            game.set_ended(ended.map(|x| Utc.timestamp_millis_opt(x).unwrap()));
+            match score {
+                Score::Ranked => game.set_places(places),
+                score => game.set_score(score),
+            }
+            result.insert(game_id, game);
+        }
+        Ok(result)
+    }
+
+    /// Returns the games of a channel starting from the given id.
+    pub fn get_games_since(
+        &self,
+        channel: ChannelId,
+        from: usize,
+    ) -> rusqlite::Result<BTreeMap<usize, Game>> {
+        self.query_games("id >= ?2", params![channel.0, from])
+    }
+
+    /// Returns the games of a channel with an id in `from..to`.
+    pub fn get_games_between(
+        &self,
+        channel: ChannelId,
+        from: usize,
+        to: usize,
+    ) -> rusqlite::Result<BTreeMap<usize, Game>> {
+        self.query_games("id >= ?2 AND id < ?3", params![channel.0, from, to])
+    }
+
+    /// Returns the games of a channel in which a player took part.
+    pub fn get_player_games(
+        &self,
+        channel: ChannelId,
+        player: UserId,
+    ) -> rusqlite::Result<BTreeMap<usize, Game>> {
+        self.query_games(
+            "id IN (SELECT game FROM game_players WHERE player = ?2 AND channel = ?1)",
+            params![channel.0, player.0],
+        )
+    }
+
+    /// Returns the first game of a player starting from the given id.
+    pub fn get_first_game(
+        &self,
+        channel: ChannelId,
+        player: UserId,
+        from: usize,
+    ) -> rusqlite::Result<Option<usize>> {
+        let connection = self.connection.lock();
+        connection.query_row(
+            "SELECT MIN(game) FROM game_players WHERE channel = ?1 AND player = ?2 AND game >= ?3;",
+            params![channel.0, player.0, from],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn get_last_games(
+        &self,
+        channel: ChannelId,
+        limit: usize,
+    ) -> rusqlite::Result<BTreeMap<usize, Game>> {
+        self.query_games(
+            "id IN (SELECT id FROM games WHERE channel = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![channel.0, limit],
+        )
+    }
+
+    /// Returns the last `limit` games of a channel before the given id,
+    /// cancelled games aside.
+    pub fn get_previous_games(
+        &self,
+        channel: ChannelId,
+        before: usize,
+        limit: usize,
+    ) -> rusqlite::Result<BTreeMap<usize, Game>> {
+        self.query_games(
+            "id IN (SELECT id FROM games WHERE channel = ?1 AND id < ?2 AND score != ?3 ORDER BY id DESC LIMIT ?4)",
+            params![channel.0, before, Score::Cancelled, limit],
+        )
+    }
+
+    pub fn get_undecided_games(
+        &self,
+        channel: ChannelId,
+    ) -> rusqlite::Result<BTreeMap<usize, Game>> {
+        self.query_games("score = ?2", params![channel.0, Score::Undecided])
+    }
+
+    pub fn get_last_game(&self, channel: ChannelId) -> rusqlite::Result<Option<Game>> {
+        Ok(self.get_last_games(channel, 1)?.into_values().next_back())
+    }
+
+    pub fn get_game(&self, channel: ChannelId, game_id: usize) -> rusqlite::Result<Game> {
+        self.query_games("id = ?2", params![channel.0, game_id])?
+            .remove(&game_id)
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)
+    }
+
+    /// Replaces the rating changes of the games of a channel starting from
+    /// the given id.
+    pub fn save_game_ratings(
+        &self,
+        channel: ChannelId,
+        from: usize,
+        game_ratings: &[GameRatings],
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        let tx = connection.unchecked_transaction()?;
+        connection.execute(
+            "DELETE FROM game_ratings WHERE channel = ?1 AND game >= ?2;",
+            params![channel.0, from],
+        )?;
+        connection.execute(
+            "UPDATE games SET quality = NULL, win_probability = NULL WHERE channel = ?1 AND id >= ?2;",
+            params![channel.0, from],
+        )?;
+        let mut update = connection.prepare(
+            "UPDATE games SET quality = ?3, win_probability = ?4 WHERE channel = ?1 AND id = ?2;",
+        )?;
+        let mut insert = connection.prepare(
+            "INSERT INTO game_ratings (channel, game, player, mean_before, variance_before, mean_after, variance_after) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+        )?;
+        for game in game_ratings.iter() {
+            update.execute(params![
+                channel.0,
+                game.game,
+                game.quality,
+                game.win_probability
+            ])?;
+            for (user_id, before, after) in game.players.iter() {
+                insert.execute(params![
+                    channel.0,
+                    game.game,
+                    user_id.0,
+                    before.mean(),
+                    before.variance(),
+                    after.mean(),
+                    after.variance()
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Returns the rating changes of a game, or `None` if it is not decided.
+    pub fn get_game_ratings(
+        &self,
+        channel: ChannelId,
+        game_id: usize,
+    ) -> rusqlite::Result<Option<GameRatings>> {
+        let connection = self.connection.lock();
+        let (quality, win_probability) = connection.query_row(
+            "SELECT quality, win_probability FROM games WHERE channel = ?1 AND id = ?2;",
+            params![channel.0, game_id],
+            |row| Ok((row.get::<_, Option<f64>>(0)?, row.get::<_, Option<f64>>(1)?)),
+        )?;
+        let quality = match quality {
+            Some(quality) => quality,
+            None => return Ok(None),
+        };
+        let mut stmt = connection.prepare(
+            "SELECT player, mean_before, variance_before, mean_after, variance_after FROM game_ratings WHERE channel = ?1 AND game = ?2;",
+        )?;
+        let players = stmt
+            .query_map(params![channel.0, game_id], |row| {
+                Ok((
+                    UserId::from(row.get::<_, u64>(0)?),
+                    Rating::new(row.get(1)?, row.get(2)?),
+                    Rating::new(row.get(3)?, row.get(4)?),
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Some(GameRatings {
+            game: game_id,
+            quality,
+            win_probability,
+            players,
+        }))
+    }
+
+    /// Returns the id of the first game of every season of a channel, in
+    /// order. The first season always starts at 0.
+    pub fn get_seasons(&self, channel: ChannelId) -> rusqlite::Result<Vec<usize>> {
+        let connection = self.connection.lock();
+        let mut stmt =
+            connection.prepare("SELECT start FROM seasons WHERE channel = ?1 ORDER BY id;")?;
+        let starts = stmt.query_map(params![channel.0], |row| row.get(0))?;
+        std::iter::once(Ok(0)).chain(starts).collect()
+    }
+
+    pub fn get_season_seeds(
+        &self,
+        channel: ChannelId,
+        season: usize,
+    ) -> rusqlite::Result<HashMap<UserId, Rating>> {
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare(
+            "SELECT player, mean, variance FROM season_seeds WHERE channel = ?1 AND season = ?2;",
+        )?;
+        let seeds = stmt.query_map(params![channel.0, season], |row| {
+            Ok((
+                UserId::from(row.get::<_, u64>(0)?),
+                Rating::new(row.get(1)?, row.get(2)?),
+            ))
+        })?;
+        seeds.collect()
+    }
+
+    pub fn remove_season_seed(
+        &self,
+        channel: ChannelId,
+        season: usize,
+        player: UserId,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        connection.execute(
+            "DELETE FROM season_seeds WHERE channel = ?1 AND season = ?2 AND player = ?3;",
+            params![channel.0, season, player.0],
         )?;
         Ok(())
     }
 
-    pub fn get_games(&self) -> rusqlite::Result<HashMap<ChannelId, BTreeMap<usize, Game>>> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT id, channel, game FROM games;")?;
-        let games_raw = stmt.query_map([], |row| {
+    /// Archives the final ratings of a season and starts the next one at the
+    /// game `start`, seeded with `seeds`.
+    pub fn end_season(
+        &self,
+        channel: ChannelId,
+        season: usize,
+        ratings: &HashMap<UserId, PlayerInfo>,
+        start: usize,
+        seeds: &HashMap<UserId, Rating>,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        let tx = connection.unchecked_transaction()?;
+        let mut stmt = connection.prepare(
+            "INSERT INTO season_ratings (channel, season, player, mean, variance, wins, losses, draws) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+        )?;
+        for (user_id, info) in ratings.iter() {
+            stmt.execute(params![
+                channel.0,
+                season,
+                user_id.0,
+                info.rating.mean(),
+                info.rating.variance(),
+                info.wins,
+                info.losses,
+                info.draws
+            ])?;
+        }
+        connection.execute(
+            "INSERT INTO seasons (channel, id, start, datetime) VALUES (?1, ?2, ?3, ?4);",
+            params![channel.0, season + 1, start, Utc::now().timestamp_millis()],
+        )?;
+        let mut stmt = connection.prepare(
+            "INSERT INTO season_seeds (channel, season, player, mean, variance) VALUES (?1, ?2, ?3, ?4, ?5);",
+        )?;
+        for (user_id, rating) in seeds.iter() {
+            stmt.execute(params![
+                channel.0,
+                season + 1,
+                user_id.0,
+                rating.mean(),
+                rating.variance()
+            ])?;
+        }
+        tx.commit()
+    }
+
+    /// Returns the archived ratings of a closed season.
+    pub fn get_season_ratings(
+        &self,
+        channel: ChannelId,
+        season: usize,
+    ) -> rusqlite::Result<HashMap<UserId, PlayerInfo>> {
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare(
+            "SELECT player, mean, variance, wins, losses, draws FROM season_ratings WHERE channel = ?1 AND season = ?2;",
+        )?;
+        let ratings = stmt.query_map(params![channel.0, season], |row| {
+            Ok((
+                UserId::from(row.get::<_, u64>(0)?),
+                PlayerInfo {
+                    rating: Rating::new(row.get(1)?, row.get(2)?),
+                    wins: row.get(3)?,
+                    losses: row.get(4)?,
+                    draws: row.get(5)?,
+                },
+            ))
+        })?;
+        ratings.collect()
+    }
+
+    /// Returns the ratings of a player before and after each of their decided
+    /// games with an id in the given range.
+    pub fn get_player_game_ratings(
+        &self,
+        channel: ChannelId,
+        player: UserId,
+        from: usize,
+        to: usize,
+    ) -> rusqlite::Result<Vec<(Rating, Rating)>> {
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare(
+            "SELECT mean_before, variance_before, mean_after, variance_after FROM game_ratings WHERE channel = ?1 AND player = ?2 AND game >= ?3 AND game < ?4 ORDER BY game;",
+        )?;
+        let ratings = stmt.query_map(params![channel.0, player.0, from, to], |row| {
+            Ok((
+                Rating::new(row.get(0)?, row.get(1)?),
+                Rating::new(row.get(2)?, row.get(3)?),
+            ))
+        })?;
+        ratings.collect()
+    }
+
+    /// Saves the queue, capacity and frozen state of a lobby.
+    pub fn save_lobby(&self, channel: ChannelId, lobby: &Lobby) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        let tx = connection.unchecked_transaction()?;
+        connection.execute(
+            "INSERT INTO lobbies (channel, capacity, frozen) VALUES (?1, ?2, ?3) ON CONFLICT(channel) DO UPDATE SET capacity = ?2, frozen = ?3;",
+            params![channel.0, lobby.capacity(), lobby.is_frozen()],
+        )?;
+        connection.execute("DELETE FROM queue WHERE channel = ?1;", params![channel.0])?;
+        let mut stmt = connection.prepare(
+            "INSERT INTO queue (channel, player, joined, expire, warn, party, priority) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+        )?;
+        for (user_id, queue_user) in lobby.queue().iter() {
+            stmt.execute(params![
+                channel.0,
+                user_id.0,
+                queue_user.joined().timestamp_millis(),
+                queue_user.expire().timestamp_millis(),
+                queue_user.warn().map(|x| x.timestamp_millis()),
+                queue_user.party().map(|x| x.0),
+                queue_user.priority()
+            ])?;
+        }
+        Self::save_draft(&connection, channel, lobby.draft())?;
+        Self::save_ready_check(&connection, channel, lobby.ready_check())?;
+        Self::save_penalties(&connection, channel, lobby)?;
+        Self::save_map_votes(&connection, channel, lobby.map_votes())?;
+        tx.commit()
+    }
+
+    fn save_map_votes(
+        connection: &Connection,
+        channel: ChannelId,
+        map_votes: &[MapVote],
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "DELETE FROM map_votes WHERE channel = ?1;",
+            params![channel.0],
+        )?;
+        let mut votes = connection.prepare(
+            "INSERT INTO map_votes (channel, game, guild, choices, deadline) VALUES (?1, ?2, ?3, ?4, ?5);",
+        )?;
+        let mut players = connection.prepare(
+            "INSERT INTO map_vote_players (channel, game, player, position, choice) VALUES (?1, ?2, ?3, ?4, ?5);",
+        )?;
+        for map_vote in map_votes {
+            votes.execute(params![
+                channel.0,
+                map_vote.game_id(),
+                map_vote.guild_id().0,
+                serde_json::to_string(map_vote.choices()).unwrap(),
+                map_vote.deadline().timestamp_millis()
+            ])?;
+            for (position, user_id) in map_vote.players().enumerate() {
+                players.execute(params![
+                    channel.0,
+                    map_vote.game_id(),
+                    user_id.0,
+                    position,
+                    map_vote.ballots().get(&user_id)
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_ready_check(
+        connection: &Connection,
+        channel: ChannelId,
+        ready_check: Option<&ReadyCheck>,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "DELETE FROM ready_checks WHERE channel = ?1;",
+            params![channel.0],
+        )?;
+        let ready_check = match ready_check {
+            Some(ready_check) => ready_check,
+            None => return Ok(()),
+        };
+        connection.execute(
+            "INSERT INTO ready_checks (channel, guild, deadline) VALUES (?1, ?2, ?3);",
+            params![
+                channel.0,
+                ready_check.guild_id().0,
+                ready_check.deadline().timestamp_millis()
+            ],
+        )?;
+        let mut stmt = connection.prepare(
+            "INSERT INTO ready_players (channel, player, joined, expire, warn, party, priority, confirmed) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+        )?;
+        for (&user_id, queue_user) in ready_check.players().iter() {
+            stmt.execute(params![
+                channel.0,
+                user_id.0,
+                queue_user.joined().timestamp_millis(),
+                queue_user.expire().timestamp_millis(),
+                queue_user.warn().map(|x| x.timestamp_millis()),
+                queue_user.party().map(|x| x.0),
+                queue_user.priority(),
+                ready_check.is_confirmed(user_id)
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// Saves the penalties of a lobby which have not expired yet.
+    fn save_penalties(
+        connection: &Connection,
+        channel: ChannelId,
+        lobby: &Lobby,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "DELETE FROM penalties WHERE channel = ?1;",
+            params![channel.0],
+        )?;
+        let mut stmt = connection
+            .prepare("INSERT INTO penalties (channel, player, until) VALUES (?1, ?2, ?3);")?;
+        let now = Utc::now();
+        for (user_id, &until) in lobby.penalties().iter().filter(|x| *x.1 > now) {
+            stmt.execute(params![channel.0, user_id.0, until.timestamp_millis()])?;
+        }
+        Ok(())
+    }
+
+    fn save_draft(
+        connection: &Connection,
+        channel: ChannelId,
+        draft: Option<&Draft>,
+    ) -> rusqlite::Result<()> {
+        connection.execute("DELETE FROM drafts WHERE channel = ?1;", params![channel.0])?;
+        let draft = match draft {
+            Some(draft) => draft,
+            None => return Ok(()),
+        };
+        connection.execute(
+            "INSERT INTO drafts (channel, guild, picks, deadline) VALUES (?1, ?2, ?3, ?4);",
+            params![
+                channel.0,
+                draft.guild_id().0,
+                draft.picks(),
+                draft.deadline().timestamp_millis()
+            ],
+        )?;
+        let mut stmt = connection.prepare(
+            "INSERT INTO draft_players (channel, player, team, position) VALUES (?1, ?2, ?3, ?4);",
+        )?;
+        for (team, players) in draft.teams().iter().enumerate() {
+            for (position, (user_id, _)) in players.iter().enumerate() {
+                stmt.execute(params![channel.0, user_id.0, Some(team), position])?;
+            }
+        }
+        for (position, (user_id, _)) in draft.pool().iter().enumerate() {
+            stmt.execute(params![channel.0, user_id.0, None::<usize>, position])?;
+        }
+        Ok(())
+    }
+
+    /// Restores the draft of a lobby, with the current ratings of its players.
+    fn load_draft(
+        connection: &Connection,
+        channel: ChannelId,
+        lobby: &mut Lobby,
+    ) -> rusqlite::Result<()> {
+        let mut stmt =
+            connection.prepare("SELECT guild, picks, deadline FROM drafts WHERE channel = ?1;")?;
+        let state = stmt.query_row(params![channel.0], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, usize>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        });
+        let (guild, picks, deadline) = match state {
+            Ok(state) => state,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut stmt = connection.prepare(
+            "SELECT player, team FROM draft_players WHERE channel = ?1 ORDER BY team, position;",
+        )?;
+        let rows = stmt.query_map(params![channel.0], |row| {
+            Ok((row.get::<_, u64>(0)?, row.get::<_, Option<usize>>(1)?))
+        })?;
+        let system = lobby.ratings().system();
+        let mut teams = [Vec::new(), Vec::new()];
+        let mut pool = Vec::new();
+        for row in rows {
+            let (user_id, team) = row?;
+            let user_id = UserId::from(user_id);
+            let rating = lobby
+                .ratings()
+                .get(&user_id)
+                .map(|x| x.rating)
+                .unwrap_or_else(|| system.create_rating());
+            match team {
+                Some(team) => teams[team.min(1)].push((user_id, rating)),
+                None => pool.push((user_id, rating)),
+            }
+        }
+        lobby.set_draft(Some(Draft::restore(
+            guild.into(),
+            teams,
+            pool,
+            picks,
+            Utc.timestamp_millis_opt(deadline).unwrap(),
+        )));
+        Ok(())
+    }
+
+    /// Restores the state saved by [`Database::save_lobby`], if any.
+    pub fn load_lobby(&self, channel: ChannelId, lobby: &mut Lobby) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        let mut stmt =
+            connection.prepare("SELECT capacity, frozen FROM lobbies WHERE channel = ?1;")?;
+        let state = stmt.query_row(params![channel.0], |row| {
+            Ok((row.get::<_, usize>(0)?, row.get::<_, bool>(1)?))
+        });
+        let (capacity, frozen) = match state {
+            Ok(state) => state,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        // Free-for-all lobbies keep one team per player
+        if lobby.teams() > 2 && lobby.teams() == lobby.capacity() {
+            lobby.set_teams(capacity);
+        }
+        lobby.set_capacity(capacity);
+        if frozen {
+            lobby.freeze();
+        }
+        let mut stmt = connection.prepare(
+            "SELECT player, joined, expire, warn, party, priority FROM queue WHERE channel = ?1 ORDER BY joined;",
+        )?;
+        let queue = stmt.query_map(params![channel.0], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<u64>>(4)?,
+                row.get::<_, bool>(5)?,
+            ))
+        })?;
+        for queue_user in queue {
+            let (user_id, joined, expire, warn, party, priority) = queue_user?;
+            lobby.queue_mut().insert(
+                user_id.into(),
+                QueueUser::new(
+                    Utc.timestamp_millis_opt(joined).unwrap(),
+                    Utc.timestamp_millis_opt(expire).unwrap(),
+                    warn.map(|x| Utc.timestamp_millis_opt(x).unwrap()),
+                )
+                .with_party(party.map(UserId::from))
+                .with_priority(priority),
+            );
+        }
+        Self::load_draft(&connection, channel, lobby)?;
+        Self::load_ready_check(&connection, channel, lobby)?;
+        Self::load_map_votes(&connection, channel, lobby)?;
+        let mut stmt =
+            connection.prepare("SELECT player, until FROM penalties WHERE channel = ?1;")?;
+        let penalties = stmt.query_map(params![channel.0], |row| {
+            Ok((row.get::<_, u64>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        for penalty in penalties {
+            let (user_id, until) = penalty?;
+            lobby.penalize(user_id.into(), Utc.timestamp_millis_opt(until).unwrap());
+        }
+        Ok(())
+    }
+
+    fn load_map_votes(
+        connection: &Connection,
+        channel: ChannelId,
+        lobby: &mut Lobby,
+    ) -> rusqlite::Result<()> {
+        let mut stmt = connection.prepare(
+            "SELECT game, guild, choices, deadline FROM map_votes WHERE channel = ?1 ORDER BY game;",
+        )?;
+        let map_votes = stmt.query_map(params![channel.0], |row| {
+            let choices =
+                serde_json::from_str::<Vec<String>>(&row.get::<_, String>(2)?).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(err))
+                })?;
             Ok((
-                row.get::<_, u64>(1)?,
                 row.get::<_, usize>(0)?,
-                serde_json::from_str::<Game>(&row.get::<_, String>(2)?).unwrap(),
+                row.get::<_, u64>(1)?,
+                choices,
+                row.get::<_, i64>(3)?,
             ))
         })?;
-        let mut games = HashMap::<_, BTreeMap<_, _>>::new();
-        for game in games_raw {
-            let game = game?;
-            games
-                .entry(game.0.into())
-                .or_default()
-                .insert(game.1, game.2);
+        let mut stmt = connection.prepare(
+            "SELECT player, choice FROM map_vote_players WHERE channel = ?1 AND game = ?2 ORDER BY position;",
+        )?;
+        for map_vote in map_votes {
+            let (game_id, guild, choices, deadline) = map_vote?;
+            let rows = stmt.query_map(params![channel.0, game_id], |row| {
+                Ok((row.get::<_, u64>(0)?, row.get::<_, Option<usize>>(1)?))
+            })?;
+            let mut players = Vec::new();
+            let mut votes = HashMap::new();
+            for row in rows {
+                let (user_id, choice) = row?;
+                let user_id = UserId::from(user_id);
+                players.push(user_id);
+                if let Some(choice) = choice {
+                    votes.insert(user_id, choice);
+                }
+            }
+            lobby.map_votes_mut().push(MapVote::restore(
+                guild.into(),
+                game_id,
+                players,
+                choices,
+                votes,
+                Utc.timestamp_millis_opt(deadline).unwrap(),
+            ));
         }
-        Ok(games)
+        Ok(())
     }
 
-    pub fn get_game(&self, channel_id: u64, game_id: usize) -> rusqlite::Result<Game> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT game FROM games WHERE channel = ?1 AND id = ?2")?;
-        stmt.query_row(params![channel_id, game_id], |row| {
-            Ok(serde_json::from_str(&row.get::<_, String>(0)?).unwrap())
-        })
+    fn load_ready_check(
+        connection: &Connection,
+        channel: ChannelId,
+        lobby: &mut Lobby,
+    ) -> rusqlite::Result<()> {
+        let mut stmt =
+            connection.prepare("SELECT guild, deadline FROM ready_checks WHERE channel = ?1;")?;
+        let state = stmt.query_row(params![channel.0], |row| {
+            Ok((row.get::<_, u64>(0)?, row.get::<_, i64>(1)?))
+        });
+        let (guild, deadline) = match state {
+            Ok(state) => state,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut stmt = connection.prepare(
+            "SELECT player, joined, expire, warn, party, priority, confirmed FROM ready_players WHERE channel = ?1;",
+        )?;
+        let rows = stmt.query_map(params![channel.0], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<u64>>(4)?,
+                row.get::<_, bool>(5)?,
+                row.get::<_, bool>(6)?,
+            ))
+        })?;
+        let mut players = HashMap::new();
+        let mut confirmed = HashSet::new();
+        for row in rows {
+            let (user_id, joined, expire, warn, party, priority, is_confirmed) = row?;
+            let user_id = UserId::from(user_id);
+            players.insert(
+                user_id,
+                QueueUser::new(
+                    Utc.timestamp_millis_opt(joined).unwrap(),
+                    Utc.timestamp_millis_opt(expire).unwrap(),
+                    warn.map(|x| Utc.timestamp_millis_opt(x).unwrap()),
+                )
+                .with_party(party.map(UserId::from))
+                .with_priority(priority),
+            );
+            if is_confirmed {
+                confirmed.insert(user_id);
+            }
+        }
+        lobby.set_ready_check(Some(ReadyCheck::restore(
+            guild.into(),
+            players,
+            confirmed,
+            Utc.timestamp_millis_opt(deadline).unwrap(),
+        )));
+        Ok(())
+    }
+
+    /// Replaces every saved party with `parties`.
+    pub fn save_parties(&self, parties: &Parties) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        let tx = connection.unchecked_transaction()?;
+        connection.execute_batch("DELETE FROM party_members; DELETE FROM party_invites;")?;
+        let mut members = connection
+            .prepare("INSERT INTO party_members (leader, position, player) VALUES (?1, ?2, ?3);")?;
+        let mut invites =
+            connection.prepare("INSERT INTO party_invites (leader, player) VALUES (?1, ?2);")?;
+        for party in parties.iter() {
+            for (position, user_id) in party.members().iter().enumerate() {
+                members.execute(params![party.leader().0, position, user_id.0])?;
+            }
+            for user_id in party.invites() {
+                invites.execute(params![party.leader().0, user_id.0])?;
+            }
+        }
+        drop((members, invites));
+        tx.commit()
+    }
+
+    pub fn load_parties(&self) -> rusqlite::Result<Parties> {
+        let connection = self.connection.lock();
+        let mut members = HashMap::<u64, Vec<UserId>>::new();
+        let mut stmt = connection
+            .prepare("SELECT leader, player FROM party_members ORDER BY leader, position;")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?)))?;
+        for row in rows {
+            let (leader, user_id) = row?;
+            members.entry(leader).or_default().push(user_id.into());
+        }
+        let mut invites = HashMap::<u64, HashSet<UserId>>::new();
+        let mut stmt = connection.prepare("SELECT leader, player FROM party_invites;")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?)))?;
+        for row in rows {
+            let (leader, user_id) = row?;
+            invites.entry(leader).or_default().insert(user_id.into());
+        }
+        let mut parties = Parties::default();
+        for (leader, members) in members {
+            parties.insert(members, invites.remove(&leader).unwrap_or_default());
+        }
+        Ok(parties)
+    }
+
+    /// Replaces the leaderboard messages of a lobby and records the previous
+    /// messages that could not be deleted.
+    pub fn save_webhook_messages(
+        &self,
+        channel: ChannelId,
+        messages: &[MessageId],
+        orphaned: &[MessageId],
+        webhook_channel: Option<ChannelId>,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        let tx = connection.unchecked_transaction()?;
+        if let Some(webhook_channel) = webhook_channel {
+            connection.execute(
+                "INSERT OR REPLACE INTO webhook_channels (channel, webhook_channel) VALUES (?1, ?2);",
+                params![channel.0, webhook_channel.0],
+            )?;
+        }
+        connection.execute(
+            "DELETE FROM webhook_messages WHERE channel = ?1 AND NOT orphaned;",
+            params![channel.0],
+        )?;
+        let mut stmt = connection.prepare(
+            "INSERT OR REPLACE INTO webhook_messages (channel, message, orphaned) VALUES (?1, ?2, ?3);",
+        )?;
+        for message in messages.iter() {
+            stmt.execute(params![channel.0, message.0, false])?;
+        }
+        for message in orphaned.iter() {
+            stmt.execute(params![channel.0, message.0, true])?;
+        }
+        tx.commit()
+    }
+
+    /// Returns the current leaderboard messages of a lobby and the orphaned
+    /// ones.
+    pub fn get_webhook_messages(
+        &self,
+        channel: ChannelId,
+    ) -> rusqlite::Result<(Vec<MessageId>, Vec<MessageId>)> {
+        let connection = self.connection.lock();
+        let mut stmt = connection
+            .prepare("SELECT message, orphaned FROM webhook_messages WHERE channel = ?1;")?;
+        let rows = stmt.query_map(params![channel.0], |row| {
+            Ok((row.get::<_, u64>(0)?, row.get::<_, bool>(1)?))
+        })?;
+        let mut messages = Vec::new();
+        let mut orphaned = Vec::new();
+        for row in rows {
+            let (message, is_orphaned) = row?;
+            if is_orphaned {
+                orphaned.push(message.into());
+            } else {
+                messages.push(message.into());
+            }
+        }
+        Ok((messages, orphaned))
+    }
+
+    /// Returns the channel in which the webhook of a lobby last posted.
+    pub fn get_webhook_channel(&self, channel: ChannelId) -> rusqlite::Result<Option<ChannelId>> {
+        let connection = self.connection.lock();
+        let mut stmt = connection
+            .prepare("SELECT webhook_channel FROM webhook_channels WHERE channel = ?1;")?;
+        let mut rows = stmt.query_map(params![channel.0], |row| row.get::<_, u64>(0))?;
+        rows.next().transpose().map(|x| x.map(ChannelId::from))
+    }
+
+    pub fn remove_orphaned_webhook_messages(&self, channel: ChannelId) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        connection.execute(
+            "DELETE FROM webhook_messages WHERE channel = ?1 AND orphaned;",
+            params![channel.0],
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_audit_entry(&self, entry: &mut AuditEntry) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        connection.execute(
+            "INSERT INTO audit_log (datetime, actor, channel, command, arguments, game, player, old_value, new_value) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9);",
+            params![
+                entry.datetime.timestamp_millis(),
+                entry.actor.0,
+                entry.channel.0,
+                entry.command,
+                entry.arguments,
+                entry.game,
+                entry.player.map(|x| x.0),
+                entry.before,
+                entry.after
+            ],
+        )?;
+        entry.id = connection.last_insert_rowid() as usize;
+        Ok(())
+    }
+
+    /// Returns the audit entries matching `filter`, most recent first.
+    pub fn get_audit_entries(
+        &self,
+        filter: AuditFilter,
+        limit: usize,
+        offset: usize,
+    ) -> rusqlite::Result<Vec<AuditEntry>> {
+        let connection = self.connection.lock();
+        let (condition, values) = match filter {
+            AuditFilter::Game(channel, game_id) => (
+                "channel = ?3 AND game = ?4",
+                vec![channel.0, game_id as u64],
+            ),
+            AuditFilter::Player(user_id) => ("player = ?3", vec![user_id.0]),
+            AuditFilter::Actor(user_id) => ("actor = ?3", vec![user_id.0]),
+        };
+        let mut stmt = connection.prepare(&format!(
+            "SELECT id, datetime, actor, channel, command, arguments, game, player, old_value, new_value FROM audit_log WHERE {} ORDER BY id DESC LIMIT ?1 OFFSET ?2;",
+            condition
+        ))?;
+        let params = [limit as u64, offset as u64].into_iter().chain(values);
+        let entries = stmt.query_map(params_from_iter(params), |row| {
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                datetime: Utc.timestamp_millis_opt(row.get(1)?).unwrap(),
+                actor: row.get::<_, u64>(2)?.into(),
+                channel: row.get::<_, u64>(3)?.into(),
+                command: row.get(4)?,
+                arguments: row.get(5)?,
+                game: row.get(6)?,
+                player: row.get::<_, Option<u64>>(7)?.map(Into::into),
+                before: row.get(8)?,
+                after: row.get(9)?,
+            })
+        })?;
+        entries.collect()
+    }
+
+    /// Records a constraint between two players, replacing any previous one.
+    pub fn set_pair_constraint(
+        &self,
+        user1: UserId,
+        user2: UserId,
+        constraint: PairConstraint,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection.lock();
+        let (user1, user2) = (user1.0.min(user2.0), user1.0.max(user2.0));
+        connection.execute(
+            "INSERT INTO pair_constraints (player1, player2, together) VALUES (?1, ?2, ?3) ON CONFLICT(player1, player2) DO UPDATE SET together = ?3;",
+            params![user1, user2, constraint == PairConstraint::Together],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the constraint between two players, returns false if there was
+    /// none.
+    pub fn remove_pair_constraint(&self, user1: UserId, user2: UserId) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock();
+        let (user1, user2) = (user1.0.min(user2.0), user1.0.max(user2.0));
+        let removed = connection.execute(
+            "DELETE FROM pair_constraints WHERE player1 = ?1 AND player2 = ?2;",
+            params![user1, user2],
+        )?;
+        Ok(removed > 0)
+    }
+
+    pub fn get_pair_constraints(&self) -> rusqlite::Result<Vec<(UserId, UserId, PairConstraint)>> {
+        let connection = self.connection.lock();
+        let mut stmt =
+            connection.prepare("SELECT player1, player2, together FROM pair_constraints;")?;
+        let constraints = stmt.query_map([], |row| {
+            Ok((
+                UserId::from(row.get::<_, u64>(0)?),
+                UserId::from(row.get::<_, u64>(1)?),
+                if row.get(2)? {
+                    PairConstraint::Together
+                } else {
+                    PairConstraint::Apart
+                },
+            ))
+        })?;
+        constraints.collect()
+    }
+
+    /// Exempts a player from the gate of a lobby, returns false if they
+    /// already were.
+    pub fn add_exemption(&self, channel: ChannelId, player: UserId) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock();
+        let added = connection.execute(
+            "INSERT OR IGNORE INTO gate_exemptions (channel, player) VALUES (?1, ?2);",
+            params![channel.0, player.0],
+        )?;
+        Ok(added > 0)
+    }
+
+    /// Removes the exemption of a player, returns false if there was none.
+    pub fn remove_exemption(&self, channel: ChannelId, player: UserId) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock();
+        let removed = connection.execute(
+            "DELETE FROM gate_exemptions WHERE channel = ?1 AND player = ?2;",
+            params![channel.0, player.0],
+        )?;
+        Ok(removed > 0)
+    }
+
+    pub fn is_exempt(&self, channel: ChannelId, player: UserId) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock();
+        connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM gate_exemptions WHERE channel = ?1 AND player = ?2);",
+            params![channel.0, player.0],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn get_exemptions(&self, channel: ChannelId) -> rusqlite::Result<Vec<UserId>> {
+        let connection = self.connection.lock();
+        let mut stmt =
+            connection.prepare("SELECT player FROM gate_exemptions WHERE channel = ?1;")?;
+        let exemptions = stmt.query_map(params![channel.0], |row| {
+            Ok(UserId::from(row.get::<_, u64>(0)?))
+        })?;
+        exemptions.collect()
     }
 
     pub fn get_initial_ratings(&self) -> rusqlite::Result<HashMap<UserId, f64>> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT player, rating FROM initial;")?;
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare("SELECT player, rating FROM initial;")?;
         let intials_raw =
             stmt.query_map([], |row| Ok((row.get::<_, u64>(0)?.into(), row.get(1)?)))?;
         intials_raw.collect()
     }
 
     pub fn insert_initial_rating(&self, user_id: UserId, rating: f64) -> rusqlite::Result<()> {
-        self.connection.execute(
+        let connection = self.connection.lock();
+        connection.execute(
             "INSERT INTO initial (player, rating) VALUES (?1, ?2) ON CONFLICT(player) DO UPDATE SET rating = ?2;",
             params![user_id.0, rating],
         )?;
         Ok(())
     }
 }
+
+impl ToSql for Score {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok((*self as u8).into())
+    }
+}
+
+impl FromSql for Score {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match u8::column_result(value)? {
+            0 => Ok(Self::Undecided),
+            1 => Ok(Self::Team1),
+            2 => Ok(Self::Team2),
+            3 => Ok(Self::Draw),
+            4 => Ok(Self::Cancelled),
+            5 => Ok(Self::Ranked),
+            x => Err(FromSqlError::OutOfRange(x.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use harmony::model::id::GuildId;
+
+    use super::*;
+    use crate::model::{Elo, Ratings, System};
+
+    fn lobby() -> Lobby {
+        let system = System::Elo(Elo::default());
+        let ratings = Ratings::new(0, &HashMap::new(), &HashMap::new(), system);
+        Lobby::new("test".to_owned(), 4, ratings)
+    }
+
+    /// The current time, rounded to the precision of the database.
+    fn now() -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(Utc::now().timestamp_millis())
+            .unwrap()
+    }
+
+    #[test]
+    fn open_migrates_to_the_latest_version() {
+        let database = Database::open(":memory:").unwrap();
+        let connection = database.connection.lock();
+        assert_eq!(
+            migrations::schema_version(&connection).unwrap(),
+            migrations::latest_version()
+        );
+    }
+
+    #[test]
+    fn ready_checks_round_trip() {
+        let database = Database::open(":memory:").unwrap();
+        let channel = ChannelId(1);
+        let now = now();
+        let mut saved = lobby();
+        saved.join(UserId(5), now, None, false).unwrap();
+        let players = (1..=4)
+            .map(|x| (UserId(x), QueueUser::new(now, now, None)))
+            .collect();
+        let mut ready_check = ReadyCheck::new(GuildId(1), players, now);
+        ready_check.confirm(UserId(2));
+        saved.set_ready_check(Some(ready_check));
+        saved.penalize(UserId(6), now + chrono::Duration::minutes(5));
+        saved.penalize(UserId(7), now - chrono::Duration::minutes(5));
+        database.save_lobby(channel, &saved).unwrap();
+        let mut loaded = lobby();
+        database.load_lobby(channel, &mut loaded).unwrap();
+        assert_eq!(loaded.order(), vec![UserId(5)]);
+        let ready_check = loaded.ready_check().unwrap();
+        assert_eq!(ready_check.players().len(), 4);
+        assert!(ready_check.is_confirmed(UserId(2)));
+        assert_eq!(ready_check.confirmed(), 1);
+        // Expired penalties are not kept
+        assert_eq!(
+            loaded.penalties().keys().collect::<Vec<_>>(),
+            vec![&UserId(6)]
+        );
+        saved.set_ready_check(None);
+        database.save_lobby(channel, &saved).unwrap();
+        let mut loaded = lobby();
+        database.load_lobby(channel, &mut loaded).unwrap();
+        assert!(loaded.ready_check().is_none());
+    }
+
+    #[test]
+    fn games_round_trip() {
+        let database = Database::open(":memory:").unwrap();
+        let channel = ChannelId(1);
+        let mut game = Game::create(vec![vec![UserId(1)], vec![UserId(2)]], Utc::now());
+        database.insert_game(&mut game, channel).unwrap();
+        game.set_map(Some("Dust".to_owned()));
+        game.set_places(vec![1, 0]);
+        database.update_game(&game, channel).unwrap();
+        let saved = database.get_game(channel, game.id()).unwrap();
+        assert_eq!(saved.teams(), game.teams());
+        assert_eq!(saved.score(), Score::Team2);
+        assert_eq!(saved.map(), Some("Dust"));
+        assert!(database.get_undecided_games(channel).unwrap().is_empty());
+    }
+
+    #[test]
+    fn map_votes_round_trip() {
+        let database = Database::open(":memory:").unwrap();
+        let channel = ChannelId(1);
+        let now = now();
+        let mut saved = lobby();
+        saved.map_votes_mut().push(MapVote::new(
+            GuildId(1),
+            3,
+            vec![UserId(7), UserId(8)],
+            vec!["A".to_owned(), "B".to_owned()],
+            now,
+        ));
+        saved.map_votes_mut()[0].vote(UserId(8), 1);
+        database.save_lobby(channel, &saved).unwrap();
+        let mut loaded = lobby();
+        database.load_lobby(channel, &mut loaded).unwrap();
+        let map_vote = &loaded.map_votes()[0];
+        assert_eq!(map_vote.game_id(), 3);
+        assert_eq!(map_vote.choices(), ["A", "B"]);
+        assert_eq!(map_vote.tally(), vec![0, 1]);
+        saved.map_votes_mut().clear();
+        database.save_lobby(channel, &saved).unwrap();
+        let mut loaded = lobby();
+        database.load_lobby(channel, &mut loaded).unwrap();
+        assert!(loaded.map_votes().is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+use chrono::{DateTime, Utc};
+use harmony::model::id::UserId;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, Transaction};
+use serde::Deserialize;
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Schema migrations, in order. The schema version stored in the database is
+/// the number of migrations that have been applied, so entries must never be
+/// reordered or removed once released.
+const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    relational_games,
+    queues,
+    webhook_messages,
+    audit_log,
+    game_ratings,
+    seasons,
+    queue_parties,
+    pair_constraints,
+    team_places,
+    gate_exemptions,
+    game_maps,
+    substitutions,
+    queue_priority,
+    game_ratings_player,
+    webhook_channels,
+    drafts,
+    parties,
+    ready_checks,
+    map_votes,
+];
+
+pub fn latest_version() -> usize {
+    MIGRATIONS.len()
+}
+
+pub fn schema_version(connection: &Connection) -> rusqlite::Result<usize> {
+    connection.query_row("PRAGMA user_version;", [], |row| row.get(0))
+}
+
+/// Applies every pending migration, each one in its own transaction.
+pub fn run(connection: &mut Connection, from: usize) -> rusqlite::Result<()> {
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from) {
+        let tx = connection.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS games (id INTEGER NOT NULL, game JSON NOT NULL, channel INTEGER NOT NULL, PRIMARY KEY (id, channel));
+        CREATE TABLE IF NOT EXISTS initial (player INTEGER NOT NULL, rating INTEGER NOT NULL, PRIMARY KEY (player));",
+    )
+}
+
+/// Game as it used to be serialized in the `game` column of the `games` table.
+#[derive(Deserialize)]
+struct LegacyGame {
+    team1: Vec<UserId>,
+    team2: Vec<UserId>,
+    score: u8,
+    datetime: DateTime<Utc>,
+}
+
+fn relational_games(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE games RENAME TO games_json;
+        CREATE TABLE games (channel INTEGER NOT NULL, id INTEGER NOT NULL, score INTEGER NOT NULL, datetime INTEGER NOT NULL, PRIMARY KEY (channel, id));
+        CREATE TABLE game_players (channel INTEGER NOT NULL, game INTEGER NOT NULL, team INTEGER NOT NULL, position INTEGER NOT NULL, player INTEGER NOT NULL, PRIMARY KEY (channel, game, player), FOREIGN KEY (channel, game) REFERENCES games (channel, id) ON DELETE CASCADE);
+        CREATE INDEX game_players_player ON game_players (player, channel, game);",
+    )?;
+    {
+        let mut select = tx.prepare("SELECT channel, id, game FROM games_json;")?;
+        let mut insert_game = tx
+            .prepare("INSERT INTO games (channel, id, score, datetime) VALUES (?1, ?2, ?3, ?4);")?;
+        let mut insert_player = tx.prepare(
+            "INSERT INTO game_players (channel, game, team, position, player) VALUES (?1, ?2, ?3, ?4, ?5);",
+        )?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let channel = row.get::<_, u64>(0)?;
+            let id = row.get::<_, usize>(1)?;
+            let game =
+                serde_json::from_str::<LegacyGame>(&row.get::<_, String>(2)?).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(2, Type::Text, err.into())
+                })?;
+            insert_game.execute(params![
+                channel,
+                id,
+                game.score,
+                game.datetime.timestamp_millis()
+            ])?;
+            for (team, players) in [game.team1, game.team2].iter().enumerate() {
+                for (position, player) in players.iter().enumerate() {
+                    insert_player.execute(params![channel, id, team, position, player.0])?;
+                }
+            }
+        }
+    }
+    tx.execute_batch("DROP TABLE games_json;")
+}
+
+fn queues(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE lobbies (channel INTEGER NOT NULL, capacity INTEGER NOT NULL, frozen INTEGER NOT NULL, PRIMARY KEY (channel));
+        CREATE TABLE queue (channel INTEGER NOT NULL, player INTEGER NOT NULL, joined INTEGER NOT NULL, expire INTEGER NOT NULL, warn INTEGER, PRIMARY KEY (channel, player));",
+    )
+}
+
+fn webhook_messages(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE webhook_messages (channel INTEGER NOT NULL, message INTEGER NOT NULL, orphaned INTEGER NOT NULL, PRIMARY KEY (channel, message));",
+    )
+}
+
+fn audit_log(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE audit_log (id INTEGER NOT NULL, datetime INTEGER NOT NULL, actor INTEGER NOT NULL, channel INTEGER NOT NULL, command TEXT NOT NULL, arguments TEXT NOT NULL, game INTEGER, player INTEGER, old_value TEXT NOT NULL, new_value TEXT NOT NULL, PRIMARY KEY (id));
+        CREATE INDEX audit_log_game ON audit_log (channel, game);
+        CREATE INDEX audit_log_player ON audit_log (player);
+        CREATE INDEX audit_log_actor ON audit_log (actor);",
+    )
+}
+
+fn game_ratings(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE games ADD COLUMN quality REAL;
+        ALTER TABLE games ADD COLUMN win_probability REAL;
+        CREATE TABLE game_ratings (channel INTEGER NOT NULL, game INTEGER NOT NULL, player INTEGER NOT NULL, mean_before REAL NOT NULL, variance_before REAL NOT NULL, mean_after REAL NOT NULL, variance_after REAL NOT NULL, PRIMARY KEY (channel, game, player), FOREIGN KEY (channel, game) REFERENCES games (channel, id) ON DELETE CASCADE);",
+    )
+}
+
+fn seasons(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE seasons (channel INTEGER NOT NULL, id INTEGER NOT NULL, start INTEGER NOT NULL, datetime INTEGER NOT NULL, PRIMARY KEY (channel, id));
+        CREATE TABLE season_seeds (channel INTEGER NOT NULL, season INTEGER NOT NULL, player INTEGER NOT NULL, mean REAL NOT NULL, variance REAL NOT NULL, PRIMARY KEY (channel, season, player));
+        CREATE TABLE season_ratings (channel INTEGER NOT NULL, season INTEGER NOT NULL, player INTEGER NOT NULL, mean REAL NOT NULL, variance REAL NOT NULL, wins INTEGER NOT NULL, losses INTEGER NOT NULL, draws INTEGER NOT NULL, PRIMARY KEY (channel, season, player));",
+    )
+}
+
+fn queue_parties(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE queue ADD COLUMN party INTEGER;")
+}
+
+fn pair_constraints(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE pair_constraints (player1 INTEGER NOT NULL, player2 INTEGER NOT NULL, together INTEGER NOT NULL, PRIMARY KEY (player1, player2));",
+    )
+}
+
+fn team_places(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE game_players ADD COLUMN place INTEGER;")
+}
+
+fn gate_exemptions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE gate_exemptions (channel INTEGER NOT NULL, player INTEGER NOT NULL, PRIMARY KEY (channel, player));",
+    )
+}
+
+fn game_maps(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE games ADD COLUMN map TEXT;")
+}
+
+fn substitutions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE games ADD COLUMN ended INTEGER;
+        CREATE TABLE substitutions (channel INTEGER NOT NULL, game INTEGER NOT NULL, position INTEGER NOT NULL, player_out INTEGER NOT NULL, player_in INTEGER NOT NULL, datetime INTEGER NOT NULL, PRIMARY KEY (channel, game, position), FOREIGN KEY (channel, game) REFERENCES games (channel, id) ON DELETE CASCADE);",
+    )
+}
+
+fn queue_priority(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE queue ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;")
+}
+
+fn game_ratings_player(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("CREATE INDEX game_ratings_player ON game_ratings (player, channel, game);")
+}
+
+fn webhook_channels(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE webhook_channels (channel INTEGER NOT NULL, webhook_channel INTEGER NOT NULL, PRIMARY KEY (channel));",
+    )
+}
+
+fn drafts(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE drafts (channel INTEGER NOT NULL, guild INTEGER NOT NULL, picks INTEGER NOT NULL, deadline INTEGER NOT NULL, PRIMARY KEY (channel));
+        CREATE TABLE draft_players (channel INTEGER NOT NULL, player INTEGER NOT NULL, team INTEGER, position INTEGER NOT NULL, PRIMARY KEY (channel, player), FOREIGN KEY (channel) REFERENCES drafts (channel) ON DELETE CASCADE);",
+    )
+}
+
+fn parties(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE party_members (leader INTEGER NOT NULL, position INTEGER NOT NULL, player INTEGER NOT NULL, PRIMARY KEY (player));
+        CREATE TABLE party_invites (leader INTEGER NOT NULL, player INTEGER NOT NULL, PRIMARY KEY (leader, player));",
+    )
+}
+
+fn ready_checks(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE ready_checks (channel INTEGER NOT NULL, guild INTEGER NOT NULL, deadline INTEGER NOT NULL, PRIMARY KEY (channel));
+        CREATE TABLE ready_players (channel INTEGER NOT NULL, player INTEGER NOT NULL, joined INTEGER NOT NULL, expire INTEGER NOT NULL, warn INTEGER, party INTEGER, priority INTEGER NOT NULL, confirmed INTEGER NOT NULL, PRIMARY KEY (channel, player), FOREIGN KEY (channel) REFERENCES ready_checks (channel) ON DELETE CASCADE);
+        CREATE TABLE penalties (channel INTEGER NOT NULL, player INTEGER NOT NULL, until INTEGER NOT NULL, PRIMARY KEY (channel, player));",
+    )
+}
+
+fn map_votes(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE map_votes (channel INTEGER NOT NULL, game INTEGER NOT NULL, guild INTEGER NOT NULL, choices TEXT NOT NULL, deadline INTEGER NOT NULL, PRIMARY KEY (channel, game));
+        CREATE TABLE map_vote_players (channel INTEGER NOT NULL, game INTEGER NOT NULL, player INTEGER NOT NULL, position INTEGER NOT NULL, choice INTEGER, PRIMARY KEY (channel, game, player), FOREIGN KEY (channel, game) REFERENCES map_votes (channel, game) ON DELETE CASCADE);",
+    )
+}
@@ -0,0 +1,216 @@
+use std::error::Error;
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use harmony::model::id::{GuildId, UserId};
+use serde::Deserialize;
+
+use super::Rating;
+
+#[derive(Debug, Clone)]
+pub enum DraftError {
+    NotCaptain(UserId),
+    NotInPool(UserId),
+    NotInDraft(UserId),
+    SameCaptain,
+    AlreadyStarted,
+    NotEnoughPlayers,
+}
+
+impl Error for DraftError {}
+
+impl fmt::Display for DraftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotCaptain(user_id) => write!(f, "It is not {}'s turn.", user_id.mention()),
+            Self::NotInPool(user_id) => {
+                write!(f, "{} cannot be picked.", user_id.mention())
+            }
+            Self::NotInDraft(user_id) => write!(f, "{} is not in the draft.", user_id.mention()),
+            Self::SameCaptain => "The captains must be different players.".fmt(f),
+            Self::AlreadyStarted => "The captains have already started picking.".fmt(f),
+            Self::NotEnoughPlayers => "A draft needs at least two players.".fmt(f),
+        }
+    }
+}
+
+/// Draft settings of a lobby.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct DraftOptions {
+    /// Seconds a captain has to pick before the best-rated player left is
+    /// picked for them.
+    #[serde(default = "default_pick_time")]
+    pub pick_time: u64,
+}
+
+fn default_pick_time() -> u64 {
+    60
+}
+
+/// Teams being picked by two captains in snake order: A, B, B, A, A, ...
+#[derive(Debug, Clone)]
+pub struct Draft {
+    guild_id: GuildId,
+    teams: [Vec<(UserId, Rating)>; 2],
+    /// Players left to pick, best-rated first.
+    pool: Vec<(UserId, Rating)>,
+    picks: usize,
+    deadline: DateTime<Utc>,
+}
+
+impl Draft {
+    /// Starts a draft with the two best-rated players as captains.
+    pub fn new(
+        guild_id: GuildId,
+        mut players: Vec<(UserId, Rating)>,
+        deadline: DateTime<Utc>,
+    ) -> Result<Self, DraftError> {
+        if players.len() < 2 {
+            return Err(DraftError::NotEnoughPlayers);
+        }
+        players.sort_by(|a, b| b.1.mean().partial_cmp(&a.1.mean()).unwrap());
+        let pool = players.split_off(2);
+        let mut draft = Self {
+            guild_id,
+            teams: [vec![players[0]], vec![players[1]]],
+            pool,
+            picks: 0,
+            deadline,
+        };
+        draft.assign_last();
+        Ok(draft)
+    }
+
+    /// Restores a draft saved after `picks` picks.
+    pub fn restore(
+        guild_id: GuildId,
+        teams: [Vec<(UserId, Rating)>; 2],
+        pool: Vec<(UserId, Rating)>,
+        picks: usize,
+        deadline: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            guild_id,
+            teams,
+            pool,
+            picks,
+            deadline,
+        }
+    }
+
+    pub fn guild_id(&self) -> GuildId {
+        self.guild_id
+    }
+
+    pub fn teams(&self) -> &[Vec<(UserId, Rating)>; 2] {
+        &self.teams
+    }
+
+    pub fn pool(&self) -> &[(UserId, Rating)] {
+        &self.pool
+    }
+
+    pub fn deadline(&self) -> DateTime<Utc> {
+        self.deadline
+    }
+
+    pub fn picks(&self) -> usize {
+        self.picks
+    }
+
+    pub fn contains(&self, user_id: UserId) -> bool {
+        self.pool
+            .iter()
+            .chain(self.teams.iter().flatten())
+            .any(|x| x.0 == user_id)
+    }
+
+    /// Index of the team whose captain picks next.
+    pub fn turn(&self) -> usize {
+        (self.picks + 1) / 2 % 2
+    }
+
+    /// Captain who picks next.
+    pub fn captain(&self) -> UserId {
+        self.teams[self.turn()][0].0
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pool.is_empty()
+    }
+
+    /// Replaces the captains, which is only possible before the first pick.
+    pub fn set_captains(&mut self, captains: [UserId; 2]) -> Result<(), DraftError> {
+        if self.picks > 0 {
+            return Err(DraftError::AlreadyStarted);
+        }
+        if captains[0] == captains[1] {
+            return Err(DraftError::SameCaptain);
+        }
+        let mut players = self
+            .pool
+            .iter()
+            .chain(self.teams.iter().flatten())
+            .copied()
+            .collect::<Vec<_>>();
+        let mut teams = [vec![], vec![]];
+        for (team, captain) in teams.iter_mut().zip(captains) {
+            let position = players
+                .iter()
+                .position(|x| x.0 == captain)
+                .ok_or(DraftError::NotInDraft(captain))?;
+            team.push(players.remove(position));
+        }
+        players.sort_by(|a, b| b.1.mean().partial_cmp(&a.1.mean()).unwrap());
+        self.teams = teams;
+        self.pool = players;
+        Ok(())
+    }
+
+    /// Picks a player for the captain whose turn it is.
+    pub fn pick(
+        &mut self,
+        captain: UserId,
+        user_id: UserId,
+        deadline: DateTime<Utc>,
+    ) -> Result<(), DraftError> {
+        if captain != self.captain() {
+            return Err(DraftError::NotCaptain(captain));
+        }
+        let position = self
+            .pool
+            .iter()
+            .position(|x| x.0 == user_id)
+            .ok_or(DraftError::NotInPool(user_id))?;
+        self.pick_at(position, deadline);
+        Ok(())
+    }
+
+    /// Picks the best-rated player left for the captain whose turn it is.
+    pub fn pick_best(&mut self, deadline: DateTime<Utc>) -> UserId {
+        let user_id = self.pool[0].0;
+        self.pick_at(0, deadline);
+        user_id
+    }
+
+    pub fn into_teams(self) -> [Vec<(UserId, Rating)>; 2] {
+        self.teams
+    }
+
+    fn pick_at(&mut self, position: usize, deadline: DateTime<Utc>) {
+        let player = self.pool.remove(position);
+        self.teams[self.turn()].push(player);
+        self.picks += 1;
+        self.deadline = deadline;
+        self.assign_last();
+    }
+
+    /// The last player left has no choice to make.
+    fn assign_last(&mut self) {
+        if self.pool.len() == 1 {
+            let player = self.pool.remove(0);
+            self.teams[self.turn()].push(player);
+            self.picks += 1;
+        }
+    }
+}
@@ -13,6 +13,8 @@ pub enum Score {
     Team2,
     Draw,
     Cancelled,
+    /// Decided by the finishing places of more than two teams.
+    Ranked,
 }
 
 impl fmt::Display for Score {
@@ -23,27 +25,46 @@ impl fmt::Display for Score {
             Self::Team2 => "team 2".fmt(f),
             Self::Draw => "draw".fmt(f),
             Self::Cancelled => "cancelled".fmt(f),
+            Self::Ranked => "ranked".fmt(f),
         }
     }
 }
 
+/// Player replaced by a substitute during a game.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Substitution {
+    pub player_out: UserId,
+    pub player_in: UserId,
+    pub datetime: DateTime<Utc>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Game {
     id: usize,
-    team1: Vec<UserId>,
-    team2: Vec<UserId>,
+    teams: Vec<Vec<UserId>>,
     score: Score,
+    /// Finishing place of every team, 0 being first, for ranked results.
+    places: Vec<usize>,
+    /// Map the game is played on, if the lobby has a map pool.
+    map: Option<String>,
+    /// Substitutions, in order, the teams holding the players at the end.
+    substitutions: Vec<Substitution>,
     datetime: DateTime<Utc>,
+    /// When the result was first recorded, kept if it is undone.
+    ended: Option<DateTime<Utc>>,
 }
 
 impl Game {
-    pub fn create(team1: Vec<UserId>, team2: Vec<UserId>, datetime: DateTime<Utc>) -> Self {
+    pub fn create(teams: Vec<Vec<UserId>>, datetime: DateTime<Utc>) -> Self {
         Self {
             id: 0,
-            team1,
-            team2,
+            teams,
             score: Score::Undecided,
+            places: Vec::new(),
+            map: None,
+            substitutions: Vec::new(),
             datetime,
+            ended: None,
         }
     }
 
@@ -55,8 +76,8 @@ impl Game {
         self.id = id;
     }
 
-    pub fn teams(&self) -> [&[UserId]; 2] {
-        [&self.team1, &self.team2]
+    pub fn teams(&self) -> &[Vec<UserId>] {
+        &self.teams
     }
 
     pub fn score(&self) -> Score {
@@ -65,14 +86,201 @@ impl Game {
 
     pub fn set_score(&mut self, score: Score) {
         self.score = score;
+        if score != Score::Ranked {
+            self.places.clear();
+        }
+    }
+
+    /// Finishing place of every team, 0 being first and tied teams sharing a
+    /// place, or `None` if the game is not decided.
+    pub fn places(&self) -> Option<Vec<usize>> {
+        match self.score {
+            Score::Undecided | Score::Cancelled => None,
+            Score::Team1 => Some(vec![0, 1]),
+            Score::Team2 => Some(vec![1, 0]),
+            Score::Draw => Some(vec![0, 0]),
+            Score::Ranked => Some(self.places.clone()),
+        }
+    }
+
+    /// Decides the game from the finishing place of every team. The result
+    /// of a game between two teams is stored as a win or a draw.
+    pub fn set_places(&mut self, places: Vec<usize>) {
+        self.score = match places[..] {
+            [a, b] if a < b => Score::Team1,
+            [a, b] if a > b => Score::Team2,
+            [_, _] => Score::Draw,
+            _ => Score::Ranked,
+        };
+        self.places = if self.score == Score::Ranked {
+            places
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Describes the result, ranked results being given as the finishing
+    /// order of the teams, or of the players in free-for-all games.
+    pub fn result(&self) -> String {
+        if self.score != Score::Ranked {
+            return self.score.to_string();
+        }
+        let ffa = self.teams.iter().all(|team| team.len() == 1);
+        let mut order = (0..self.teams.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| self.places[i]);
+        let mut result = String::new();
+        for (n, &i) in order.iter().enumerate() {
+            if n > 0 {
+                result += if self.places[i] == self.places[order[n - 1]] {
+                    " = "
+                } else {
+                    " > "
+                };
+            }
+            if ffa {
+                result += &self.teams[i][0].mention();
+            } else {
+                result += &format!("team {}", i + 1);
+            }
+        }
+        result
+    }
+
+    pub fn map(&self) -> Option<&str> {
+        self.map.as_deref()
+    }
+
+    pub fn set_map(&mut self, map: Option<String>) {
+        self.map = map;
     }
 
     pub fn datetime(&self) -> DateTime<Utc> {
         self.datetime
     }
 
-    pub fn set_teams(&mut self, mut teams: [Vec<UserId>; 2]) {
-        std::mem::swap(&mut self.team1, &mut teams[0]);
-        std::mem::swap(&mut self.team2, &mut teams[1]);
+    pub fn ended(&self) -> Option<DateTime<Utc>> {
+        self.ended
+    }
+
+    pub fn set_ended(&mut self, ended: Option<DateTime<Utc>>) {
+        self.ended = ended;
+    }
+
+    pub fn substitutions(&self) -> &[Substitution] {
+        &self.substitutions
+    }
+
+    pub fn set_substitutions(&mut self, substitutions: Vec<Substitution>) {
+        self.substitutions = substitutions;
+    }
+
+    /// Replaces a player by a substitute who is not in the game.
+    pub fn substitute(&mut self, substitution: Substitution) {
+        for x in self.teams.iter_mut().flatten() {
+            if *x == substitution.player_out {
+                *x = substitution.player_in;
+            }
+        }
+        self.substitutions.push(substitution);
+    }
+
+    /// Everyone who played in the game, substituted players included.
+    pub fn participants(&self) -> Vec<UserId> {
+        let mut participants = self.teams.iter().flatten().copied().collect::<Vec<_>>();
+        for substitution in self.substitutions.iter() {
+            if !participants.contains(&substitution.player_out) {
+                participants.push(substitution.player_out);
+            }
+        }
+        participants
+    }
+
+    /// Share of the game played by every participant, along with the player
+    /// who ended the game in their place. Everyone played the whole game if
+    /// there was no substitution or if its end is unknown.
+    pub fn play_shares(&self) -> Vec<(UserId, UserId, f64)> {
+        let end = match self.ended {
+            Some(end) if end > self.datetime && !self.substitutions.is_empty() => end,
+            _ => return self.teams.iter().flatten().map(|&x| (x, x, 1.0)).collect(),
+        };
+        let length = (end - self.datetime).num_milliseconds() as f64;
+        // Players at the start of the game
+        let mut lineup = self.teams.iter().flatten().copied().collect::<Vec<_>>();
+        for substitution in self.substitutions.iter().rev() {
+            for x in lineup.iter_mut().filter(|x| **x == substitution.player_in) {
+                *x = substitution.player_out;
+            }
+        }
+        self.participants()
+            .into_iter()
+            .map(|user_id| {
+                let mut since = lineup.contains(&user_id).then_some(self.datetime);
+                let mut slot = user_id;
+                let mut played = 0;
+                for substitution in self.substitutions.iter() {
+                    if substitution.player_out == slot {
+                        slot = substitution.player_in;
+                    }
+                    if substitution.player_out == user_id {
+                        if let Some(since) = since.take() {
+                            played += (substitution.datetime - since).num_milliseconds();
+                        }
+                    } else if substitution.player_in == user_id {
+                        since = Some(substitution.datetime);
+                    }
+                }
+                if let Some(since) = since {
+                    played += (end - since).num_milliseconds();
+                    slot = user_id;
+                }
+                let share = (played as f64 / length).clamp(0.0, 1.0);
+                (user_id, slot, share)
+            })
+            .collect()
+    }
+
+    pub fn set_teams(&mut self, teams: Vec<Vec<UserId>>) {
+        self.teams = teams;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    #[test]
+    fn play_shares_without_substitution() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let (a, b) = (UserId(1), UserId(2));
+        let mut game = Game::create(vec![vec![a], vec![b]], start);
+        game.set_ended(Some(start + Duration::minutes(30)));
+        assert_eq!(game.play_shares(), vec![(a, a, 1.0), (b, b, 1.0)]);
+    }
+
+    #[test]
+    fn play_shares_with_substitutions() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let (a, b, c, d) = (UserId(1), UserId(2), UserId(3), UserId(4));
+        let mut game = Game::create(vec![vec![a], vec![b]], start);
+        // c replaces a after a quarter, then d replaces c at half time
+        game.substitute(Substitution {
+            player_out: a,
+            player_in: c,
+            datetime: start + Duration::minutes(10),
+        });
+        game.substitute(Substitution {
+            player_out: c,
+            player_in: d,
+            datetime: start + Duration::minutes(20),
+        });
+        game.set_ended(Some(start + Duration::minutes(40)));
+        let mut shares = game.play_shares();
+        shares.sort_by_key(|x| x.0 .0);
+        assert_eq!(
+            shares,
+            vec![(a, d, 0.25), (b, b, 1.0), (c, d, 0.25), (d, d, 0.5)]
+        );
     }
 }
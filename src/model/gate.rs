@@ -0,0 +1,79 @@
+use std::error::Error;
+use std::fmt;
+
+use harmony::model::id::{RoleId, UserId};
+use serde::Deserialize;
+
+#[derive(Debug, Clone)]
+pub enum GateError {
+    RatingTooLow(UserId, f64, f64),
+    RatingTooHigh(UserId, f64, f64),
+    MissingRole(UserId, RoleId),
+}
+
+impl Error for GateError {}
+
+impl fmt::Display for GateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RatingTooLow(user_id, rating, min) => write!(
+                f,
+                "This lobby requires a rating of at least {:.0}, {} has {:.0}.",
+                min,
+                user_id.mention(),
+                rating
+            ),
+            Self::RatingTooHigh(user_id, rating, max) => write!(
+                f,
+                "This lobby requires a rating of at most {:.0}, {} has {:.0}.",
+                max,
+                user_id.mention(),
+                rating
+            ),
+            Self::MissingRole(user_id, role_id) => write!(
+                f,
+                "This lobby requires the {} role, which {} does not have.",
+                role_id.mention(),
+                user_id.mention()
+            ),
+        }
+    }
+}
+
+/// Rating checked against the bounds of a gate.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GateRating {
+    /// Rating in the lobby itself.
+    #[default]
+    Lobby,
+    /// Best rating across every lobby with the same rating system.
+    Best,
+}
+
+/// Requirements to join a lobby, which admins can waive for some players.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Gate {
+    pub min_rating: Option<f64>,
+    pub max_rating: Option<f64>,
+    pub rating: GateRating,
+    /// Roles a player needs, all of them.
+    pub roles: Vec<RoleId>,
+}
+
+impl Gate {
+    pub fn is_open(&self) -> bool {
+        self.min_rating.is_none() && self.max_rating.is_none() && self.roles.is_empty()
+    }
+
+    pub fn check_rating(&self, user_id: UserId, rating: f64) -> Result<(), GateError> {
+        if let Some(min) = self.min_rating.filter(|&min| rating < min) {
+            return Err(GateError::RatingTooLow(user_id, rating, min));
+        }
+        if let Some(max) = self.max_rating.filter(|&max| rating > max) {
+            return Err(GateError::RatingTooHigh(user_id, rating, max));
+        }
+        Ok(())
+    }
+}
@@ -6,14 +6,24 @@ use std::ops::{Deref, DerefMut};
 
 use chrono::{DateTime, Utc};
 use harmony::model::id::{ChannelId, MessageId, UserId, WebhookId};
+use serde::Deserialize;
 
-use super::Ratings;
+use super::{
+    pack, Balance, Draft, DraftOptions, Gate, LeaderboardOptions, MapVote, MapVoteOptions, Ratings,
+    ReadyCheck, ReadyOptions, Variety,
+};
 
 #[derive(Debug, Clone)]
 pub enum LobbyError {
     NotInQueue(UserId),
     AlreadyInQueue(UserId),
     Frozen,
+    NotEnoughRoom,
+    PartyTooLarge,
+    Full,
+    Penalized(UserId, DateTime<Utc>),
+    Pending(UserId),
+    PartiesDoNotFit,
 }
 
 impl Error for LobbyError {}
@@ -26,10 +36,40 @@ impl fmt::Display for LobbyError {
                 write!(f, "{} is already in the queue.", user_id.mention())
             }
             Self::Frozen => "The queue is frozen.".fmt(f),
+            Self::NotEnoughRoom => "Not enough room in the queue for the party.".fmt(f),
+            Self::PartyTooLarge => "The party does not fit in a team.".fmt(f),
+            Self::Full => "The queue is full.".fmt(f),
+            Self::Penalized(user_id, until) => write!(
+                f,
+                "{} cannot queue until <t:{}:t> for missing a ready check.",
+                user_id.mention(),
+                until.timestamp()
+            ),
+            Self::PartiesDoNotFit => {
+                "The parties in the queue could not all be kept together.".fmt(f)
+            }
+            Self::Pending(user_id) => {
+                write!(f, "{} is already picked for a game.", user_id.mention())
+            }
         }
     }
 }
 
+/// Settings of a lobby whose queue holds more players than a game needs.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct PoolOptions {
+    /// Players in the queue when a game is picked from it.
+    pub size: usize,
+    /// Importance of the time waited against the quality of the game, the
+    /// longest wait counting as much as a perfect quality at 1.
+    #[serde(default = "default_wait")]
+    pub wait: f64,
+}
+
+fn default_wait() -> f64 {
+    0.5
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Lobbies(HashMap<ChannelId, Lobby>);
 
@@ -47,13 +87,50 @@ impl DerefMut for Lobbies {
     }
 }
 
+impl Lobbies {
+    /// Finds a lobby from its name or one of its aliases, ignoring case.
+    pub fn find(&self, name: &str) -> Option<ChannelId> {
+        self.0
+            .iter()
+            .find(|(_, lobby)| {
+                lobby.name.eq_ignore_ascii_case(name)
+                    || lobby.aliases.iter().any(|x| x.eq_ignore_ascii_case(name))
+            })
+            .map(|(&channel_id, _)| channel_id)
+    }
+
+    /// Whether a player is picked for a game being set up in any lobby.
+    pub fn is_pending(&self, user_id: UserId) -> bool {
+        self.0.values().any(|lobby| lobby.is_pending(user_id))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Lobby {
     queue: HashMap<UserId, QueueUser>,
     name: String,
+    aliases: Vec<String>,
     ratings: Ratings,
+    leaderboard_options: LeaderboardOptions,
+    balance: Balance,
+    variety: Variety,
+    party_handicap: f64,
+    draft_options: Option<DraftOptions>,
+    draft: Option<Draft>,
+    ready_options: Option<ReadyOptions>,
+    ready_check: Option<ReadyCheck>,
+    /// End of the penalty of the players who missed a ready check.
+    penalties: HashMap<UserId, DateTime<Utc>>,
+    map_vote_options: Option<MapVoteOptions>,
+    map_votes: Vec<MapVote>,
+    pool_options: Option<PoolOptions>,
+    /// Whether the players of a cancelled game queue again with priority.
+    requeue_cancelled: bool,
+    rebalance: Option<Rebalance>,
+    gate: Gate,
     webhook: Option<(WebhookId, String, Vec<MessageId>)>,
     capacity: usize,
+    teams: usize,
     frozen: bool,
 }
 
@@ -62,9 +139,26 @@ impl Lobby {
         Self {
             queue: HashMap::default(),
             name,
+            aliases: Vec::new(),
             ratings,
+            leaderboard_options: LeaderboardOptions::default(),
+            balance: Balance::default(),
+            variety: Variety::default(),
+            party_handicap: 0.0,
+            draft_options: None,
+            draft: None,
+            ready_options: None,
+            ready_check: None,
+            penalties: HashMap::new(),
+            map_vote_options: None,
+            map_votes: Vec::new(),
+            pool_options: None,
+            requeue_cancelled: false,
+            rebalance: None,
+            gate: Gate::default(),
             webhook: None,
             capacity,
+            teams: 2,
             frozen: false,
         }
     }
@@ -73,10 +167,244 @@ impl Lobby {
         &self.ratings
     }
 
+    pub fn ratings_mut(&mut self) -> &mut Ratings {
+        &mut self.ratings
+    }
+
+    pub fn leaderboard_options(&self) -> LeaderboardOptions {
+        self.leaderboard_options
+    }
+
+    pub fn set_leaderboard_options(&mut self, leaderboard_options: LeaderboardOptions) {
+        self.leaderboard_options = leaderboard_options;
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    pub fn set_balance(&mut self, balance: Balance) {
+        self.balance = balance;
+    }
+
+    /// Penalty for repeated teammates when balancing the teams.
+    pub fn variety(&self) -> Variety {
+        self.variety
+    }
+
+    pub fn set_variety(&mut self, variety: Variety) {
+        self.variety = variety;
+    }
+
+    /// Number of teams of a game, free-for-all games having one team per
+    /// player.
+    pub fn teams(&self) -> usize {
+        self.teams
+    }
+
+    pub fn set_teams(&mut self, teams: usize) {
+        self.teams = teams;
+    }
+
+    /// Rating added to the players of a party when balancing the teams.
+    pub fn party_handicap(&self) -> f64 {
+        self.party_handicap
+    }
+
+    pub fn set_party_handicap(&mut self, party_handicap: f64) {
+        self.party_handicap = party_handicap;
+    }
+
+    /// Draft settings, `None` if teams are balanced automatically.
+    pub fn draft_options(&self) -> Option<DraftOptions> {
+        self.draft_options
+    }
+
+    pub fn set_draft_options(&mut self, draft_options: Option<DraftOptions>) {
+        self.draft_options = draft_options;
+    }
+
+    /// Ready check settings, `None` if games start as soon as the queue is
+    /// full.
+    pub fn ready_options(&self) -> Option<ReadyOptions> {
+        self.ready_options
+    }
+
+    pub fn set_ready_options(&mut self, ready_options: Option<ReadyOptions>) {
+        self.ready_options = ready_options;
+    }
+
+    /// Ready check in progress, if any.
+    pub fn ready_check(&self) -> Option<&ReadyCheck> {
+        self.ready_check.as_ref()
+    }
+
+    pub fn ready_check_mut(&mut self) -> Option<&mut ReadyCheck> {
+        self.ready_check.as_mut()
+    }
+
+    pub fn set_ready_check(&mut self, ready_check: Option<ReadyCheck>) {
+        self.ready_check = ready_check;
+    }
+
+    pub fn take_ready_check(&mut self) -> Option<ReadyCheck> {
+        self.ready_check.take()
+    }
+
+    /// Prevents a player from queuing until `until`.
+    pub fn penalize(&mut self, user_id: UserId, until: DateTime<Utc>) {
+        self.penalties.insert(user_id, until);
+    }
+
+    /// End of the penalty of every penalized player, expired ones included.
+    pub fn penalties(&self) -> &HashMap<UserId, DateTime<Utc>> {
+        &self.penalties
+    }
+
+    /// Whether the queue is full and no game is being set up.
+    pub fn can_start(&self) -> bool {
+        self.queue.len() == self.queue_capacity()
+            && self.draft.is_none()
+            && self.ready_check.is_none()
+    }
+
+    /// Puts players back at the front of the queue, removing the players who
+    /// joined last if it overflows. Returns the removed players.
+    pub fn requeue(&mut self, players: HashMap<UserId, QueueUser>) -> Vec<UserId> {
+        let added = players
+            .keys()
+            .filter(|x| !self.queue.contains_key(x))
+            .count();
+        let overflow = (self.queue.len() + added).saturating_sub(self.queue_capacity());
+        let removed = self
+            .order()
+            .into_iter()
+            .filter(|x| !players.contains_key(x))
+            .rev()
+            .take(overflow)
+            .collect::<Vec<_>>();
+        for user_id in removed.iter() {
+            self.queue.remove(user_id);
+        }
+        self.queue.extend(players);
+        removed
+    }
+
+    /// Whether a player is in the queue, its ready check or a map vote.
+    pub fn is_queued(&self, user_id: UserId) -> bool {
+        self.queue.contains_key(&user_id)
+            || self
+                .ready_check
+                .as_ref()
+                .is_some_and(|x| x.contains(user_id))
+            || self.map_votes.iter().any(|x| x.contains(user_id))
+    }
+
+    /// Whether a player is picked for a game being set up in the lobby.
+    pub fn is_pending(&self, user_id: UserId) -> bool {
+        self.draft.as_ref().is_some_and(|x| x.contains(user_id))
+            || self
+                .ready_check
+                .as_ref()
+                .is_some_and(|x| x.contains(user_id))
+            || self.map_votes.iter().any(|x| x.contains(user_id))
+    }
+
+    /// Checks the conditions shared by every way of joining the queue.
+    fn check_join(&self, user_id: UserId, force: bool) -> Result<(), LobbyError> {
+        if !force && self.frozen {
+            return Err(LobbyError::Frozen);
+        }
+        if self.is_queued(user_id) {
+            return Err(LobbyError::AlreadyInQueue(user_id));
+        }
+        match self.penalties.get(&user_id) {
+            Some(&until) if !force && until > Utc::now() => {
+                Err(LobbyError::Penalized(user_id, until))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Map vote settings, `None` if the lobby has no map pool.
+    pub fn map_vote_options(&self) -> Option<&MapVoteOptions> {
+        self.map_vote_options.as_ref()
+    }
+
+    pub fn set_map_vote_options(&mut self, map_vote_options: Option<MapVoteOptions>) {
+        self.map_vote_options = map_vote_options;
+    }
+
+    /// Map votes of the games about to start.
+    pub fn map_votes(&self) -> &[MapVote] {
+        &self.map_votes
+    }
+
+    pub fn map_votes_mut(&mut self) -> &mut Vec<MapVote> {
+        &mut self.map_votes
+    }
+
+    /// Pool settings, `None` if the queue holds a single game.
+    pub fn pool_options(&self) -> Option<PoolOptions> {
+        self.pool_options
+    }
+
+    pub fn set_pool_options(&mut self, pool_options: Option<PoolOptions>) {
+        self.pool_options = pool_options;
+    }
+
+    pub fn requeue_cancelled(&self) -> bool {
+        self.requeue_cancelled
+    }
+
+    pub fn set_requeue_cancelled(&mut self, requeue_cancelled: bool) {
+        self.requeue_cancelled = requeue_cancelled;
+    }
+
+    /// Team splits proposed by the last rebalance, if any.
+    pub fn rebalance(&self) -> Option<&Rebalance> {
+        self.rebalance.as_ref()
+    }
+
+    pub fn set_rebalance(&mut self, rebalance: Option<Rebalance>) {
+        self.rebalance = rebalance;
+    }
+
+    /// Requirements to join the queue, admins not being bound by them.
+    pub fn gate(&self) -> &Gate {
+        &self.gate
+    }
+
+    pub fn set_gate(&mut self, gate: Gate) {
+        self.gate = gate;
+    }
+
+    /// Draft in progress, if any.
+    pub fn draft(&self) -> Option<&Draft> {
+        self.draft.as_ref()
+    }
+
+    pub fn draft_mut(&mut self) -> Option<&mut Draft> {
+        self.draft.as_mut()
+    }
+
+    pub fn set_draft(&mut self, draft: Option<Draft>) {
+        self.draft = draft;
+    }
+
+    pub fn take_draft(&mut self) -> Option<Draft> {
+        self.draft.take()
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Sets the other names of the lobby, to join it from another channel.
+    pub fn set_aliases(&mut self, aliases: Vec<String>) {
+        self.aliases = aliases;
+    }
+
     pub fn join(
         &mut self,
         user_id: UserId,
@@ -84,19 +412,91 @@ impl Lobby {
         warn: Option<DateTime<Utc>>,
         force: bool,
     ) -> Result<(), LobbyError> {
-        if !force && self.frozen {
-            return Err(LobbyError::Frozen);
+        self.check_join(user_id, force)?;
+        if self.queue.len() >= self.queue_capacity() {
+            return Err(LobbyError::Full);
         }
-        if self
-            .queue
-            .insert(user_id, QueueUser::new(expire, warn))
-            .is_some()
-        {
-            return Err(LobbyError::AlreadyInQueue(user_id));
+        self.queue
+            .insert(user_id, QueueUser::new(Utc::now(), expire, warn));
+        Ok(())
+    }
+
+    /// Adds every member of a party to the queue, the leader first.
+    pub fn join_party(
+        &mut self,
+        members: &[UserId],
+        expire: DateTime<Utc>,
+        warn: Option<DateTime<Utc>>,
+        force: bool,
+    ) -> Result<(), LobbyError> {
+        for &user_id in members {
+            self.check_join(user_id, force)?;
+        }
+        if members.len() > self.capacity / self.teams {
+            return Err(LobbyError::PartyTooLarge);
+        }
+        if self.queue.len() + members.len() > self.queue_capacity() {
+            return Err(LobbyError::NotEnoughRoom);
+        }
+        let fits = match self.pool_options {
+            Some(_) => self.pool_fits(members.len()),
+            None => self.parties_fit(members.len()),
+        };
+        if !fits {
+            return Err(LobbyError::PartiesDoNotFit);
+        }
+        let now = Utc::now();
+        for &user_id in members {
+            self.queue.insert(
+                user_id,
+                QueueUser::new(now, expire, warn).with_party(Some(members[0])),
+            );
         }
         Ok(())
     }
 
+    /// Sizes of the parties of the queue, plus a new one of `len` players.
+    fn party_sizes(&self, len: usize) -> Vec<usize> {
+        let mut parties = HashMap::<UserId, usize>::new();
+        for queue_user in self.queue.values() {
+            if let Some(party) = queue_user.party {
+                *parties.entry(party).or_default() += 1;
+            }
+        }
+        parties.into_values().chain(std::iter::once(len)).collect()
+    }
+
+    /// Whether every party of the queue, plus a new one of `len` players, can
+    /// be kept on a single team.
+    fn parties_fit(&self, len: usize) -> bool {
+        pack(&self.party_sizes(len), &self.team_capacities()).is_some()
+    }
+
+    /// Whether a pooled queue can still make up a game once a new party of
+    /// `len` players joins, the rest of the queue being filled by players
+    /// without a party: some of the parties must fit on the teams and leave
+    /// no more room than there are such players.
+    fn pool_fits(&self, len: usize) -> bool {
+        let sizes = self.party_sizes(len);
+        let solos = self
+            .queue_capacity()
+            .saturating_sub(sizes.iter().sum::<usize>());
+        let min = self.capacity.saturating_sub(solos);
+        let capacities = self.team_capacities();
+        let mut fits = |subset: &[usize]| {
+            let sum = subset.iter().sum::<usize>();
+            sum >= min && sum <= self.capacity && pack(subset, &capacities).is_some()
+        };
+        any_subset(&sizes, &mut Vec::new(), &mut fits)
+    }
+
+    /// Number of players of every team of a game.
+    pub fn team_capacities(&self) -> Vec<usize> {
+        (0..self.teams)
+            .map(|i| self.capacity / self.teams + usize::from(i < self.capacity % self.teams))
+            .collect()
+    }
+
     pub fn leave(&mut self, user_id: UserId, force: bool) -> Result<(), LobbyError> {
         if !force && self.frozen {
             return Err(LobbyError::Frozen);
@@ -115,6 +515,14 @@ impl Lobby {
         &mut self.queue
     }
 
+    /// Players of the queue in order: those with priority first, then by
+    /// join time, the leader of a party before its members.
+    pub fn order(&self) -> Vec<UserId> {
+        let mut order = self.queue.iter().collect::<Vec<_>>();
+        order.sort_by_key(|&(x, y)| (!y.priority, y.joined, y.party.is_some_and(|z| z != *x), x.0));
+        order.into_iter().map(|(&x, _)| x).collect()
+    }
+
     pub fn clear(&mut self) -> HashMap<UserId, QueueUser> {
         mem::take(&mut self.queue)
     }
@@ -131,6 +539,15 @@ impl Lobby {
         self.capacity = capacity;
     }
 
+    /// Players the queue holds, more than a game needs if the lobby pools
+    /// its players.
+    pub fn queue_capacity(&self) -> usize {
+        match self.pool_options {
+            Some(options) => options.size.max(self.capacity),
+            None => self.capacity,
+        }
+    }
+
     pub fn freeze(&mut self) {
         self.frozen = true;
     }
@@ -139,28 +556,87 @@ impl Lobby {
         self.frozen = false;
     }
 
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn webhook(&self) -> Option<&(WebhookId, String, Vec<MessageId>)> {
+        self.webhook.as_ref()
+    }
+
     pub fn webhook_mut(&mut self) -> &mut Option<(WebhookId, String, Vec<MessageId>)> {
         &mut self.webhook
     }
 
-    pub fn set_webhook(&mut self, webhook_id: WebhookId, webhook_token: String) {
-        self.webhook = Some((webhook_id, webhook_token, Vec::new()));
+    pub fn set_webhook(
+        &mut self,
+        webhook_id: WebhookId,
+        webhook_token: String,
+        messages: Vec<MessageId>,
+    ) {
+        self.webhook = Some((webhook_id, webhook_token, messages));
     }
+}
 
-    pub fn set_ratings(&mut self, ratings: Ratings) {
-        self.ratings = ratings;
-    }
+/// Whether `f` holds for a subset of `sizes`, added to `subset`.
+fn any_subset(
+    sizes: &[usize],
+    subset: &mut Vec<usize>,
+    f: &mut impl FnMut(&[usize]) -> bool,
+) -> bool {
+    let (&size, rest) = match sizes.split_first() {
+        Some(first) => first,
+        None => return f(subset),
+    };
+    subset.push(size);
+    let found = any_subset(rest, subset, f);
+    subset.pop();
+    found || any_subset(rest, subset, f)
+}
+
+/// Team splits proposed to the admins for a game.
+#[derive(Debug, Clone)]
+pub struct Rebalance {
+    pub game_id: usize,
+    /// Teams of the game when the splits were proposed.
+    pub teams: Vec<Vec<UserId>>,
+    pub splits: Vec<Vec<Vec<UserId>>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct QueueUser {
+    joined: DateTime<Utc>,
     expire: DateTime<Utc>,
     warn: Option<DateTime<Utc>>,
+    /// Leader of the party the player queued with.
+    party: Option<UserId>,
+    /// Whether the player is ahead of the others, after a cancelled game.
+    priority: bool,
 }
 
 impl QueueUser {
-    pub fn new(expire: DateTime<Utc>, warn: Option<DateTime<Utc>>) -> Self {
-        Self { expire, warn }
+    pub fn new(joined: DateTime<Utc>, expire: DateTime<Utc>, warn: Option<DateTime<Utc>>) -> Self {
+        Self {
+            joined,
+            expire,
+            warn,
+            party: None,
+            priority: false,
+        }
+    }
+
+    pub fn with_party(mut self, party: Option<UserId>) -> Self {
+        self.party = party;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: bool) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn joined(&self) -> DateTime<Utc> {
+        self.joined
     }
 
     pub fn expire(&self) -> DateTime<Utc> {
@@ -170,4 +646,63 @@ impl QueueUser {
     pub fn warn(&self) -> Option<DateTime<Utc>> {
         self.warn
     }
+
+    pub fn party(&self) -> Option<UserId> {
+        self.party
+    }
+
+    pub fn priority(&self) -> bool {
+        self.priority
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Elo, System};
+
+    fn lobby(capacity: usize, pool: Option<usize>) -> Lobby {
+        let system = System::Elo(Elo::default());
+        let ratings = Ratings::new(0, &HashMap::new(), &HashMap::new(), system);
+        let mut lobby = Lobby::new("test".to_owned(), capacity, ratings);
+        lobby.set_pool_options(pool.map(|size| PoolOptions { size, wait: 0.5 }));
+        lobby
+    }
+
+    fn join_party(lobby: &mut Lobby, members: &[u64]) -> Result<(), LobbyError> {
+        let members = members.iter().map(|&x| UserId(x)).collect::<Vec<_>>();
+        lobby.join_party(&members, Utc::now(), None, false)
+    }
+
+    #[test]
+    fn parties_must_fit_on_the_teams() {
+        let mut lobby = lobby(6, None);
+        join_party(&mut lobby, &[1, 2]).unwrap();
+        join_party(&mut lobby, &[3, 4]).unwrap();
+        assert!(matches!(
+            join_party(&mut lobby, &[5, 6]),
+            Err(LobbyError::PartiesDoNotFit)
+        ));
+        lobby.join(UserId(5), Utc::now(), None, false).unwrap();
+        lobby.join(UserId(6), Utc::now(), None, false).unwrap();
+        assert!(lobby.can_start());
+    }
+
+    #[test]
+    fn pooled_parties_must_make_up_a_game() {
+        // Three parties of two cannot make two teams of three, and a single
+        // player is not enough to complete two of them
+        let mut lobby = lobby(6, Some(7));
+        join_party(&mut lobby, &[1, 2]).unwrap();
+        join_party(&mut lobby, &[3, 4]).unwrap();
+        assert!(matches!(
+            join_party(&mut lobby, &[5, 6]),
+            Err(LobbyError::PartiesDoNotFit)
+        ));
+        // Two single players can
+        let mut lobby = self::lobby(6, Some(8));
+        join_party(&mut lobby, &[1, 2]).unwrap();
+        join_party(&mut lobby, &[3, 4]).unwrap();
+        join_party(&mut lobby, &[5, 6]).unwrap();
+    }
 }
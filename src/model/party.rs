@@ -0,0 +1,228 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+use harmony::model::id::UserId;
+
+#[derive(Debug, Clone)]
+pub enum PartyError {
+    NotInParty(UserId),
+    AlreadyInParty(UserId),
+    NotLeader(UserId),
+    NotInvited(UserId),
+}
+
+impl Error for PartyError {}
+
+impl fmt::Display for PartyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInParty(user_id) => write!(f, "{} is not in a party.", user_id.mention()),
+            Self::AlreadyInParty(user_id) => {
+                write!(f, "{} is already in a party.", user_id.mention())
+            }
+            Self::NotLeader(user_id) => {
+                write!(f, "{} is not the party leader.", user_id.mention())
+            }
+            Self::NotInvited(user_id) => write!(f, "{} was not invited.", user_id.mention()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Party {
+    /// Members of the party, the leader first.
+    members: Vec<UserId>,
+    invites: HashSet<UserId>,
+}
+
+impl Party {
+    pub fn leader(&self) -> UserId {
+        self.members[0]
+    }
+
+    pub fn members(&self) -> &[UserId] {
+        &self.members
+    }
+
+    pub fn invites(&self) -> &HashSet<UserId> {
+        &self.invites
+    }
+}
+
+/// Assigns items of the given sizes to bins of the given capacities, returning
+/// the bin of every item, or `None` if they cannot all fit.
+pub fn pack(sizes: &[usize], capacities: &[usize]) -> Option<Vec<usize>> {
+    // Placing the largest items first fails early
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&x| std::cmp::Reverse(sizes[x]));
+    let mut room = capacities.to_vec();
+    let mut bins = vec![0; sizes.len()];
+    if pack_from(sizes, &order, &mut room, &mut bins) {
+        Some(bins)
+    } else {
+        None
+    }
+}
+
+fn pack_from(sizes: &[usize], order: &[usize], room: &mut [usize], bins: &mut [usize]) -> bool {
+    let (&x, rest) = match order.split_first() {
+        Some(first) => first,
+        None => return true,
+    };
+    for i in 0..room.len() {
+        // Bins with the same room left are interchangeable
+        if room[i] < sizes[x] || room[..i].contains(&room[i]) {
+            continue;
+        }
+        room[i] -= sizes[x];
+        bins[x] = i;
+        if pack_from(sizes, rest, room, bins) {
+            return true;
+        }
+        room[i] += sizes[x];
+    }
+    false
+}
+
+/// Premade groups of players, which queue together and play on the same team.
+#[derive(Debug, Clone, Default)]
+pub struct Parties {
+    /// Parties by leader.
+    parties: HashMap<UserId, Party>,
+    /// Leader of the party of every player in a party.
+    leaders: HashMap<UserId, UserId>,
+}
+
+impl Parties {
+    /// Restores a party, the leader first.
+    pub fn insert(&mut self, members: Vec<UserId>, invites: HashSet<UserId>) {
+        let leader = members[0];
+        for &member in members.iter() {
+            self.leaders.insert(member, leader);
+        }
+        self.parties.insert(leader, Party { members, invites });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Party> {
+        self.parties.values()
+    }
+
+    pub fn get(&self, user_id: UserId) -> Option<&Party> {
+        self.leaders
+            .get(&user_id)
+            .and_then(|leader| self.parties.get(leader))
+    }
+
+    /// Invites a player into the party of `leader`, creating it if needed.
+    pub fn invite(&mut self, leader: UserId, user_id: UserId) -> Result<(), PartyError> {
+        if self.leaders.contains_key(&user_id) {
+            return Err(PartyError::AlreadyInParty(user_id));
+        }
+        match self.leaders.get(&leader) {
+            Some(&x) if x != leader => return Err(PartyError::NotLeader(leader)),
+            Some(_) => (),
+            None => {
+                self.leaders.insert(leader, leader);
+                self.parties.insert(
+                    leader,
+                    Party {
+                        members: vec![leader],
+                        invites: HashSet::new(),
+                    },
+                );
+            }
+        }
+        self.parties
+            .get_mut(&leader)
+            .unwrap()
+            .invites
+            .insert(user_id);
+        Ok(())
+    }
+
+    pub fn accept(&mut self, user_id: UserId, leader: UserId) -> Result<(), PartyError> {
+        if self.leaders.contains_key(&user_id) {
+            return Err(PartyError::AlreadyInParty(user_id));
+        }
+        let party = self
+            .parties
+            .get_mut(&leader)
+            .ok_or(PartyError::NotInParty(leader))?;
+        if !party.invites.remove(&user_id) {
+            return Err(PartyError::NotInvited(user_id));
+        }
+        party.members.push(user_id);
+        self.leaders.insert(user_id, leader);
+        Ok(())
+    }
+
+    /// Removes a player from their party. The next member becomes the leader if
+    /// the leader leaves, and a party left with a single member is disbanded.
+    pub fn leave(&mut self, user_id: UserId) -> Result<(), PartyError> {
+        let leader = self
+            .leaders
+            .remove(&user_id)
+            .ok_or(PartyError::NotInParty(user_id))?;
+        let mut party = self.parties.remove(&leader).unwrap();
+        party.members.retain(|&x| x != user_id);
+        if party.members.len() < 2 {
+            for member in party.members {
+                self.leaders.remove(&member);
+            }
+            return Ok(());
+        }
+        let leader = party.leader();
+        for &member in party.members.iter() {
+            self.leaders.insert(member, leader);
+        }
+        self.parties.insert(leader, party);
+        Ok(())
+    }
+
+    /// Removes the party of `leader` and returns its members.
+    pub fn disband(&mut self, leader: UserId) -> Result<Vec<UserId>, PartyError> {
+        match self.leaders.get(&leader) {
+            Some(&x) if x == leader => (),
+            Some(_) => return Err(PartyError::NotLeader(leader)),
+            None => return Err(PartyError::NotInParty(leader)),
+        }
+        let party = self.parties.remove(&leader).unwrap();
+        for member in party.members.iter() {
+            self.leaders.remove(member);
+        }
+        Ok(party.members)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_fits() {
+        let bins = pack(&[2, 3, 1, 2], &[4, 4]).unwrap();
+        let mut room = [4, 4];
+        for (&size, &bin) in [2, 3, 1, 2].iter().zip(bins.iter()) {
+            room[bin] -= size;
+        }
+        assert_eq!(room, [0, 0]);
+    }
+
+    #[test]
+    fn pack_does_not_fit() {
+        assert_eq!(pack(&[3, 3], &[4, 2]), None);
+        assert_eq!(pack(&[5], &[4, 4]), None);
+    }
+
+    #[test]
+    fn leave_passes_leadership() {
+        let (a, b, c) = (UserId(1), UserId(2), UserId(3));
+        let mut parties = Parties::default();
+        parties.insert(vec![a, b, c], HashSet::new());
+        parties.leave(a).unwrap();
+        assert_eq!(parties.get(c).unwrap().leader(), b);
+        parties.leave(b).unwrap();
+        assert!(parties.get(c).is_none());
+    }
+}
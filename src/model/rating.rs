@@ -2,92 +2,278 @@ use std::collections::{BTreeMap, HashMap};
 use std::ops::{Deref, DerefMut};
 
 use harmony::model::id::UserId;
-use trueskill::{self, Rating, SimpleTrueSkill as TrueSkill};
+use serde::Deserialize;
 
-use super::{Game, Score};
+use super::Game;
 
-#[derive(Debug, Clone, Default)]
-pub struct Ratings(HashMap<UserId, PlayerInfo>);
+mod elo;
+mod glicko2;
+mod system;
+mod trueskill;
+
+pub use self::elo::Elo;
+pub use self::glicko2::Glicko2;
+pub use self::system::{Outcome, RatingSystem, System};
+pub use self::trueskill::TrueSkill;
+
+/// Number of decided games between two rating snapshots.
+const SNAPSHOT_INTERVAL: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rating {
+    mean: f64,
+    variance: f64,
+    volatility: f64,
+}
+
+impl Rating {
+    pub fn new(mean: f64, variance: f64) -> Self {
+        Self::with_volatility(mean, variance, 0.0)
+    }
+
+    pub fn with_volatility(mean: f64, variance: f64, volatility: f64) -> Self {
+        Self {
+            mean,
+            variance,
+            volatility,
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn variance(&self) -> f64 {
+        self.variance
+    }
+
+    /// Volatility of the rating, only used by Glicko-2.
+    pub fn volatility(&self) -> f64 {
+        self.volatility
+    }
+}
+
+/// Ratings of the players of a decided game, before and after it was applied.
+#[derive(Debug, Clone)]
+pub struct GameRatings {
+    pub game: usize,
+    pub quality: f64,
+    /// Probability that the first team wins, only known for two teams.
+    pub win_probability: Option<f64>,
+    pub players: Vec<(UserId, Rating, Rating)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ratings {
+    system: System,
+    ratings: HashMap<UserId, PlayerInfo>,
+    /// Ratings as they were just before the game with the given id.
+    snapshots: BTreeMap<usize, HashMap<UserId, PlayerInfo>>,
+    /// Id of the first game of the season.
+    start: usize,
+    /// Id of the last decided game that has been applied.
+    last: usize,
+    since_snapshot: usize,
+}
 
 impl Ratings {
-    pub fn from_games(
-        games: &BTreeMap<usize, Game>,
+    /// Creates the ratings of a season starting at the game `start`. Seeds
+    /// from the previous season take precedence over initial ratings.
+    pub fn new(
+        start: usize,
         initial: &HashMap<UserId, f64>,
-        trueskill: TrueSkill,
+        seeds: &HashMap<UserId, Rating>,
+        system: System,
     ) -> Self {
-        let mut ratings = HashMap::new();
-        for (user_id, rating) in initial.iter() {
-            ratings.insert(
-                *user_id,
-                PlayerInfo::new(Rating::new(*rating, trueskill.sigma().powi(2))),
-            );
+        let ratings = initial
+            .iter()
+            .map(|(&user_id, &rating)| (user_id, PlayerInfo::new(system.initial_rating(rating))))
+            .chain(seeds.iter().map(|(&user_id, rating)| {
+                (
+                    user_id,
+                    PlayerInfo::new(system.rating(rating.mean(), rating.variance())),
+                )
+            }))
+            .collect::<HashMap<_, _>>();
+        let mut snapshots = BTreeMap::new();
+        snapshots.insert(start, ratings.clone());
+        Self {
+            system,
+            ratings,
+            snapshots,
+            start,
+            last: start.saturating_sub(1),
+            since_snapshot: 0,
         }
-        let default_rating = trueskill.create_rating();
+    }
+
+    pub fn system(&self) -> System {
+        self.system
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Returns true if the game comes after every game already applied, in
+    /// which case its result can be applied without a replay.
+    pub fn is_latest(&self, game_id: usize) -> bool {
+        game_id > self.last
+    }
+
+    /// Returns the id of the game from which a replay must start when the
+    /// game with the given id changes.
+    pub fn replay_start(&self, game_id: usize) -> usize {
+        self.snapshots
+            .range(..=game_id.max(self.start))
+            .next_back()
+            .map(|(&id, _)| id)
+            .unwrap_or_default()
+    }
+
+    /// Restores the snapshot taken before the game `from`, which must have
+    /// been returned by `replay_start`, and applies `games` on top of it.
+    pub fn replay(&mut self, from: usize, games: &BTreeMap<usize, Game>) -> Vec<GameRatings> {
+        self.snapshots.split_off(&(from + 1));
+        self.ratings = self.snapshots.get(&from).cloned().unwrap_or_default();
+        self.last = from.saturating_sub(1);
+        self.since_snapshot = 0;
+        games
+            .range(from..)
+            .filter_map(|(_, game)| self.apply(game))
+            .collect()
+    }
+
+    /// Changes the initial rating of a player. The snapshots taken before the
+    /// player's first game are updated, the later ones are dropped, so a
+    /// replay from `first_game` is needed afterwards.
+    pub fn set_initial(&mut self, user_id: UserId, rating: f64, first_game: Option<usize>) {
+        let info = PlayerInfo::new(self.system.initial_rating(rating));
+        match first_game {
+            Some(first_game) => {
+                self.snapshots.split_off(&(first_game + 1));
+            }
+            None => {
+                self.ratings.insert(user_id, info);
+            }
+        }
+        for snapshot in self.snapshots.values_mut() {
+            snapshot.insert(user_id, info);
+        }
+    }
+
+    /// Applies the result of a single game. Games must be applied in order,
+    /// an earlier game changing requires a `replay`.
+    pub fn apply(&mut self, game: &Game) -> Option<GameRatings> {
+        if game.id() < self.start {
+            return None;
+        }
+        let places = game.places()?;
+        if self.since_snapshot >= SNAPSHOT_INTERVAL {
+            self.snapshots.insert(game.id(), self.ratings.clone());
+            self.since_snapshot = 0;
+        }
+        self.since_snapshot += 1;
+        self.last = game.id();
+        let system = self.system;
+        let ratings = &mut self.ratings;
+        let default_rating = system.create_rating();
         let default_info = PlayerInfo::new(default_rating);
-        for game in games.values() {
-            let score = match game.score() {
-                Score::Undecided | Score::Cancelled => continue,
-                Score::Team1 => trueskill::Score::Win,
-                Score::Team2 => trueskill::Score::Loss,
-                Score::Draw => trueskill::Score::Draw,
-            };
-            let teams = game.teams();
-            let mut team1_ratings = teams[0]
-                .iter()
-                .map(|&x| {
-                    ratings
-                        .get(&x)
-                        .map(|y: &PlayerInfo| y.rating)
-                        .unwrap_or_else(|| default_rating)
-                })
-                .collect::<Vec<_>>();
-            let mut team2_ratings = teams[1]
-                .iter()
-                .map(|&x| {
-                    ratings.get(&x).map(|x| x.rating).unwrap_or_else(|| {
-                        if let Some(&rating) = initial.get(&x) {
-                            Rating::new(rating, default_rating.variance())
-                        } else {
-                            default_rating
-                        }
+        let teams = game.teams();
+        let team_ratings = teams
+            .iter()
+            .map(|team| {
+                team.iter()
+                    .map(|&x| {
+                        ratings
+                            .get(&x)
+                            .map(|y: &PlayerInfo| y.rating)
+                            .unwrap_or_else(|| default_rating)
                     })
-                })
-                .collect::<Vec<_>>();
-            trueskill.update(&mut team1_ratings, &mut team2_ratings, score);
-            for (i, &user_id) in teams[0].iter().enumerate() {
-                let player_info = ratings.entry(user_id).or_insert(default_info);
-                match score {
-                    trueskill::Score::Win => player_info.wins += 1,
-                    trueskill::Score::Loss => player_info.losses += 1,
-                    trueskill::Score::Draw => player_info.draws += 1,
-                };
-                player_info.rating = team1_ratings[i];
-            }
-            for (i, &user_id) in teams[1].iter().enumerate() {
-                let player_info = ratings.entry(user_id).or_insert(default_info);
-                match score {
-                    trueskill::Score::Win => player_info.losses += 1,
-                    trueskill::Score::Loss => player_info.wins += 1,
-                    trueskill::Score::Draw => player_info.draws += 1,
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let quality = system.teams_quality(&team_ratings);
+        let win_probability = match &team_ratings[..] {
+            [team1, team2] => Some(system.win_probability(team1, team2)),
+            _ => None,
+        };
+        let mut new_ratings = team_ratings.clone();
+        system.update_ranked(&mut new_ratings, &places);
+        // Only a team finishing first on its own wins
+        let first = places.iter().filter(|&&x| x == 0).count();
+        let players = game
+            .play_shares()
+            .into_iter()
+            .filter_map(|(user_id, slot, share)| {
+                let (i, j) = teams
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, team)| Some((i, team.iter().position(|&x| x == slot)?)))?;
+                let (before, after) = if user_id == slot {
+                    (team_ratings[i][j], new_ratings[i][j])
+                } else {
+                    // Substituted players are rated as if they had played in
+                    // the place of the player who ended the game
+                    let before = ratings
+                        .get(&user_id)
+                        .map(|x| x.rating)
+                        .unwrap_or(default_rating);
+                    let mut alternative = team_ratings.clone();
+                    alternative[i][j] = before;
+                    system.update_ranked(&mut alternative, &places);
+                    (before, alternative[i][j])
                 };
-                player_info.rating = team2_ratings[i];
-            }
+                Some((user_id, before, blend(before, after, share), places[i]))
+            })
+            .collect::<Vec<_>>();
+        for &(user_id, _, after, place) in players.iter() {
+            let player_info = ratings.entry(user_id).or_insert(default_info);
+            match (place, first) {
+                (0, 1) => player_info.wins += 1,
+                (0, _) => player_info.draws += 1,
+                _ => player_info.losses += 1,
+            };
+            player_info.rating = after;
         }
-        Self(ratings)
+        let players = players
+            .into_iter()
+            .map(|(user_id, before, after, _)| (user_id, before, after))
+            .collect();
+        Some(GameRatings {
+            game: game.id(),
+            quality,
+            win_probability,
+            players,
+        })
     }
 }
 
+/// Moves a rating by `share` of the way to its update, for players who only
+/// played part of a game.
+fn blend(before: Rating, after: Rating, share: f64) -> Rating {
+    if share >= 1.0 {
+        return after;
+    }
+    let f = |before: f64, after: f64| before + share * (after - before);
+    Rating::with_volatility(
+        f(before.mean, after.mean),
+        f(before.variance, after.variance),
+        f(before.volatility, after.volatility),
+    )
+}
+
 impl Deref for Ratings {
     type Target = HashMap<UserId, PlayerInfo>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.ratings
     }
 }
 
 impl DerefMut for Ratings {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.ratings
     }
 }
 
@@ -108,4 +294,54 @@ impl PlayerInfo {
             draws: 0,
         }
     }
+
+    pub fn games(&self) -> usize {
+        self.wins + self.losses + self.draws
+    }
+}
+
+/// How players are ordered on the leaderboard of a lobby.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct LeaderboardOptions {
+    /// Number of standard deviations subtracted from the mean to rank players.
+    pub conservative: f64,
+    /// Number of games needed to be ranked, other players are provisional.
+    pub min_games: usize,
+}
+
+impl LeaderboardOptions {
+    pub fn score(&self, rating: &Rating) -> f64 {
+        rating.mean() - self.conservative * rating.variance().sqrt()
+    }
+
+    pub fn is_provisional(&self, player_info: &PlayerInfo) -> bool {
+        player_info.games() < self.min_games
+    }
+}
+
+/// How the teams of a lobby are balanced.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Balance {
+    /// Minimizes the difference between the summed means of the teams.
+    #[default]
+    Mean,
+    /// Maximizes the match quality of the rating system.
+    Quality,
+    /// Minimizes `weight * (1 - quality) + (1 - weight) * difference`, the
+    /// mean difference being relative to the worst split.
+    Mixed { weight: f64 },
+}
+
+/// Penalty for splits that keep the teammates of the last games together.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct Variety {
+    /// Previous games whose teammates are looked at, 0 to disable.
+    pub games: usize,
+    /// Weight of the penalty against the balance of the teams. At 1, keeping
+    /// every pair of previous teammates together costs as much as the worst
+    /// balance.
+    pub weight: f64,
 }
@@ -0,0 +1,58 @@
+use serde::Deserialize;
+
+use super::{Outcome, Rating, RatingSystem};
+
+/// Team Elo: a team is rated by the average of its players and every player
+/// of a team gains or loses the same amount.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Elo {
+    rating: f64,
+    k: f64,
+    scale: f64,
+}
+
+impl Default for Elo {
+    fn default() -> Self {
+        Self {
+            rating: 1500.0,
+            k: 32.0,
+            scale: 400.0,
+        }
+    }
+}
+
+impl Elo {
+    fn expected(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        let delta = average(team2) - average(team1);
+        1.0 / (1.0 + 10f64.powf(delta / self.scale))
+    }
+}
+
+impl RatingSystem for Elo {
+    fn create_rating(&self) -> Rating {
+        Rating::new(self.rating, 0.0)
+    }
+
+    fn update(&self, team1: &mut [Rating], team2: &mut [Rating], outcome: Outcome) {
+        let delta = self.k * (outcome.score() - self.expected(team1, team2));
+        for rating in team1.iter_mut() {
+            *rating = Rating::new(rating.mean() + delta, rating.variance());
+        }
+        for rating in team2.iter_mut() {
+            *rating = Rating::new(rating.mean() - delta, rating.variance());
+        }
+    }
+
+    fn win_probability(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        self.expected(team1, team2)
+    }
+
+    fn quality(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        1.0 - (2.0 * self.expected(team1, team2) - 1.0).abs()
+    }
+}
+
+fn average(team: &[Rating]) -> f64 {
+    team.iter().map(|x| x.mean()).sum::<f64>() / team.len() as f64
+}
@@ -0,0 +1,139 @@
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+use super::{Outcome, Rating, RatingSystem};
+
+/// Convergence tolerance of the volatility iteration.
+const EPSILON: f64 = 0.000001;
+
+/// Glicko-2, where every player is rated against a composite opponent made of
+/// the average mean and deviation of the other team, each game being its own
+/// rating period.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Glicko2 {
+    rating: f64,
+    deviation: f64,
+    volatility: f64,
+    tau: f64,
+    scale: f64,
+}
+
+impl Default for Glicko2 {
+    fn default() -> Self {
+        Self {
+            rating: 1500.0,
+            deviation: 350.0,
+            volatility: 0.06,
+            tau: 0.5,
+            scale: 173.7178,
+        }
+    }
+}
+
+impl Glicko2 {
+    /// Mean and deviation of a team on the Glicko-2 scale.
+    fn composite(&self, team: &[Rating]) -> (f64, f64) {
+        let len = team.len() as f64;
+        let mu = team
+            .iter()
+            .map(|x| (x.mean() - self.rating) / self.scale)
+            .sum::<f64>()
+            / len;
+        let phi = (team
+            .iter()
+            .map(|x| x.variance() / self.scale.powi(2))
+            .sum::<f64>()
+            / len)
+            .sqrt();
+        (mu, phi)
+    }
+
+    fn update_player(&self, rating: Rating, opponent: (f64, f64), score: f64) -> Rating {
+        let mu = (rating.mean() - self.rating) / self.scale;
+        let phi = rating.variance().sqrt() / self.scale;
+        let sigma = rating.volatility();
+        let (mu_j, phi_j) = opponent;
+        let g = g(phi_j);
+        let e = 1.0 / (1.0 + (-g * (mu - mu_j)).exp());
+        let v = 1.0 / (g.powi(2) * e * (1.0 - e));
+        let delta = v * g * (score - e);
+        let alpha = sigma.powi(2).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            ex * (delta.powi(2) - phi.powi(2) - v - ex) / (2.0 * (phi.powi(2) + v + ex).powi(2))
+                - (x - alpha) / self.tau.powi(2)
+        };
+        let mut x_a = alpha;
+        let mut x_b = if delta.powi(2) > phi.powi(2) + v {
+            (delta.powi(2) - phi.powi(2) - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(alpha - k * self.tau) < 0.0 {
+                k += 1.0;
+            }
+            alpha - k * self.tau
+        };
+        let mut f_a = f(x_a);
+        let mut f_b = f(x_b);
+        while (x_b - x_a).abs() > EPSILON {
+            let x_c = x_a + (x_a - x_b) * f_a / (f_b - f_a);
+            let f_c = f(x_c);
+            if f_c * f_b <= 0.0 {
+                x_a = x_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            x_b = x_c;
+            f_b = f_c;
+        }
+        let sigma = (x_a / 2.0).exp();
+        let phi_star = (phi.powi(2) + sigma.powi(2)).sqrt();
+        let phi = 1.0 / (1.0 / phi_star.powi(2) + 1.0 / v).sqrt();
+        let mu = mu + phi.powi(2) * g * (score - e);
+        Rating::with_volatility(
+            self.rating + self.scale * mu,
+            (self.scale * phi).powi(2),
+            sigma,
+        )
+    }
+}
+
+impl RatingSystem for Glicko2 {
+    fn create_rating(&self) -> Rating {
+        Rating::with_volatility(self.rating, self.deviation.powi(2), self.volatility)
+    }
+
+    fn rating(&self, mean: f64, variance: f64) -> Rating {
+        Rating::with_volatility(mean, variance, self.volatility)
+    }
+
+    fn update(&self, team1: &mut [Rating], team2: &mut [Rating], outcome: Outcome) {
+        let composite1 = self.composite(team1);
+        let composite2 = self.composite(team2);
+        let score = outcome.score();
+        for rating in team1.iter_mut() {
+            *rating = self.update_player(*rating, composite2, score);
+        }
+        for rating in team2.iter_mut() {
+            *rating = self.update_player(*rating, composite1, 1.0 - score);
+        }
+    }
+
+    fn win_probability(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        let (mu1, phi1) = self.composite(team1);
+        let (mu2, phi2) = self.composite(team2);
+        let g = g((phi1.powi(2) + phi2.powi(2)).sqrt());
+        1.0 / (1.0 + (-g * (mu1 - mu2)).exp())
+    }
+
+    fn quality(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        1.0 - (2.0 * self.win_probability(team1, team2) - 1.0).abs()
+    }
+}
+
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi.powi(2) / PI.powi(2)).sqrt()
+}
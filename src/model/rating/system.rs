@@ -0,0 +1,204 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use super::{Elo, Glicko2, Rating, TrueSkill};
+
+/// Result of a game from the point of view of the first team.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl Outcome {
+    /// Score of the first team: 1 for a win, 0 for a loss and 0.5 for a draw.
+    pub fn score(self) -> f64 {
+        match self {
+            Self::Win => 1.0,
+            Self::Loss => 0.0,
+            Self::Draw => 0.5,
+        }
+    }
+}
+
+pub trait RatingSystem {
+    /// Rating of a player who has not played yet.
+    fn create_rating(&self) -> Rating;
+
+    /// Rating of a player with a known mean and variance, any other state
+    /// being reset to its default.
+    fn rating(&self, mean: f64, variance: f64) -> Rating {
+        Rating::new(mean, variance)
+    }
+
+    /// Rating of a player whose initial mean was set by an admin.
+    fn initial_rating(&self, mean: f64) -> Rating {
+        self.rating(mean, self.create_rating().variance())
+    }
+
+    fn update(&self, team1: &mut [Rating], team2: &mut [Rating], outcome: Outcome);
+
+    /// Probability that the first team wins against the second one.
+    fn win_probability(&self, team1: &[Rating], team2: &[Rating]) -> f64;
+
+    /// Match quality between 0 and 1, higher meaning a more even game.
+    fn quality(&self, team1: &[Rating], team2: &[Rating]) -> f64;
+
+    /// Updates the ratings of any number of teams from their finishing
+    /// places, 0 being first. Every pair of teams is rated as a game between
+    /// them from the ratings before the game, and the changes of every team
+    /// are averaged over its opponents.
+    fn update_ranked(&self, teams: &mut [Vec<Rating>], places: &[usize]) {
+        if teams.len() < 2 {
+            return;
+        }
+        let before = teams.to_vec();
+        let mut changes = teams
+            .iter()
+            .map(|team| vec![[0.0; 3]; team.len()])
+            .collect::<Vec<_>>();
+        for i in 0..teams.len() {
+            for j in i + 1..teams.len() {
+                let outcome = match places[i].cmp(&places[j]) {
+                    Ordering::Less => Outcome::Win,
+                    Ordering::Greater => Outcome::Loss,
+                    Ordering::Equal => Outcome::Draw,
+                };
+                let mut team1 = before[i].clone();
+                let mut team2 = before[j].clone();
+                self.update(&mut team1, &mut team2, outcome);
+                for (k, team) in [(i, team1), (j, team2)] {
+                    for ((change, old), new) in changes[k].iter_mut().zip(&before[k]).zip(team) {
+                        change[0] += new.mean() - old.mean();
+                        change[1] += new.variance() - old.variance();
+                        change[2] += new.volatility() - old.volatility();
+                    }
+                }
+            }
+        }
+        let opponents = (teams.len() - 1) as f64;
+        for (team, changes) in teams.iter_mut().zip(changes) {
+            for (rating, change) in team.iter_mut().zip(changes) {
+                *rating = Rating::with_volatility(
+                    rating.mean() + change[0] / opponents,
+                    rating.variance() + change[1] / opponents,
+                    rating.volatility() + change[2] / opponents,
+                );
+            }
+        }
+    }
+
+    /// Match quality of any number of teams, averaged over every pair.
+    fn teams_quality(&self, teams: &[Vec<Rating>]) -> f64 {
+        let mut sum = 0.0;
+        let mut pairs = 0;
+        for (i, team1) in teams.iter().enumerate() {
+            for team2 in teams[i + 1..].iter() {
+                sum += self.quality(team1, team2);
+                pairs += 1;
+            }
+        }
+        if pairs == 0 {
+            return 1.0;
+        }
+        sum / pairs as f64
+    }
+}
+
+/// Rating system of a lobby.
+#[derive(Debug, Clone, Copy)]
+pub enum System {
+    TrueSkill(TrueSkill),
+    Glicko2(Glicko2),
+    Elo(Elo),
+}
+
+impl RatingSystem for System {
+    fn create_rating(&self) -> Rating {
+        match self {
+            Self::TrueSkill(system) => system.create_rating(),
+            Self::Glicko2(system) => system.create_rating(),
+            Self::Elo(system) => system.create_rating(),
+        }
+    }
+
+    fn rating(&self, mean: f64, variance: f64) -> Rating {
+        match self {
+            Self::TrueSkill(system) => system.rating(mean, variance),
+            Self::Glicko2(system) => system.rating(mean, variance),
+            Self::Elo(system) => system.rating(mean, variance),
+        }
+    }
+
+    fn update(&self, team1: &mut [Rating], team2: &mut [Rating], outcome: Outcome) {
+        match self {
+            Self::TrueSkill(system) => system.update(team1, team2, outcome),
+            Self::Glicko2(system) => system.update(team1, team2, outcome),
+            Self::Elo(system) => system.update(team1, team2, outcome),
+        }
+    }
+
+    fn win_probability(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        match self {
+            Self::TrueSkill(system) => system.win_probability(team1, team2),
+            Self::Glicko2(system) => system.win_probability(team1, team2),
+            Self::Elo(system) => system.win_probability(team1, team2),
+        }
+    }
+
+    fn quality(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        match self {
+            Self::TrueSkill(system) => system.quality(team1, team2),
+            Self::Glicko2(system) => system.quality(team1, team2),
+            Self::Elo(system) => system.quality(team1, team2),
+        }
+    }
+}
+
+impl fmt::Display for System {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TrueSkill(_) => "TrueSkill".fmt(f),
+            Self::Glicko2(_) => "Glicko-2".fmt(f),
+            Self::Elo(_) => "Elo".fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_ranked_two_teams() {
+        let system = System::Elo(Elo::default());
+        let (a, b) = (Rating::new(1500.0, 0.0), Rating::new(1600.0, 0.0));
+        let mut teams = vec![vec![a], vec![b]];
+        system.update_ranked(&mut teams, &[1, 0]);
+        let (mut team1, mut team2) = (vec![a], vec![b]);
+        system.update(&mut team1, &mut team2, Outcome::Loss);
+        assert_eq!(teams, vec![team1, team2]);
+    }
+
+    #[test]
+    fn update_ranked_ignores_order() {
+        let system = System::Elo(Elo::default());
+        let ratings = [1500.0, 1600.0, 1400.0].map(|x| vec![Rating::new(x, 0.0)]);
+        let mut teams = ratings.to_vec();
+        system.update_ranked(&mut teams, &[1, 2, 0]);
+        let mut reversed = ratings.iter().rev().cloned().collect::<Vec<_>>();
+        system.update_ranked(&mut reversed, &[0, 2, 1]);
+        reversed.reverse();
+        for (a, b) in teams.iter().zip(reversed.iter()) {
+            assert!((a[0].mean() - b[0].mean()).abs() < 1e-9);
+        }
+        // The middle team is rated once, not twice
+        let (mut first, mut middle) = (ratings[2].clone(), ratings[0].clone());
+        system.update(&mut first, &mut middle, Outcome::Win);
+        let (mut middle2, mut last) = (ratings[0].clone(), ratings[1].clone());
+        system.update(&mut middle2, &mut last, Outcome::Win);
+        let change = (middle[0].mean() + middle2[0].mean()) / 2.0 - 1500.0;
+        assert!((teams[0][0].mean() - 1500.0 - change).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+use serde::{de, Deserialize, Deserializer};
+use trueskill::SimpleTrueSkill;
+
+use super::{Outcome, Rating, RatingSystem};
+
+/// TrueSkill environment from the configuration. `SimpleTrueSkill` does not
+/// expose `beta`, which is needed to compute win probabilities.
+#[derive(Clone, Copy)]
+pub struct TrueSkill {
+    system: SimpleTrueSkill,
+    beta: f64,
+}
+
+impl RatingSystem for TrueSkill {
+    fn create_rating(&self) -> Rating {
+        let rating = self.system.create_rating();
+        Rating::new(rating.mean(), rating.variance())
+    }
+
+    fn update(&self, team1: &mut [Rating], team2: &mut [Rating], outcome: Outcome) {
+        let mut ratings1 = convert(team1);
+        let mut ratings2 = convert(team2);
+        let score = match outcome {
+            Outcome::Win => trueskill::Score::Win,
+            Outcome::Loss => trueskill::Score::Loss,
+            Outcome::Draw => trueskill::Score::Draw,
+        };
+        self.system.update(&mut ratings1, &mut ratings2, score);
+        for (rating, new) in team1
+            .iter_mut()
+            .zip(ratings1)
+            .chain(team2.iter_mut().zip(ratings2))
+        {
+            *rating = Rating::new(new.mean(), new.variance());
+        }
+    }
+
+    fn win_probability(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        let mean = team1.iter().map(|x| x.mean()).sum::<f64>()
+            - team2.iter().map(|x| x.mean()).sum::<f64>();
+        let variance = team1
+            .iter()
+            .chain(team2.iter())
+            .map(|x| x.variance() + self.beta.powi(2))
+            .sum::<f64>();
+        normal_cdf(mean / variance.sqrt())
+    }
+
+    fn quality(&self, team1: &[Rating], team2: &[Rating]) -> f64 {
+        self.system.quality(&convert(team1), &convert(team2))
+    }
+}
+
+impl fmt::Debug for TrueSkill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrueSkill")
+            .field("beta", &self.beta)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'de> Deserialize<'de> for TrueSkill {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let beta = value
+            .get("beta")
+            .and_then(|x| x.as_f64())
+            .ok_or_else(|| de::Error::missing_field("beta"))?;
+        let system = SimpleTrueSkill::deserialize(value).map_err(de::Error::custom)?;
+        Ok(Self { system, beta })
+    }
+}
+
+fn convert(team: &[Rating]) -> Vec<trueskill::Rating> {
+    team.iter()
+        .map(|x| trueskill::Rating::new(x.mean(), x.variance()))
+        .collect()
+}
+
+/// Standard normal cumulative distribution function, using the complementary
+/// error function approximation from Numerical Recipes.
+fn normal_cdf(x: f64) -> f64 {
+    let z = x.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let erfc = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        1.0 - 0.5 * erfc
+    } else {
+        0.5 * erfc
+    }
+}
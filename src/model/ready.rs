@@ -0,0 +1,105 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use harmony::model::id::{GuildId, UserId};
+use serde::Deserialize;
+
+use super::QueueUser;
+
+/// Ready check settings of a lobby.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ReadyOptions {
+    /// Seconds the players have to confirm.
+    #[serde(default = "default_time")]
+    pub time: u64,
+    /// Minutes during which the players who did not confirm cannot queue
+    /// again, 0 for no penalty.
+    #[serde(default)]
+    pub penalty: u64,
+}
+
+fn default_time() -> u64 {
+    60
+}
+
+/// Players of a full queue confirming that they are ready to play.
+#[derive(Debug, Clone)]
+pub struct ReadyCheck {
+    guild_id: GuildId,
+    players: HashMap<UserId, QueueUser>,
+    confirmed: HashSet<UserId>,
+    deadline: DateTime<Utc>,
+}
+
+impl ReadyCheck {
+    pub fn new(
+        guild_id: GuildId,
+        players: HashMap<UserId, QueueUser>,
+        deadline: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            guild_id,
+            players,
+            confirmed: HashSet::new(),
+            deadline,
+        }
+    }
+
+    /// Restores a ready check saved in the database.
+    pub fn restore(
+        guild_id: GuildId,
+        players: HashMap<UserId, QueueUser>,
+        confirmed: HashSet<UserId>,
+        deadline: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            guild_id,
+            players,
+            confirmed,
+            deadline,
+        }
+    }
+
+    pub fn guild_id(&self) -> GuildId {
+        self.guild_id
+    }
+
+    pub fn players(&self) -> &HashMap<UserId, QueueUser> {
+        &self.players
+    }
+
+    pub fn deadline(&self) -> DateTime<Utc> {
+        self.deadline
+    }
+
+    pub fn contains(&self, user_id: UserId) -> bool {
+        self.players.contains_key(&user_id)
+    }
+
+    /// Confirms a player, returns false if they already were.
+    pub fn confirm(&mut self, user_id: UserId) -> bool {
+        self.confirmed.insert(user_id)
+    }
+
+    pub fn is_confirmed(&self, user_id: UserId) -> bool {
+        self.confirmed.contains(&user_id)
+    }
+
+    pub fn confirmed(&self) -> usize {
+        self.confirmed.len()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.confirmed.len() == self.players.len()
+    }
+
+    /// Splits the players into those who confirmed and those who did not.
+    pub fn into_players(self) -> (HashMap<UserId, QueueUser>, Vec<UserId>) {
+        let confirmed = self.confirmed;
+        let (ready, pending): (HashMap<_, _>, HashMap<_, _>) = self
+            .players
+            .into_iter()
+            .partition(|(x, _)| confirmed.contains(x));
+        (ready, pending.into_keys().collect())
+    }
+}
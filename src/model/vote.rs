@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use harmony::model::id::{GuildId, UserId};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+/// Map vote settings of a lobby.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MapVoteOptions {
+    /// Maps, or map and mode combinations, the games can be played on.
+    pub pool: Vec<String>,
+    /// Seconds the players have to vote.
+    #[serde(default = "default_time")]
+    pub time: u64,
+    /// Number of maps drawn from the pool for each vote, 0 for all of them.
+    #[serde(default)]
+    pub choices: usize,
+    /// Number of previous games whose maps cannot be picked again.
+    #[serde(default)]
+    pub recent: usize,
+}
+
+fn default_time() -> u64 {
+    30
+}
+
+/// Players of a game which just started voting for its map.
+#[derive(Debug, Clone)]
+pub struct MapVote {
+    guild_id: GuildId,
+    game_id: usize,
+    players: Vec<UserId>,
+    choices: Vec<String>,
+    votes: HashMap<UserId, usize>,
+    deadline: DateTime<Utc>,
+}
+
+impl MapVote {
+    pub fn new(
+        guild_id: GuildId,
+        game_id: usize,
+        players: Vec<UserId>,
+        choices: Vec<String>,
+        deadline: DateTime<Utc>,
+    ) -> Self {
+        Self::restore(
+            guild_id,
+            game_id,
+            players,
+            choices,
+            HashMap::new(),
+            deadline,
+        )
+    }
+
+    /// Restores a map vote saved in the database.
+    pub fn restore(
+        guild_id: GuildId,
+        game_id: usize,
+        players: Vec<UserId>,
+        choices: Vec<String>,
+        votes: HashMap<UserId, usize>,
+        deadline: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            guild_id,
+            game_id,
+            players,
+            choices,
+            votes,
+            deadline,
+        }
+    }
+
+    pub fn guild_id(&self) -> GuildId {
+        self.guild_id
+    }
+
+    /// Id of the game whose map is voted.
+    pub fn game_id(&self) -> usize {
+        self.game_id
+    }
+
+    pub fn choices(&self) -> &[String] {
+        &self.choices
+    }
+
+    pub fn deadline(&self) -> DateTime<Utc> {
+        self.deadline
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = UserId> + '_ {
+        self.players.iter().copied()
+    }
+
+    pub fn contains(&self, user_id: UserId) -> bool {
+        self.players().any(|x| x == user_id)
+    }
+
+    /// Finds a choice from its number, starting at 1, or its name.
+    pub fn parse(&self, arg: &str) -> Option<usize> {
+        match arg.parse::<usize>() {
+            Ok(n) => n.checked_sub(1).filter(|&i| i < self.choices.len()),
+            Err(_) => self
+                .choices
+                .iter()
+                .position(|x| x.eq_ignore_ascii_case(arg)),
+        }
+    }
+
+    /// Records the vote of a player, replacing their previous one.
+    pub fn vote(&mut self, user_id: UserId, choice: usize) {
+        self.votes.insert(user_id, choice);
+    }
+
+    pub fn votes(&self) -> usize {
+        self.votes.len()
+    }
+
+    /// Choice of every player who voted.
+    pub fn ballots(&self) -> &HashMap<UserId, usize> {
+        &self.votes
+    }
+
+    /// Number of votes of every choice.
+    pub fn tally(&self) -> Vec<usize> {
+        let mut tally = vec![0; self.choices.len()];
+        for &choice in self.votes.values() {
+            tally[choice] += 1;
+        }
+        tally
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.votes.len() == self.players().count()
+    }
+
+    /// Returns the choice with the most votes, ties being broken at random.
+    pub fn into_result(mut self) -> String {
+        let tally = self.tally();
+        let max = tally.iter().copied().max().unwrap_or_default();
+        let best = (0..tally.len())
+            .filter(|&i| tally[i] == max)
+            .collect::<Vec<_>>();
+        let &i = best.choose(&mut rand::thread_rng()).unwrap();
+        self.choices.swap_remove(i)
+    }
+}
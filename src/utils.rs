@@ -1,5 +1,5 @@
 mod leaderboard;
 mod matchmaking;
 
-pub use leaderboard::{get_rank, leaderboard};
-pub use matchmaking::{balance, quality};
+pub use leaderboard::{get_rank, leaderboard, post_leaderboard};
+pub use matchmaking::{broken_constraints, candidates, mean_difference, pick_players, quality};
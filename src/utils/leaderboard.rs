@@ -1,20 +1,22 @@
-use harmony::model::id::{RoleId, UserId};
-use trueskill::Rating;
+use std::collections::HashMap;
+
+use harmony::client::Context;
+use harmony::model::id::{ChannelId, MessageId, RoleId, UserId, WebhookId};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::config::Rank;
-use crate::model::Lobby;
-use crate::model::PlayerInfo;
+use crate::model::{LeaderboardOptions, PlayerInfo, Rating};
 use crate::Result;
 
 enum Row {
-    Player(UserId, Rating),
+    Player(UserId, Rating, f64),
     Rank(RoleId, f64),
 }
 
 impl Row {
     fn value(&self) -> f64 {
         match self {
-            Self::Player(_, x) => x.mean(),
+            Self::Player(_, _, x) => *x,
             Self::Rank(_, x) => *x,
         }
     }
@@ -24,8 +26,11 @@ impl Row {
     }
 }
 
+/// Builds the pages of a leaderboard, players who have not played enough games
+/// being listed on separate provisional pages after the ranked ones.
 pub fn leaderboard<F>(
-    lobby: &Lobby,
+    ratings: &HashMap<UserId, PlayerInfo>,
+    options: LeaderboardOptions,
     page_len: usize,
     ranks: &[Rank],
     mut f: F,
@@ -33,15 +38,23 @@ pub fn leaderboard<F>(
 where
     F: FnMut(UserId) -> Result<bool>,
 {
-    let ratings = ratings(
-        lobby,
+    let ratings = self::ratings(
+        ratings,
         |user_id, &info| match f(user_id) {
-            Ok(true) => Some(Ok(info.rating)),
+            Ok(true) => Some(Ok(info)),
             Ok(false) => None,
             Err(err) => Some(Err(err)),
         },
-        |a, b| b.mean().partial_cmp(&a.mean()).unwrap(),
+        |a, b| {
+            options
+                .score(&b.rating)
+                .partial_cmp(&options.score(&a.rating))
+                .unwrap()
+        },
     )?;
+    let (provisional, ratings): (Vec<_>, Vec<_>) = ratings
+        .into_iter()
+        .partition(|(_, info)| options.is_provisional(info));
     let pages = (ratings.len() + page_len - 1) / page_len;
     let pages = pages.max(1);
     let mut v = Vec::new();
@@ -49,14 +62,19 @@ where
         let mut ratings = ratings
             .iter()
             .enumerate()
-            .map(|(i, (user_id, rating))| (page * page_len + i + 1, Row::Player(*user_id, *rating)))
+            .map(|(i, (user_id, info))| {
+                (
+                    page * page_len + i + 1,
+                    Row::Player(*user_id, info.rating, options.score(&info.rating)),
+                )
+            })
             .chain(ranks.iter().map(|rank| (0, Row::Rank(rank.id, rank.limit))))
             .collect::<Vec<_>>();
         ratings.sort_by(|(_, a), (_, b)| b.value().partial_cmp(&a.value()).unwrap());
         for i in (0..ratings.len()).rev() {
             match ratings[i].1 {
                 Row::Rank(_, _) => continue,
-                Row::Player(_, _) => {
+                Row::Player(_, _, _) => {
                     ratings.truncate(i + 1);
                     break;
                 }
@@ -69,14 +87,25 @@ where
         let description = ratings
             .iter()
             .map(|(i, x)| match x {
-                Row::Player(user_id, rating) => {
-                    format!(
-                        "{}: {} - **{:.0}** ± {:.0}",
-                        i,
-                        user_id.mention(),
-                        rating.mean(),
-                        2.0 * rating.variance().sqrt(),
-                    )
+                Row::Player(user_id, rating, score) => {
+                    if options.conservative == 0.0 {
+                        format!(
+                            "{}: {} - **{:.0}** ± {:.0}",
+                            i,
+                            user_id.mention(),
+                            rating.mean(),
+                            2.0 * rating.variance().sqrt(),
+                        )
+                    } else {
+                        format!(
+                            "{}: {} - **{:.0}** ({:.0} ± {:.0})",
+                            i,
+                            user_id.mention(),
+                            score,
+                            rating.mean(),
+                            2.0 * rating.variance().sqrt(),
+                        )
+                    }
                 }
                 Row::Rank(role_id, _) => {
                     format!("-- {} --", role_id.mention())
@@ -86,16 +115,78 @@ where
             .join("\n");
         v.push((title, description));
     }
+    let pages = (provisional.len() + page_len - 1) / page_len;
+    for (page, ratings) in provisional.chunks(page_len).enumerate() {
+        let title = format!("Provisional ({}/{})", page + 1, pages);
+        let description = ratings
+            .iter()
+            .map(|(user_id, info)| {
+                format!(
+                    "{} - {:.0} ± {:.0} ({}/{} games)",
+                    user_id.mention(),
+                    info.rating.mean(),
+                    2.0 * info.rating.variance().sqrt(),
+                    info.games(),
+                    options.min_games,
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        v.push((title, description));
+    }
     Ok(v)
 }
 
-pub fn ratings<F, T, S>(lobby: &Lobby, mut f: F, mut s: S) -> Result<Vec<(UserId, T)>>
+/// Replaces the leaderboard messages posted with a webhook.
+///
+/// Returns the previous messages that could not be deleted, and the channel
+/// of the webhook if a message was posted.
+pub fn post_leaderboard(
+    ctx: &Context,
+    webhook_id: WebhookId,
+    webhook_token: &str,
+    messages: &mut Vec<MessageId>,
+    leaderboard: &[(String, String)],
+) -> (Vec<MessageId>, Option<ChannelId>) {
+    let orphaned = messages
+        .par_iter()
+        .filter_map(|&message| {
+            match ctx.delete_webhook_message(webhook_id, webhook_token, message) {
+                Ok(_) => None,
+                Err(err) => {
+                    eprintln!("Err: {:?}", err);
+                    Some(message)
+                }
+            }
+        })
+        .collect();
+    messages.clear();
+    let mut channel_id = None;
+    for (title, description) in leaderboard.iter() {
+        match ctx.execute_webhook(webhook_id, webhook_token, true, |m| {
+            m.embed(|e| e.description(description).title(title))
+        }) {
+            Ok(Some(message)) => {
+                messages.push(message.id);
+                channel_id = Some(message.channel_id);
+            }
+            Ok(None) => (),
+            Err(err) => eprintln!("Err: {:?}", err),
+        }
+    }
+    (orphaned, channel_id)
+}
+
+pub fn ratings<F, T, S>(
+    ratings: &HashMap<UserId, PlayerInfo>,
+    mut f: F,
+    mut s: S,
+) -> Result<Vec<(UserId, T)>>
 where
     F: FnMut(UserId, &PlayerInfo) -> Option<Result<T>>,
     S: FnMut(&T, &T) -> std::cmp::Ordering,
 {
-    let mut ratings = lobby
-        .ratings()
+    let mut ratings = ratings
         .iter()
         .filter_map(|(&user_id, info)| f(user_id, info).map(|x| Ok((user_id, x?))))
         .collect::<Result<Vec<_>>>()?;
@@ -1,57 +1,468 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use harmony::model::id::UserId;
 use itertools::Itertools;
-use rand::Rng;
-use trueskill::{Rating, SimpleTrueSkill as TrueSkill};
+use rand::seq::SliceRandom;
 
-fn balance_internal(players: &[f64]) -> u64 {
-    let goal = players.iter().sum::<f64>() / 2.0 - players[0];
-    let len = players.len();
-    let mut best_score = f64::INFINITY;
-    let mut best_team1 = vec![];
-    for team1 in (1..len).combinations(len / 2 - 1) {
-        let score = (goal - team1.iter().map(|&x| players[x]).sum::<f64>()).abs();
-        if score < best_score {
-            best_score = score;
-            best_team1 = team1;
+use crate::model::{Balance, PairConstraint, Rating, RatingSystem, System};
+
+#[cfg(test)]
+mod bench;
+mod pool;
+mod search;
+
+pub use pool::pick_players;
+
+/// Largest lobby whose splits are all tried.
+const EXHAUSTIVE_LEN: usize = 16;
+/// Largest lobby searched exactly when only the means matter.
+const MEET_IN_THE_MIDDLE_LEN: usize = 32;
+/// Time spent improving the teams of larger lobbies.
+const SEARCH_BUDGET: Duration = Duration::from_millis(100);
+/// Restarts of the local search without improvement before giving up early.
+const MAX_STALE_RESTARTS: usize = 20;
+
+/// Split parties, broken constraints and score of a split, lower is better.
+/// Parties come first so that no split which keeps them together loses to
+/// one which does not.
+type Cost = (usize, usize, f64);
+
+/// Players to split into teams, sorted by decreasing mean. A split into two
+/// teams is given by whether each player is on the first team, which has
+/// `len / 2` players.
+struct Problem<'a> {
+    players: &'a [Rating],
+    /// Players of every party of two or more.
+    parties: Vec<Vec<usize>>,
+    constraints: &'a [(usize, usize, PairConstraint)],
+    /// Pairs of previous teammates and the number of games they played
+    /// together.
+    teammates: &'a [(usize, usize, usize)],
+    /// Weight of the penalty for keeping previous teammates together.
+    variety: f64,
+    mode: Balance,
+    system: System,
+    /// Mean difference of the worst split, used by the mixed mode.
+    max_difference: f64,
+}
+
+impl<'a> Problem<'a> {
+    fn new(
+        players: &'a [Rating],
+        parties: Vec<Vec<usize>>,
+        constraints: &'a [(usize, usize, PairConstraint)],
+        teammates: &'a [(usize, usize, usize)],
+        variety: f64,
+        mode: Balance,
+        system: System,
+    ) -> Self {
+        let len = players.len();
+        let sum = |players: &[Rating]| players.iter().map(|x| x.mean()).sum::<f64>();
+        // The best players against the others
+        let max_difference = (sum(&players[..len / 2]) - sum(&players[len / 2..])).abs();
+        Self {
+            players,
+            parties,
+            constraints,
+            teammates,
+            variety,
+            mode,
+            system,
+            max_difference,
         }
     }
-    let mut bitmap = 1;
-    for x in best_team1 {
-        bitmap ^= 1 << x;
+
+    fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    /// Whether only the mean difference matters, in which case the exact
+    /// meet-in-the-middle search applies.
+    fn is_mean_only(&self) -> bool {
+        matches!(self.mode, Balance::Mean)
+            && self.parties.is_empty()
+            && self.constraints.is_empty()
+            && (self.teammates.is_empty() || self.variety == 0.0)
+    }
+
+    /// Penalty for the previous teammates that `same_team` keeps together,
+    /// relative to `worst`, the score of the worst balance.
+    fn variety_penalty(&self, worst: f64, same_team: impl Fn(usize, usize) -> bool) -> f64 {
+        let total = self.teammates.iter().map(|x| x.2).sum::<usize>();
+        if total == 0 || self.variety == 0.0 {
+            return 0.0;
+        }
+        let kept = self
+            .teammates
+            .iter()
+            .filter(|&&(a, b, _)| same_team(a, b))
+            .map(|x| x.2)
+            .sum::<usize>();
+        self.variety * worst * kept as f64 / total as f64
+    }
+
+    fn difference(&self, team1: &[bool]) -> f64 {
+        self.players
+            .iter()
+            .zip(team1)
+            .map(|(x, &team1)| if team1 { x.mean() } else { -x.mean() })
+            .sum::<f64>()
+            .abs()
+    }
+
+    fn quality(&self, team1: &[bool]) -> f64 {
+        let (team1, team2): (Vec<_>, Vec<_>) = self
+            .players
+            .iter()
+            .zip(team1)
+            .partition(|(_, &team1)| team1);
+        self.system.quality(
+            &team1.into_iter().map(|x| *x.0).collect::<Vec<_>>(),
+            &team2.into_iter().map(|x| *x.0).collect::<Vec<_>>(),
+        )
+    }
+
+    fn cost(&self, team1: &[bool]) -> Cost {
+        let constraints = self
+            .constraints
+            .iter()
+            .filter(|&&(a, b, constraint)| !constraint.is_satisfied(team1[a] == team1[b]))
+            .count();
+        let parties = self
+            .parties
+            .iter()
+            .filter(|party| party.iter().any(|&x| team1[x] != team1[party[0]]))
+            .count();
+        let (score, worst) = match self.mode {
+            Balance::Mean => (self.difference(team1), self.max_difference),
+            Balance::Quality => (1.0 - self.quality(team1), 1.0),
+            Balance::Mixed { weight } => {
+                let difference = if self.max_difference > 0.0 {
+                    self.difference(team1) / self.max_difference
+                } else {
+                    0.0
+                };
+                (
+                    weight * (1.0 - self.quality(team1)) + (1.0 - weight) * difference,
+                    1.0,
+                )
+            }
+        };
+        let penalty = self.variety_penalty(worst, |a, b| team1[a] == team1[b]);
+        (parties, constraints, score + penalty)
     }
-    bitmap
 }
 
-pub fn balance<T: Copy>(players: &[(T, Rating)]) -> [Vec<(T, Rating)>; 2] {
-    let len = players.len();
-    if len < 2 {
-        panic!("Not enough players");
+fn compare(a: &Cost, b: &Cost) -> Ordering {
+    a.partial_cmp(b).unwrap_or(Ordering::Equal)
+}
+
+/// Distinct splits with the lowest costs seen so far, best first.
+struct Top<S> {
+    len: usize,
+    splits: Vec<(Cost, S)>,
+}
+
+impl<S: PartialEq> Top<S> {
+    fn new(len: usize) -> Self {
+        Self {
+            len,
+            splits: Vec::with_capacity(len + 1),
+        }
+    }
+
+    /// Keeps a split if it is among the best ones, returns whether it was
+    /// kept. Splits must be given in a canonical form to be told apart.
+    fn insert(&mut self, cost: Cost, split: S) -> bool {
+        if self.splits.len() == self.len
+            && compare(&cost, &self.splits[self.len - 1].0) != Ordering::Less
+        {
+            return false;
+        }
+        if self.splits.iter().any(|(_, x)| *x == split) {
+            return false;
+        }
+        // Ties keep the order in which the splits were found
+        let i = self
+            .splits
+            .partition_point(|(x, _)| compare(x, &cost) != Ordering::Greater);
+        self.splits.insert(i, (cost, split));
+        self.splits.truncate(self.len);
+        true
+    }
+
+    /// Whether every split kept is perfect, so that no better one exists.
+    fn is_perfect(&self) -> bool {
+        self.splits.len() == self.len
+            && self
+                .splits
+                .last()
+                .map(|(cost, _)| cost.0 == 0 && cost.1 == 0 && cost.2 <= 0.0)
+                .unwrap_or(true)
+    }
+
+    fn into_splits(self) -> Vec<S> {
+        self.splits.into_iter().map(|(_, x)| x).collect()
     }
-    let mut players = players.to_vec();
-    players.sort_by(|a, b| b.1.mean().partial_cmp(&a.1.mean()).unwrap());
-    let team1 = balance_internal(&players.iter().map(|x| x.1.mean()).collect::<Vec<_>>());
-    let team1 = if rand::thread_rng().gen_bool(0.5) {
-        !team1
+}
+
+/// Picks the search suited to the size of the lobby and returns up to `n`
+/// splits into two teams, given by the team of each player, best first.
+fn balance_internal(problem: &Problem, n: usize) -> Vec<Vec<usize>> {
+    let splits = if problem.len() <= EXHAUSTIVE_LEN {
+        exhaustive(problem, n)
+    } else if problem.len() <= MEET_IN_THE_MIDDLE_LEN && problem.is_mean_only() && n == 1 {
+        vec![meet_in_the_middle(problem)]
     } else {
-        team1
+        return search::local_search(problem, 2, SEARCH_BUDGET, n);
+    };
+    splits
+        .into_iter()
+        .map(|team1| team1.into_iter().map(|x| usize::from(!x)).collect())
+        .collect()
+}
+
+/// Tries every split. Teams of the same size can be exchanged, so the first
+/// player is then kept on the first team.
+fn exhaustive(problem: &Problem, n: usize) -> Vec<Vec<bool>> {
+    let len = problem.len();
+    let first = usize::from(len - len / 2 == len / 2);
+    let mut top = Top::new(n);
+    for others in (first..len).combinations(len / 2 - first) {
+        let mut team1 = vec![false; len];
+        team1[0] = first == 1;
+        for x in others {
+            team1[x] = true;
+        }
+        let cost = problem.cost(&team1);
+        top.insert(cost, team1);
+    }
+    top.into_splits()
+}
+
+/// Finds the split with the smallest mean difference by combining the
+/// subsets of both halves of the players, in `O(2^(len / 2) * len)`.
+fn meet_in_the_middle(problem: &Problem) -> Vec<bool> {
+    let len = problem.len();
+    // The players are split in halves, and the first team has `half` players
+    let half = len / 2;
+    let means = problem.players.iter().map(|x| x.mean()).collect::<Vec<_>>();
+    let total = means.iter().sum::<f64>();
+    // Sums of the subsets of a half, grouped by subset size
+    let subsets = |means: &[f64]| {
+        let mut sums = vec![0.0; 1 << means.len()];
+        let mut subsets = vec![Vec::new(); means.len() + 1];
+        for mask in 0..1usize << means.len() {
+            if mask > 0 {
+                sums[mask] = sums[mask & (mask - 1)] + means[mask.trailing_zeros() as usize];
+            }
+            subsets[mask.count_ones() as usize].push((sums[mask], mask));
+        }
+        subsets
     };
-    let mut teams = [
-        Vec::with_capacity(len / 2),
-        Vec::with_capacity((len + 1) / 2),
-    ];
-    for (i, player) in players.into_iter().enumerate() {
-        if team1 & (1 << i) != 0 {
-            &mut teams[0]
-        } else {
-            &mut teams[1]
+    let left = subsets(&means[..half]);
+    let mut right = subsets(&means[half..]);
+    for subsets in right.iter_mut() {
+        subsets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    }
+    let mut best = (f64::INFINITY, 0, 0);
+    for (count, subsets) in left.iter().enumerate() {
+        let others = match half.checked_sub(count) {
+            Some(others) if others < right.len() => &right[others],
+            _ => continue,
+        };
+        for &(sum, mask) in subsets {
+            // Closest sum to the goal and its predecessor
+            let goal = total / 2.0 - sum;
+            let i = others.partition_point(|x| x.0 < goal);
+            for &(other_sum, other_mask) in
+                others[i.saturating_sub(1)..others.len().min(i + 1)].iter()
+            {
+                let difference = (total - 2.0 * (sum + other_sum)).abs();
+                if difference < best.0 {
+                    best = (difference, mask, other_mask);
+                }
+            }
         }
-        .push(player);
     }
-    teams
+    (0..len)
+        .map(|i| {
+            if i < half {
+                best.1 & (1 << i) != 0
+            } else {
+                best.2 & (1 << (i - half)) != 0
+            }
+        })
+        .collect()
+}
+
+/// Splits the players into `teams` teams, returning up to `n` distinct
+/// splits, best first. Players with the same entry in `parties` are kept on
+/// the same team and `constraints`, given as indices in `players`, are
+/// honored whenever possible. Keeping the previous `teammates` together is
+/// penalized by `variety`.
+#[allow(clippy::too_many_arguments)]
+pub fn candidates<T: Copy>(
+    players: &[(T, Rating)],
+    parties: &[usize],
+    constraints: &[(usize, usize, PairConstraint)],
+    teammates: &[(usize, usize, usize)],
+    variety: f64,
+    teams: usize,
+    mode: Balance,
+    system: System,
+    n: usize,
+) -> Vec<Vec<Vec<(T, Rating)>>> {
+    let len = players.len();
+    if len < 2 || teams < 2 {
+        panic!("Not enough players");
+    }
+    let teams = teams.min(len);
+    let mut order = (0..len).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        players[b]
+            .1
+            .mean()
+            .partial_cmp(&players[a].1.mean())
+            .unwrap()
+    });
+    let mut position = vec![0; len];
+    for (i, &x) in order.iter().enumerate() {
+        position[x] = i;
+    }
+    let mut groups = HashMap::<usize, Vec<usize>>::new();
+    for (i, &x) in order.iter().enumerate() {
+        groups.entry(parties[x]).or_default().push(i);
+    }
+    let groups = groups
+        .into_values()
+        .filter(|x| x.len() > 1)
+        .collect::<Vec<_>>();
+    let constraints = constraints
+        .iter()
+        .map(|&(a, b, constraint)| (position[a], position[b], constraint))
+        .collect::<Vec<_>>();
+    let teammates = teammates
+        .iter()
+        .map(|&(a, b, games)| (position[a], position[b], games))
+        .collect::<Vec<_>>();
+    let ratings = order.iter().map(|&x| players[x].1).collect::<Vec<_>>();
+    let problem = Problem::new(
+        &ratings,
+        groups,
+        &constraints,
+        &teammates,
+        variety,
+        mode,
+        system,
+    );
+    let assignments = if teams == 2 {
+        balance_internal(&problem, n.max(1))
+    } else {
+        search::local_search(&problem, teams, SEARCH_BUDGET, n.max(1))
+    };
+    let mut rng = rand::thread_rng();
+    assignments
+        .into_iter()
+        .map(|assignment: Vec<usize>| {
+            let mut result = vec![Vec::with_capacity(len / teams + 1); teams];
+            for (&x, team) in order.iter().zip(assignment) {
+                result[team].push(players[x]);
+            }
+            result.shuffle(&mut rng);
+            result
+        })
+        .collect()
 }
 
-pub fn quality<T: Copy>(teams: &[Vec<(T, Rating)>; 2], trueskill: TrueSkill) -> f64 {
-    trueskill.quality(
-        &teams[0].iter().map(|x| x.1).collect::<Vec<_>>(),
-        &teams[1].iter().map(|x| x.1).collect::<Vec<_>>(),
+pub fn quality<T: Copy>(teams: &[Vec<(T, Rating)>], system: System) -> f64 {
+    system.teams_quality(
+        &teams
+            .iter()
+            .map(|team| team.iter().map(|x| x.1).collect())
+            .collect::<Vec<_>>(),
     )
 }
+
+/// Difference between the summed means of the strongest and the weakest
+/// teams.
+pub fn mean_difference<T: Copy>(teams: &[Vec<(T, Rating)>]) -> f64 {
+    let sums = teams
+        .iter()
+        .map(|team| team.iter().map(|x| x.1.mean()).sum::<f64>())
+        .collect::<Vec<_>>();
+    sums.iter().copied().fold(f64::MIN, f64::max) - sums.iter().copied().fold(f64::MAX, f64::min)
+}
+
+/// Returns the constraints broken by the teams, ignoring those involving
+/// players who are not playing.
+pub fn broken_constraints(
+    teams: &[Vec<UserId>],
+    constraints: &[(UserId, UserId, PairConstraint)],
+) -> Vec<(UserId, UserId, PairConstraint)> {
+    let team = |user_id| teams.iter().position(|team| team.contains(&user_id));
+    constraints
+        .iter()
+        .filter(|&&(a, b, constraint)| match (team(a), team(b)) {
+            (Some(a), Some(b)) => !constraint.is_satisfied(a == b),
+            _ => false,
+        })
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::model::Elo;
+
+    fn random_players(len: usize) -> Vec<Rating> {
+        let mut rng = rand::thread_rng();
+        let mut players = (0..len)
+            .map(|_| Rating::new(rng.gen_range(1000.0..3000.0), 0.0))
+            .collect::<Vec<_>>();
+        players.sort_by(|a, b| b.mean().partial_cmp(&a.mean()).unwrap());
+        players
+    }
+
+    #[test]
+    fn top_keeps_the_best_distinct_splits() {
+        let mut top = Top::new(2);
+        assert!(top.insert((0, 0, 3.0), 'a'));
+        assert!(top.insert((0, 0, 1.0), 'b'));
+        assert!(!top.insert((0, 0, 0.5), 'b'));
+        assert!(top.insert((0, 0, 2.0), 'c'));
+        // Broken constraints outweigh any score
+        assert!(!top.insert((0, 1, 0.0), 'd'));
+        assert!(!top.is_perfect());
+        assert_eq!(top.into_splits(), vec!['b', 'c']);
+    }
+
+    #[test]
+    fn top_is_perfect() {
+        let mut top = Top::new(1);
+        assert!(!top.is_perfect());
+        top.insert((0, 0, 0.0), 'a');
+        assert!(top.is_perfect());
+    }
+
+    #[test]
+    fn meet_in_the_middle_is_exact() {
+        let system = System::Elo(Elo::default());
+        for len in (2..=12).chain([13, 14]) {
+            for _ in 0..20 {
+                let players = random_players(len);
+                let problem =
+                    Problem::new(&players, Vec::new(), &[], &[], 0.0, Balance::Mean, system);
+                let team1 = meet_in_the_middle(&problem);
+                assert_eq!(team1.iter().filter(|&&x| x).count(), len / 2);
+                let exact = problem.difference(&exhaustive(&problem, 1)[0]);
+                assert!((problem.difference(&team1) - exact).abs() < 1e-6);
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::model::{Balance, Elo, Rating, System};
+
+use super::search::local_search;
+use super::{
+    exhaustive, meet_in_the_middle, Problem, EXHAUSTIVE_LEN, MEET_IN_THE_MIDDLE_LEN, SEARCH_BUDGET,
+};
+
+const TRIALS: usize = 20;
+const SIZES: &[usize] = &[8, 10, 12, 16, 20, 24, 32, 40, 64, 128];
+
+/// Compares the mean difference found by every search on random lobbies. The
+/// gap of the local search is measured against the exact searches where they
+/// apply, and is the mean difference itself beyond them. Run with
+/// `cargo test --release bench_balance -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_balance() {
+    let system = System::Elo(Elo::default());
+    let mut rng = rand::thread_rng();
+    println!(
+        "{:>7} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "players", "exhaustive", "mitm", "local", "local gap", "optimal"
+    );
+    for &len in SIZES {
+        let mut times = [Duration::ZERO; 3];
+        let mut gap = 0.0;
+        let mut optimal = 0;
+        for _ in 0..TRIALS {
+            let mut players = (0..len)
+                .map(|_| Rating::new(rng.gen_range(1000.0..3000.0), 0.0))
+                .collect::<Vec<_>>();
+            players.sort_by(|a, b| b.mean().partial_cmp(&a.mean()).unwrap());
+            let problem = Problem::new(&players, Vec::new(), &[], &[], 0.0, Balance::Mean, system);
+            let mut run = |i: usize, search: &dyn Fn(&Problem) -> Vec<bool>| {
+                let start = Instant::now();
+                let team1 = search(&problem);
+                times[i] += start.elapsed();
+                problem.difference(&team1)
+            };
+            let exact = if len <= EXHAUSTIVE_LEN {
+                let exact = run(0, &|problem| exhaustive(problem, 1).swap_remove(0));
+                let mitm = run(1, &meet_in_the_middle);
+                assert!((exact - mitm).abs() < 1e-6, "Searches disagree");
+                Some(exact)
+            } else if len <= MEET_IN_THE_MIDDLE_LEN {
+                Some(run(1, &meet_in_the_middle))
+            } else {
+                None
+            };
+            let local = run(2, &|problem| {
+                let team = local_search(problem, 2, SEARCH_BUDGET, 1).swap_remove(0);
+                team.into_iter().map(|x| x == 0).collect()
+            });
+            match exact {
+                Some(exact) => {
+                    gap += local - exact;
+                    if local - exact < 1e-6 {
+                        optimal += 1;
+                    }
+                }
+                None => gap += local,
+            }
+        }
+        let time = |i: usize| {
+            if times[i].is_zero() {
+                "-".to_owned()
+            } else {
+                format!("{:.2?}", times[i] / TRIALS as u32)
+            }
+        };
+        let exact = len <= MEET_IN_THE_MIDDLE_LEN;
+        println!(
+            "{:>7} {:>12} {:>12} {:>12} {:>12.2} {:>12}",
+            len,
+            time(0),
+            time(1),
+            time(2),
+            gap / TRIALS as f64,
+            if exact {
+                format!("{}/{}", optimal, TRIALS)
+            } else {
+                "-".to_owned()
+            }
+        );
+    }
+}
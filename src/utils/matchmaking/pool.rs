@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::model::{pack, Rating, RatingSystem, System};
+
+use super::SEARCH_BUDGET;
+
+/// Largest number of selections which are all tried.
+const EXHAUSTIVE_PICKS: usize = 5000;
+
+/// Players of a queue to choose a game from, grouped by party.
+struct Pool<'a, T> {
+    players: &'a [(T, Rating)],
+    /// Parties, or single players, from the longest wait to the shortest.
+    units: Vec<Vec<usize>>,
+    /// Longest relative wait of every unit.
+    waits: Vec<f64>,
+    size: usize,
+    teams: usize,
+    weight: f64,
+    system: System,
+}
+
+impl<'a, T: Copy> Pool<'a, T> {
+    fn sizes(&self) -> Vec<usize> {
+        self.units.iter().map(|x| x.len()).collect()
+    }
+
+    /// Whether the picked units can be placed on the teams without splitting
+    /// any of them.
+    fn fits(&self, picked: &[bool]) -> bool {
+        let sizes = self
+            .units
+            .iter()
+            .zip(picked)
+            .filter(|(_, &picked)| picked)
+            .map(|(unit, _)| unit.len())
+            .collect::<Vec<_>>();
+        let capacities = (0..self.teams)
+            .map(|i| self.size / self.teams + usize::from(i < self.size % self.teams))
+            .collect::<Vec<_>>();
+        pack(&sizes, &capacities).is_some()
+    }
+
+    /// Quality of the teams formed greedily from the picked units, plus the
+    /// mean wait of their players times the weight, higher is better.
+    fn score(&self, picked: &[bool]) -> f64 {
+        let mut units = self
+            .units
+            .iter()
+            .zip(picked)
+            .filter(|(_, &picked)| picked)
+            .map(|(unit, _)| unit)
+            .collect::<Vec<_>>();
+        let mean = |unit: &Vec<usize>| unit.iter().map(|&x| self.players[x].1.mean()).sum::<f64>();
+        units.sort_by(|a, b| mean(b).partial_cmp(&mean(a)).unwrap());
+        let mut teams = vec![Vec::with_capacity(self.size / self.teams + 1); self.teams];
+        let mut sums = vec![0.0; self.teams];
+        for unit in units {
+            let room = |i: usize| {
+                (self.size / self.teams + usize::from(i < self.size % self.teams))
+                    .saturating_sub(teams[i].len())
+            };
+            // The weakest team with room for the unit, or the emptiest one
+            let team = (0..self.teams)
+                .filter(|&i| room(i) >= unit.len())
+                .min_by(|&a, &b| sums[a].partial_cmp(&sums[b]).unwrap())
+                .or_else(|| (0..self.teams).max_by_key(|&i| room(i)))
+                .unwrap();
+            sums[team] += mean(unit);
+            teams[team].extend(unit.iter().map(|&x| self.players[x].1));
+        }
+        let wait = self
+            .units
+            .iter()
+            .zip(self.waits.iter())
+            .zip(picked)
+            .filter(|(_, &picked)| picked)
+            .map(|((unit, wait), _)| unit.len() as f64 * wait)
+            .sum::<f64>()
+            / self.size as f64;
+        self.system.teams_quality(&teams) + self.weight * wait
+    }
+}
+
+/// Calls `f` with every selection of units whose sizes add up to `size`, the
+/// units being taken in order first, until it returns false.
+fn selections(
+    sizes: &[usize],
+    size: usize,
+    picked: &mut Vec<bool>,
+    f: &mut impl FnMut(&[bool]) -> bool,
+) -> bool {
+    if size == 0 {
+        let len = picked.len();
+        picked.resize(sizes.len(), false);
+        let more = f(picked);
+        picked.truncate(len);
+        return more;
+    }
+    let i = picked.len();
+    if i == sizes.len() || sizes[i..].iter().sum::<usize>() < size {
+        return true;
+    }
+    for pick in [true, false] {
+        if pick && sizes[i] > size {
+            continue;
+        }
+        picked.push(pick);
+        let more = selections(sizes, size - if pick { sizes[i] } else { 0 }, picked, f);
+        picked.pop();
+        if !more {
+            return false;
+        }
+    }
+    true
+}
+
+/// Number of selections of units whose sizes add up to `size`, saturated.
+fn count(sizes: &[usize], size: usize) -> usize {
+    let mut ways = vec![0usize; size + 1];
+    ways[0] = 1;
+    for &x in sizes {
+        for total in (x..=size).rev() {
+            ways[total] = ways[total].saturating_add(ways[total - x]);
+        }
+    }
+    ways[size]
+}
+
+/// Tries every selection which fits on the teams and keeps the best one.
+fn exhaustive<T: Copy>(pool: &Pool<T>) -> Option<Vec<bool>> {
+    let mut best = (f64::NEG_INFINITY, None);
+    selections(&pool.sizes(), pool.size, &mut Vec::new(), &mut |picked| {
+        if !pool.fits(picked) {
+            return true;
+        }
+        let score = pool.score(picked);
+        if score > best.0 || best.1.is_none() {
+            best = (score, Some(picked.to_vec()));
+        }
+        true
+    });
+    best.1
+}
+
+/// Starts from the players who waited the longest and exchanges units of the
+/// same size while it improves the selection and the time budget lasts.
+/// Exchanges keep the sizes picked, so the selection keeps fitting.
+fn local_search<T: Copy>(pool: &Pool<T>) -> Option<Vec<bool>> {
+    let start = Instant::now();
+    let sizes = pool.sizes();
+    let mut picked = Vec::new();
+    selections(&sizes, pool.size, &mut Vec::new(), &mut |x| {
+        if !pool.fits(x) {
+            return true;
+        }
+        picked = x.to_vec();
+        false
+    });
+    if picked.is_empty() {
+        return None;
+    }
+    let mut score = pool.score(&picked);
+    'search: while start.elapsed() < SEARCH_BUDGET {
+        for i in 0..sizes.len() {
+            if !picked[i] {
+                continue;
+            }
+            for j in 0..sizes.len() {
+                if picked[j] || sizes[j] != sizes[i] {
+                    continue;
+                }
+                picked.swap(i, j);
+                let new_score = pool.score(&picked);
+                if new_score > score {
+                    score = new_score;
+                    continue 'search;
+                }
+                picked.swap(i, j);
+            }
+        }
+        break;
+    }
+    Some(picked)
+}
+
+/// Picks the `size` players of a game out of a larger queue, keeping the
+/// players with the same entry in `parties` together. The selections which
+/// make the most even `teams` teams are favored, along with the players who
+/// waited the longest: `waits` are between 0 and 1 and `weight` is the
+/// importance of a full wait against a perfect quality. Returns the indices
+/// of the picked players, or `None` if the parties cannot make up a game
+/// without splitting one of them.
+pub fn pick_players<T: Copy>(
+    players: &[(T, Rating)],
+    parties: &[usize],
+    waits: &[f64],
+    size: usize,
+    teams: usize,
+    weight: f64,
+    system: System,
+) -> Option<Vec<usize>> {
+    let mut groups = HashMap::<usize, Vec<usize>>::new();
+    for (i, &x) in parties.iter().enumerate() {
+        groups.entry(x).or_default().push(i);
+    }
+    let wait = |unit: &Vec<usize>| unit.iter().map(|&x| waits[x]).fold(0.0, f64::max);
+    let mut units = groups.into_values().collect::<Vec<_>>();
+    units.sort_by(|a, b| wait(b).partial_cmp(&wait(a)).unwrap());
+    let pool = Pool {
+        players,
+        waits: units.iter().map(wait).collect(),
+        units,
+        size,
+        teams: teams.clamp(1, size.max(1)),
+        weight,
+        system,
+    };
+    let picked = match count(&pool.sizes(), size) {
+        0 => return None,
+        x if x <= EXHAUSTIVE_PICKS => exhaustive(&pool)?,
+        _ => local_search(&pool)?,
+    };
+    Some(
+        pool.units
+            .iter()
+            .zip(picked)
+            .filter(|(_, picked)| *picked)
+            .flat_map(|(unit, _)| unit.iter().copied())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Elo;
+
+    #[test]
+    fn pick_players_keeps_parties_whole() {
+        let system = System::Elo(Elo::default());
+        let players = (0..8)
+            .map(|x| (x, Rating::new(1500.0, 0.0)))
+            .collect::<Vec<_>>();
+        // Three parties of two, who waited the longest, and two single players
+        let parties = [0, 0, 1, 1, 2, 2, 3, 4];
+        let waits = [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0];
+        let picked = pick_players(&players, &parties, &waits, 6, 2, 1.0, system).unwrap();
+        // Two teams of three cannot hold the three parties
+        assert_eq!(picked.len(), 6);
+        assert!(picked.contains(&6) && picked.contains(&7));
+        for party in 0..3 {
+            assert_eq!(
+                picked.contains(&(2 * party)),
+                picked.contains(&(2 * party + 1))
+            );
+        }
+    }
+
+    #[test]
+    fn pick_players_without_a_game() {
+        let system = System::Elo(Elo::default());
+        let players = (0..4)
+            .map(|x| (x, Rating::new(1500.0, 0.0)))
+            .collect::<Vec<_>>();
+        let parties = [0, 0, 0, 1];
+        let waits = [0.0; 4];
+        assert_eq!(
+            pick_players(&players, &parties, &waits, 4, 2, 0.5, system),
+            None
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use crate::model::{pack, Balance, PairConstraint, Rating, RatingSystem};
+
+use super::{compare, Cost, Problem, Top, MAX_STALE_RESTARTS};
+
+/// Split of the players of a problem into teams, given by the team of each
+/// player. The first `len % teams` teams have an extra player.
+struct Split<'a> {
+    problem: &'a Problem<'a>,
+    capacities: Vec<usize>,
+    /// Spread of the worst split, used by the mixed mode.
+    max_spread: f64,
+}
+
+impl<'a> Split<'a> {
+    fn new(problem: &'a Problem<'a>, teams: usize) -> Self {
+        let len = problem.len();
+        let capacities = (0..teams)
+            .map(|i| len / teams + usize::from(i < len % teams))
+            .collect::<Vec<_>>();
+        let sum = |players: &[Rating]| players.iter().map(|x| x.mean()).sum::<f64>();
+        // The best players against the worst ones
+        let size = len / teams;
+        let max_spread = sum(&problem.players[..size]) - sum(&problem.players[len - size..]);
+        Self {
+            problem,
+            capacities,
+            max_spread,
+        }
+    }
+
+    /// Difference between the summed means of the strongest and the weakest
+    /// teams.
+    fn spread(&self, team: &[usize]) -> f64 {
+        let mut sums = vec![0.0; self.capacities.len()];
+        for (x, &i) in self.problem.players.iter().zip(team) {
+            sums[i] += x.mean();
+        }
+        sums.iter().copied().fold(f64::MIN, f64::max)
+            - sums.iter().copied().fold(f64::MAX, f64::min)
+    }
+
+    fn quality(&self, team: &[usize]) -> f64 {
+        let mut teams = vec![Vec::new(); self.capacities.len()];
+        for (&x, &i) in self.problem.players.iter().zip(team) {
+            teams[i].push(x);
+        }
+        self.problem.system.teams_quality(&teams)
+    }
+
+    fn cost(&self, team: &[usize]) -> Cost {
+        let problem = self.problem;
+        let constraints = problem
+            .constraints
+            .iter()
+            .filter(|&&(a, b, constraint)| !constraint.is_satisfied(team[a] == team[b]))
+            .count();
+        let parties = problem
+            .parties
+            .iter()
+            .filter(|party| party.iter().any(|&x| team[x] != team[party[0]]))
+            .count();
+        let (score, worst) = match problem.mode {
+            Balance::Mean => (self.spread(team), self.max_spread),
+            Balance::Quality => (1.0 - self.quality(team), 1.0),
+            Balance::Mixed { weight } => {
+                let spread = if self.max_spread > 0.0 {
+                    self.spread(team) / self.max_spread
+                } else {
+                    0.0
+                };
+                (
+                    weight * (1.0 - self.quality(team)) + (1.0 - weight) * spread,
+                    1.0,
+                )
+            }
+        };
+        let penalty = problem.variety_penalty(worst, |a, b| team[a] == team[b]);
+        (parties, constraints, score + penalty)
+    }
+
+    /// Places the units one by one on the team with the lowest summed mean
+    /// that has room for them, avoiding the players they must be apart from.
+    fn construct(&self, units: &[Vec<usize>]) -> Vec<usize> {
+        let problem = self.problem;
+        let capacities = &self.capacities;
+        let mut team = vec![None; problem.len()];
+        let mut sizes = vec![0; capacities.len()];
+        let mut sums = vec![0.0; capacities.len()];
+        for unit in units {
+            let conflicts = |i: usize| {
+                problem
+                    .constraints
+                    .iter()
+                    .filter(|x| x.2 == PairConstraint::Apart)
+                    .filter(|&&(a, b, _)| {
+                        (unit.contains(&a) && team[b] == Some(i))
+                            || (unit.contains(&b) && team[a] == Some(i))
+                    })
+                    .count()
+            };
+            let choice = (0..capacities.len())
+                .filter(|&i| sizes[i] + unit.len() <= capacities[i])
+                .min_by(|&i, &j| {
+                    (conflicts(i), sums[i])
+                        .partial_cmp(&(conflicts(j), sums[j]))
+                        .unwrap_or(Ordering::Equal)
+                });
+            if choice.is_none() {
+                if let Some(team) = packed(problem, units, capacities) {
+                    return team;
+                }
+            }
+            for &x in unit {
+                // A unit too large for every team is split
+                let i = choice.unwrap_or_else(|| {
+                    (0..capacities.len())
+                        .filter(|&i| sizes[i] < capacities[i])
+                        .min_by(|&i, &j| sums[i].partial_cmp(&sums[j]).unwrap_or(Ordering::Equal))
+                        .unwrap()
+                });
+                team[x] = Some(i);
+                sizes[i] += 1;
+                sums[i] += problem.players[x].mean();
+            }
+        }
+        team.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Swaps players between teams while it lowers the cost.
+    fn improve(&self, team: &mut [usize], start: Instant, budget: Duration) -> Cost {
+        let len = team.len();
+        let mut cost = self.cost(team);
+        let mut improved = true;
+        while improved && start.elapsed() < budget {
+            improved = false;
+            for i in 0..len {
+                for j in i + 1..len {
+                    if team[i] == team[j] {
+                        continue;
+                    }
+                    team.swap(i, j);
+                    let new_cost = self.cost(team);
+                    if compare(&new_cost, &cost) == Ordering::Less {
+                        cost = new_cost;
+                        improved = true;
+                    } else {
+                        team.swap(i, j);
+                    }
+                }
+            }
+        }
+        cost
+    }
+}
+
+/// Numbers the teams in the order of their first player, so that splits
+/// which only differ by the order of the teams are equal.
+fn canonical(team: &[usize], teams: usize) -> Vec<usize> {
+    let mut labels = vec![None; teams];
+    let mut next = 0;
+    team.iter()
+        .map(|&i| {
+            *labels[i].get_or_insert_with(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect()
+}
+
+/// Splits the players into `teams` teams by building them greedily then
+/// swapping players, restarting from shuffled orders until the time budget is
+/// spent. Returns the `n` best distinct local optima found. Every player is
+/// alone in free-for-all games.
+pub(super) fn local_search(
+    problem: &Problem,
+    teams: usize,
+    budget: Duration,
+    n: usize,
+) -> Vec<Vec<usize>> {
+    let start = Instant::now();
+    if teams >= problem.len() {
+        return vec![(0..problem.len()).collect()];
+    }
+    let split = Split::new(problem, teams);
+    let mut units = units(problem);
+    let mut rng = rand::thread_rng();
+    let mut top = Top::new(n);
+    let mut stale = 0;
+    loop {
+        let mut team = split.construct(&units);
+        let cost = split.improve(&mut team, start, budget);
+        if top.insert(cost, canonical(&team, teams)) {
+            stale = 0;
+        } else {
+            stale += 1;
+        }
+        if top.is_perfect() || stale >= MAX_STALE_RESTARTS || start.elapsed() >= budget {
+            break;
+        }
+        units.shuffle(&mut rng);
+    }
+    top.into_splits()
+}
+
+/// Groups the parties and the players who must play together into units,
+/// sorted by decreasing summed mean.
+fn units(problem: &Problem) -> Vec<Vec<usize>> {
+    let len = problem.len();
+    let mut unit_of = (0..len).collect::<Vec<_>>();
+    let together = problem
+        .constraints
+        .iter()
+        .filter(|x| x.2 == PairConstraint::Together)
+        .map(|&(a, b, _)| vec![a, b]);
+    for group in problem.parties.iter().cloned().chain(together) {
+        for &x in group[1..].iter() {
+            let root = find(&mut unit_of, group[0]);
+            let other = find(&mut unit_of, x);
+            unit_of[other] = root;
+        }
+    }
+    let mut units = HashMap::<usize, Vec<usize>>::new();
+    for x in 0..len {
+        let unit = find(&mut unit_of, x);
+        units.entry(unit).or_default().push(x);
+    }
+    let mut units = units.into_values().collect::<Vec<_>>();
+    let unit_mean = |unit: &[usize]| unit.iter().map(|&x| problem.players[x].mean()).sum::<f64>();
+    units.sort_by(|a, b| unit_mean(b).partial_cmp(&unit_mean(a)).unwrap());
+    units
+}
+
+/// Finds the representative of the unit of `x`.
+fn find(unit_of: &mut [usize], x: usize) -> usize {
+    if unit_of[x] != x {
+        unit_of[x] = find(unit_of, unit_of[x]);
+    }
+    unit_of[x]
+}
+
+/// Assigns whole units to teams of the given capacities, or whole parties if
+/// the units cannot all fit, returning the team of every player. Used when
+/// the greedy construction has to split a unit.
+fn packed(problem: &Problem, units: &[Vec<usize>], capacities: &[usize]) -> Option<Vec<usize>> {
+    let assign = |units: &[Vec<usize>]| {
+        let sizes = units.iter().map(|x| x.len()).collect::<Vec<_>>();
+        let bins = pack(&sizes, capacities)?;
+        let mut team = vec![0; problem.len()];
+        for (unit, bin) in units.iter().zip(bins) {
+            for &x in unit {
+                team[x] = bin;
+            }
+        }
+        Some(team)
+    };
+    assign(units).or_else(|| {
+        let mut parties = problem.parties.clone();
+        parties.extend(
+            (0..problem.len())
+                .filter(|x| !problem.parties.iter().any(|party| party.contains(x)))
+                .map(|x| vec![x]),
+        );
+        assign(&parties)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Elo, System};
+
+    #[test]
+    fn local_search_keeps_parties() {
+        let system = System::Elo(Elo::default());
+        let players = (0..12)
+            .map(|x| Rating::new(2000.0 - 50.0 * x as f64, 0.0))
+            .collect::<Vec<_>>();
+        let parties = vec![vec![0, 1, 2], vec![3, 4]];
+        let problem = Problem::new(&players, parties, &[], &[], 0.0, Balance::Mean, system);
+        for teams in [2, 3] {
+            let team = local_search(&problem, teams, Duration::from_millis(10), 1).swap_remove(0);
+            assert_eq!(team[0], team[1]);
+            assert_eq!(team[0], team[2]);
+            assert_eq!(team[3], team[4]);
+            for i in 0..teams {
+                assert_eq!(team.iter().filter(|&&x| x == i).count(), 12 / teams);
+            }
+        }
+    }
+}